@@ -8,14 +8,20 @@ export RUSTDOCFLAGS=-Dwarnings
 # std
 cargo build --no-default-features
 cargo build --no-default-features --features log
+cargo build --no-default-features --features iphc-contexts-4
+cargo build --no-default-features --features deflate
 
 # embedded
 cargo build --target thumbv7em-none-eabi --no-default-features
 cargo build --target thumbv7em-none-eabi --no-default-features --features log
 cargo build --target thumbv7em-none-eabi --no-default-features --features defmt
+cargo build --target thumbv7em-none-eabi --no-default-features --features deflate
 
 # docs
 cargo doc
 
+# tests
+cargo test --no-default-features --features iphc-contexts-4,deflate
+
 # examples
 (cd examples; cargo build --bins)
@@ -4,9 +4,10 @@ mod serial_port;
 use clap::Parser;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::time::Instant as StdInstant;
 
 use ppproto::pppos::{PPPoS, PPPoSAction};
-use ppproto::Config;
+use ppproto::{Config, Instant};
 use serial_port::SerialPort;
 
 #[derive(Parser)]
@@ -24,8 +25,11 @@ fn main() {
     let config = Config {
         username: b"myuser",
         password: b"mypass",
+        random_seed: rand::random(),
+        ..Default::default()
     };
     let mut ppp = PPPoS::new(config);
+    let start = StdInstant::now();
 
     let mut rx_buf = [0; 2048];
 
@@ -37,10 +41,11 @@ fn main() {
     let mut data: &[u8] = &[];
     loop {
         // Poll the ppp
-        match ppp.poll(&mut tx_buf, &mut rx_buf) {
+        let now = Instant::from_millis(start.elapsed().as_millis() as u64);
+        match ppp.poll(&mut tx_buf, &mut rx_buf, now) {
             PPPoSAction::None => {}
             PPPoSAction::Transmit(n) => port.write_all(&tx_buf[..n]).unwrap(),
-            PPPoSAction::Received(range) => {
+            PPPoSAction::Received(0x0021, range) => {
                 let pkt = &mut rx_buf[range];
                 log::info!("received packet: {:x?}", pkt);
 
@@ -80,6 +85,9 @@ fn main() {
                     }
                 }
             }
+            PPPoSAction::Received(proto, _) => {
+                log::info!("ignoring packet with protocol {:04x}", proto)
+            }
         }
 
         // If we have no data, read some.
@@ -48,7 +48,7 @@ impl Device for PPPDevice {
     type RxToken<'a> = PPPRxToken<'a>;
     type TxToken<'a> = PPPTxToken<'a>;
 
-    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
         self.port.set_nonblocking(true).unwrap();
 
         let mut tx_buf = [0; 2048];
@@ -57,10 +57,12 @@ impl Device for PPPDevice {
         let mut data: &[u8] = &[];
         loop {
             // Poll the ppp
-            match self.ppp.poll(&mut tx_buf, &mut self.rx_buf) {
+            let now = ppproto::Instant::from_millis(timestamp.total_millis() as u64);
+            match self.ppp.poll(&mut tx_buf, &mut self.rx_buf, now) {
                 PPPoSAction::None => {}
                 PPPoSAction::Transmit(n) => self.port.write_all(&tx_buf[..n]).unwrap(),
-                PPPoSAction::Received(range) => {
+                // The interface only has an IPv4 address.
+                PPPoSAction::Received(0x0021, range) => {
                     return Some((
                         PPPRxToken {
                             buf: &mut self.rx_buf[range],
@@ -71,6 +73,9 @@ impl Device for PPPDevice {
                         },
                     ));
                 }
+                PPPoSAction::Received(proto, _) => {
+                    debug!("ignoring packet with protocol {:04x}", proto)
+                }
             }
 
             // If we have no data, read some.
@@ -155,6 +160,8 @@ fn main() {
     let config = Config {
         username: b"myuser",
         password: b"mypass",
+        random_seed: rand::random(),
+        ..Default::default()
     };
     let mut ppp = PPPoS::new(config);
 
@@ -344,6 +351,14 @@ fn main() {
             }
         }
 
-        phy_wait(fd, iface.poll_delay(timestamp, &sockets)).expect("wait error");
+        let mut delay = iface.poll_delay(timestamp, &sockets);
+        if let Some(at) = device.ppp.poll_at() {
+            let ppp_delay = Duration::from_millis(
+                at.as_millis()
+                    .saturating_sub(timestamp.total_millis() as u64),
+            );
+            delay = Some(delay.map_or(ppp_delay, |d| d.min(ppp_delay)));
+        }
+        phy_wait(fd, delay).expect("wait error");
     }
 }
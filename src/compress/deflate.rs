@@ -0,0 +1,656 @@
+//! Deflate packet compression, RFC 1979.
+//!
+//! The packets of each direction form a single Deflate stream, until the history is reset.
+//! Each packet ends with a sync flush so the peer can decompress it right away, and is
+//! numbered so a lost one is noticed: the peer's history is then out of sync, and must be
+//! reset.
+//!
+//! Packets are compressed with fixed Huffman codes, by an encoder that doesn't allocate.
+//! Decompression uses miniz_oxide, so the peer may use any Deflate encoder.
+
+use core::cell::RefCell;
+
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_HAS_MORE_INPUT;
+use miniz_oxide::inflate::core::DecompressorOxide;
+use miniz_oxide::inflate::TINFLStatus;
+
+use crate::wire::parse_protocol;
+use crate::{Compressor, OptionVerdict};
+
+/// CCP option type of Deflate.
+const OPTION_TYPE: u8 = 26;
+/// Compression method in the option, the only one defined.
+const METHOD_DEFLATE: u8 = 8;
+/// Largest window size, as a power of two.
+const MAX_WINDOW_BITS: u8 = 15;
+/// Size of the histories, large enough for any window.
+const HISTORY_SIZE: usize = 1 << MAX_WINDOW_BITS;
+const HISTORY_MASK: usize = HISTORY_SIZE - 1;
+/// Length of the sequence number before the compressed data.
+const SEQ_LEN: usize = 2;
+
+/// Repeated strings are found from a hash of their first `MIN_MATCH` bytes.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 12;
+/// How many earlier strings with the same hash are tried, at most.
+const MAX_CHAIN: usize = 32;
+
+/// End-of-block symbol of the literal/length alphabet.
+const END_OF_BLOCK: u16 = 256;
+/// Base of each length code from 257, and its number of extra bits, RFC 1951 section 3.2.5.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base of each distance code, and its number of extra bits.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Parse the option data, returning the window size if we support the parameters.
+fn parse_option(data: &[u8]) -> Option<u8> {
+    let [params, check] = *data else {
+        return None;
+    };
+    // Only the sequence number check method is defined, as 0.
+    let bits = (params >> 4) + 8;
+    (params & 0x0f == METHOD_DEFLATE && check & 0x03 == 0 && bits <= MAX_WINDOW_BITS)
+        .then_some(bits)
+}
+
+/// Write the option data for a window size, returning its length.
+fn write_option(bits: u8, data: &mut [u8]) -> usize {
+    data[0] = (bits - 8) << 4 | METHOD_DEFLATE;
+    data[1] = 0;
+    2
+}
+
+/// The uncompressed contents of a packet: its protocol, compressed to 1 byte if possible as
+/// with Protocol-Field-Compression, followed by its data.
+struct Input<'a> {
+    protocol: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn new(protocol: &'a [u8; 2], data: &'a [u8]) -> Self {
+        let protocol = if protocol[0] == 0 {
+            &protocol[1..]
+        } else {
+            &protocol[..]
+        };
+        Self { protocol, data }
+    }
+
+    fn len(&self) -> usize {
+        self.protocol.len() + self.data.len()
+    }
+
+    fn get(&self, i: usize) -> u8 {
+        match i.checked_sub(self.protocol.len()) {
+            Some(i) => self.data[i],
+            None => self.protocol[i],
+        }
+    }
+
+    fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.protocol.iter().chain(self.data).copied()
+    }
+
+    /// Hash of the string at `i`, which must be at least `MIN_MATCH` bytes long.
+    fn hash(&self, i: usize) -> usize {
+        let s = u32::from_le_bytes([self.get(i), self.get(i + 1), self.get(i + 2), 0]);
+        (s.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+}
+
+/// Writes Deflate data, least significant bit first.
+///
+/// Bytes that don't fit in the output are counted but dropped.
+struct BitWriter<'a> {
+    out: &'a mut [u8],
+    len: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        Self {
+            out,
+            len: 0,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn put(&mut self, bits: u32, count: u32) {
+        self.bits |= bits << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            if let Some(o) = self.out.get_mut(self.len) {
+                *o = self.bits as u8;
+            }
+            self.len += 1;
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Pad to a byte boundary.
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.put(0, 8 - self.count);
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit.
+    fn put_code(&mut self, code: u16, count: u32) {
+        self.put((code.reverse_bits() >> (16 - count)) as u32, count);
+    }
+
+    /// Write a symbol of the literal/length alphabet with its fixed Huffman code.
+    fn symbol(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    /// Write a repetition of `length` bytes from `distance` bytes back.
+    fn repeat(&mut self, length: usize, distance: usize) {
+        let i = LENGTH_BASE.partition_point(|&b| b as usize <= length) - 1;
+        self.symbol(257 + i as u16);
+        self.put(
+            (length - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+
+        // Distance codes are all 5 bits long.
+        let i = DISTANCE_BASE.partition_point(|&b| b as usize <= distance) - 1;
+        self.put_code(i as u16, 5);
+        self.put(
+            (distance - DISTANCE_BASE[i] as usize) as u32,
+            DISTANCE_EXTRA[i] as u32,
+        );
+    }
+}
+
+/// Compressor state.
+struct Encoder {
+    /// Window size accepted for the peer's decompressor, as a power of two.
+    window_bits: u8,
+    seq: u16,
+    /// The last bytes compressed, by position modulo the history size.
+    history: [u8; HISTORY_SIZE],
+    /// Position of the next byte, counting from the last reset.
+    pos: usize,
+    /// Number of valid bytes in the history.
+    filled: usize,
+    /// Latest position of each hash.
+    head: [u32; 1 << HASH_BITS],
+    /// Distance from each position in the history to the previous one with the same hash,
+    /// 0 if none.
+    prev: [u16; HISTORY_SIZE],
+}
+
+impl Encoder {
+    const fn new() -> Self {
+        Self {
+            window_bits: MAX_WINDOW_BITS,
+            seq: 0,
+            history: [0; HISTORY_SIZE],
+            pos: 0,
+            filled: 0,
+            head: [0; 1 << HASH_BITS],
+            prev: [0; HISTORY_SIZE],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.seq = 0;
+        self.pos = 0;
+        self.filled = 0;
+        self.head.fill(0);
+        self.prev.fill(0);
+    }
+
+    /// Compress `input` as one fixed Huffman block followed by a sync flush.
+    ///
+    /// The whole input is added to the history, even if the output doesn't fit in `w`.
+    fn compress(&mut self, input: &Input<'_>, w: &mut BitWriter<'_>) {
+        // Not the last block, fixed Huffman codes.
+        w.put(0b010, 3);
+        let mut i = 0;
+        while i < input.len() {
+            match self.find_match(input, i) {
+                Some((length, distance)) => {
+                    w.repeat(length, distance);
+                    for j in i..i + length {
+                        self.insert(input, j);
+                    }
+                    i += length;
+                }
+                None => {
+                    w.symbol(input.get(i) as u16);
+                    self.insert(input, i);
+                    i += 1;
+                }
+            }
+        }
+        w.symbol(END_OF_BLOCK);
+
+        // The sync flush is an empty stored block, ending on a byte boundary.
+        w.put(0b000, 3);
+        w.align();
+        for b in [0x00, 0x00, 0xff, 0xff] {
+            w.put(b, 8);
+        }
+    }
+
+    /// Find the longest earlier string matching the one at `i`, returning its length and
+    /// distance.
+    fn find_match(&self, input: &Input<'_>, i: usize) -> Option<(usize, usize)> {
+        let max_length = (input.len() - i).min(MAX_MATCH);
+        if max_length < MIN_MATCH {
+            return None;
+        }
+        let max_distance = (1 << self.window_bits).min(self.filled);
+
+        let mut best = (0, 0);
+        let mut distance = (self.pos as u32).wrapping_sub(self.head[input.hash(i)]) as usize;
+        for _ in 0..MAX_CHAIN {
+            if distance == 0 || distance > max_distance {
+                break;
+            }
+            let length = self.match_length(input, i, distance, max_length);
+            if length > best.0 {
+                best = (length, distance);
+                if length == max_length {
+                    break;
+                }
+            }
+            match self.prev[self.pos.wrapping_sub(distance) & HISTORY_MASK] {
+                0 => break,
+                d => distance += d as usize,
+            }
+        }
+        (best.0 >= MIN_MATCH).then_some(best)
+    }
+
+    /// Length of the match between the strings at `i` and `distance` bytes before.
+    fn match_length(&self, input: &Input<'_>, i: usize, distance: usize, max: usize) -> usize {
+        (0..max)
+            .take_while(|&k| {
+                // Earlier packets are only in the history. The match may overlap the string.
+                let b = match (i + k).checked_sub(distance) {
+                    Some(j) => input.get(j),
+                    None => {
+                        self.history[self.pos.wrapping_add(k).wrapping_sub(distance) & HISTORY_MASK]
+                    }
+                };
+                b == input.get(i + k)
+            })
+            .count()
+    }
+
+    /// Add the byte at `i` to the history, and the string starting at it to the hash chains.
+    fn insert(&mut self, input: &Input<'_>, i: usize) {
+        let slot = self.pos & HISTORY_MASK;
+        self.prev[slot] = 0;
+        if i + MIN_MATCH <= input.len() {
+            let head = &mut self.head[input.hash(i)];
+            let distance = (self.pos as u32).wrapping_sub(*head) as usize;
+            if distance <= HISTORY_SIZE {
+                self.prev[slot] = distance as u16;
+            }
+            *head = self.pos as u32;
+        }
+        self.history[slot] = input.get(i);
+        self.pos = self.pos.wrapping_add(1);
+        self.filled = (self.filled + 1).min(HISTORY_SIZE);
+    }
+}
+
+/// Decompressor state.
+struct Decoder {
+    /// Window size we request from the peer's compressor, as a power of two.
+    window_bits: u8,
+    seq: u16,
+    /// Created on the first reset: it can't be in a const constructor.
+    inflate: Option<DecompressorOxide>,
+    /// The last bytes decompressed, by position modulo the history size.
+    history: [u8; HISTORY_SIZE],
+    /// Position of the next byte in the history.
+    pos: usize,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Self {
+            window_bits: MAX_WINDOW_BITS,
+            seq: 0,
+            inflate: None,
+            history: [0; HISTORY_SIZE],
+            pos: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.seq = 0;
+        match &mut self.inflate {
+            Some(inflate) => inflate.init(),
+            None => self.inflate = Some(DecompressorOxide::new()),
+        }
+        self.history.fill(0);
+        self.pos = 0;
+    }
+
+    /// Decompress the data of one packet into `out`, returning its length.
+    fn inflate(&mut self, mut input: &[u8], out: &mut [u8]) -> Option<usize> {
+        let inflate = self.inflate.as_mut()?;
+        let mut n = 0;
+        loop {
+            // The history is used as miniz_oxide's wrapping output buffer, so each call
+            // writes from the current position up to its end at most.
+            let (status, used, written) = miniz_oxide::inflate::core::decompress(
+                inflate,
+                input,
+                &mut self.history,
+                self.pos,
+                TINFL_FLAG_HAS_MORE_INPUT,
+            );
+            out.get_mut(n..n + written)?
+                .copy_from_slice(&self.history[self.pos..self.pos + written]);
+            n += written;
+            self.pos = (self.pos + written) & HISTORY_MASK;
+            input = &input[used..];
+            match status {
+                TINFLStatus::HasMoreOutput => {}
+                // The packet ended on a block boundary, as the compressor flushed.
+                TINFLStatus::NeedsMoreInput => return Some(n),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Add a packet sent uncompressed to the history.
+    fn add(&mut self, input: &Input<'_>) {
+        for b in input.bytes() {
+            self.history[self.pos] = b;
+            self.pos = (self.pos + 1) & HISTORY_MASK;
+        }
+    }
+}
+
+/// Deflate compression, RFC 1979, to use with [`Config::compressors`](crate::Config::compressors).
+///
+/// It compresses much better than [`Predictor1`](crate::Predictor1), but needs about 160 KiB
+/// of RAM for the histories of both directions and the tables used to find repeated strings.
+/// The window size is negotiated: we ask for the largest one, and compress with the one the
+/// peer asks for.
+///
+/// As it's so large, [`new()`](Self::new) is `const`: it can be placed in static memory,
+/// e.g. with a `static_cell::ConstStaticCell`, instead of being built on the stack.
+pub struct Deflate {
+    tx: RefCell<Encoder>,
+    rx: RefCell<Decoder>,
+}
+
+impl Deflate {
+    /// Create a new Deflate.
+    pub const fn new() -> Self {
+        Self {
+            tx: RefCell::new(Encoder::new()),
+            rx: RefCell::new(Decoder::new()),
+        }
+    }
+}
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compressor for Deflate {
+    fn option_type(&self) -> u8 {
+        OPTION_TYPE
+    }
+
+    fn rx_option(&self, data: &mut [u8]) -> usize {
+        write_option(self.rx.borrow().window_bits, data)
+    }
+
+    fn rx_option_nacked(&self, data: &[u8]) -> bool {
+        // The peer can't compress with a window as large as ours: any smaller one is fine.
+        match parse_option(data) {
+            Some(bits) => {
+                self.rx.borrow_mut().window_bits = bits;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn tx_option(&self, data: &[u8], nak: &mut [u8]) -> OptionVerdict {
+        match parse_option(data) {
+            // We can compress with any window size up to the one the peer asks for.
+            Some(bits) => {
+                self.tx.borrow_mut().window_bits = bits;
+                OptionVerdict::Ack
+            }
+            None if data.len() == 2 => OptionVerdict::Nak(write_option(MAX_WINDOW_BITS, nak)),
+            None => OptionVerdict::Reject,
+        }
+    }
+
+    fn reset_tx(&self) {
+        self.tx.borrow_mut().reset();
+    }
+
+    fn reset_rx(&self) {
+        self.rx.borrow_mut().reset();
+    }
+
+    fn compress(&self, protocol: u16, data: &[u8], out: &mut [u8]) -> Option<usize> {
+        let protocol = protocol.to_be_bytes();
+        let input = Input::new(&protocol, data);
+        let mut tx = self.tx.borrow_mut();
+
+        // Packets that end up sent as is are still in the history, and counted: the peer adds
+        // them to its own when receiving them.
+        let seq = tx.seq;
+        tx.seq = seq.wrapping_add(1);
+        let mut w = BitWriter::new(out.get_mut(SEQ_LEN..).unwrap_or_default());
+        tx.compress(&input, &mut w);
+
+        let n = SEQ_LEN + w.len;
+        if n > out.len() || n >= input.len() {
+            return None;
+        }
+        out[..SEQ_LEN].copy_from_slice(&seq.to_be_bytes());
+        Some(n)
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> Option<(u16, usize)> {
+        let (seq, data) = data.split_first_chunk::<SEQ_LEN>()?;
+        let seq = u16::from_be_bytes(*seq);
+        let mut rx = self.rx.borrow_mut();
+        if seq != rx.seq {
+            debug!("Deflate: expected packet {:?}, got {:?}", rx.seq, seq);
+            return None;
+        }
+        rx.seq = seq.wrapping_add(1);
+
+        let n = rx.inflate(data, out)?;
+        let (protocol, proto_len) = parse_protocol(&out[..n])?;
+        out.copy_within(proto_len..n, 0);
+        Some((protocol, n - proto_len))
+    }
+
+    fn incompressible(&self, protocol: u16, data: &[u8]) {
+        let protocol = protocol.to_be_bytes();
+        let mut rx = self.rx.borrow_mut();
+        rx.seq = rx.seq.wrapping_add(1);
+        rx.add(&Input::new(&protocol, data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hex;
+
+    const PACKET: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: www.example.com\r\n\
+        Referer: http://www.example.com/index.html\r\nAccept: text/html, text/plain\r\n\r\n";
+
+    /// Compress `data` with `tx` and decompress it with `rx`, or hand it to `rx` as sent
+    /// uncompressed. Returns the compressed length, None if it wasn't compressed.
+    fn transfer(tx: &Deflate, rx: &Deflate, data: &[u8]) -> Option<usize> {
+        let mut frame = [0; 1600];
+        let Some(n) = tx.compress(0x0021, data, &mut frame) else {
+            rx.incompressible(0x0021, data);
+            return None;
+        };
+        let mut out = [0; 1600];
+        assert_eq!(
+            rx.decompress(&frame[..n], &mut out),
+            Some((0x0021, data.len()))
+        );
+        assert_eq!(&out[..data.len()], data);
+        Some(n)
+    }
+
+    fn setup() -> (Deflate, Deflate) {
+        let (a, b) = (Deflate::new(), Deflate::new());
+        a.reset_tx();
+        b.reset_rx();
+        (a, b)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (a, b) = &setup();
+        let first = transfer(a, b, PACKET).unwrap();
+        // Found in the history of the previous packet.
+        assert!(transfer(a, b, PACKET).unwrap() < first / 4);
+
+        // Matches longer than MAX_MATCH, and every byte value.
+        let mut data = [0; 1500];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = if i < 600 { 7 } else { (i * 37 % 256) as u8 };
+        }
+        assert!(transfer(a, b, &data).unwrap() < data.len());
+        assert!(transfer(a, b, PACKET).is_some());
+    }
+
+    #[test]
+    fn incompressible() {
+        let (a, b) = &setup();
+        assert_eq!(transfer(a, b, b"x"), None);
+        // Still counted and in the history on both sides.
+        assert!(transfer(a, b, PACKET).is_some());
+        assert_eq!(transfer(a, b, b"y"), None);
+        assert!(transfer(a, b, PACKET).is_some());
+    }
+
+    #[test]
+    fn lost_packet() {
+        let (a, b) = &setup();
+        transfer(a, b, PACKET).unwrap();
+
+        // Lost on the link: the sequence number tells.
+        let mut frame = [0; 128];
+        a.compress(0x0021, PACKET, &mut frame).unwrap();
+        let n = a.compress(0x0021, PACKET, &mut frame).unwrap();
+        let mut out = [0; 128];
+        assert_eq!(b.decompress(&frame[..n], &mut out), None);
+
+        // Reset-Request, then Reset-Ack.
+        a.reset_tx();
+        b.reset_rx();
+        transfer(a, b, PACKET).unwrap();
+        transfer(a, b, PACKET).unwrap();
+    }
+
+    /// Packets compressed by zlib, with a sync flush after each as pppd and Linux do, to check
+    /// the decoder against another encoder. The last one has dynamic Huffman codes.
+    #[test]
+    fn zlib_packets() {
+        let mut text = [0; 150];
+        let mut x: u32 = 1;
+        for t in text.iter_mut() {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            *t = b"eeeeeeeetttaaoinsh"[(x >> 16) as usize % 18];
+        }
+        let packets: [(&[u8], &[u8]); 3] = [
+            (
+                PACKET,
+                &hex::<104>(concat!(
+                    "5274770d51d0cfcc4b49add0cb28c9cd51f0080909d037d433e4e5f2c82f2eb152282f2fd74bad48cc2dc849",
+                    "d54bcecfe5e50a4a4d4b2d4a2db252c8282929b0d2d747538064162f976372726a01d09092d48a127d90900e",
+                    "845990939899c7cbc5cb05000000ffff",
+                )),
+            ),
+            (PACKET, &hex::<10>("521c58eb01000000ffff")),
+            (
+                &text,
+                &hex::<88>(concat!(
+                    "1c8fb10d044108036bf9d2084682048275ff3afb49cc9ac1687f6225a6e028e1ea11b52a2ecff7acdbc82e63",
+                    "f5c80df6c4ddb0341422f411224812dddc6b130ea4c6dff91f706476446b2d7accd8dd1cac00e2030000ffff",
+                )),
+            ),
+        ];
+
+        let rx = Deflate::new();
+        rx.reset_rx();
+        for (seq, (data, compressed)) in packets.into_iter().enumerate() {
+            let mut frame = [0; 128];
+            frame[..SEQ_LEN].copy_from_slice(&(seq as u16).to_be_bytes());
+            frame[SEQ_LEN..][..compressed.len()].copy_from_slice(compressed);
+            let mut out = [0; 256];
+            let n = SEQ_LEN + compressed.len();
+            assert_eq!(
+                rx.decompress(&frame[..n], &mut out),
+                Some((0x0021, data.len()))
+            );
+            assert_eq!(&out[..data.len()], data);
+        }
+    }
+
+    #[test]
+    fn window_size() {
+        let (a, b) = &setup();
+        let mut data = [0; 4];
+        let n = b.rx_option(&mut data);
+        assert_eq!(a.tx_option(&data[..n], &mut []), OptionVerdict::Ack);
+
+        // A smaller window, suggested by the peer.
+        let mut nak = [0; 4];
+        let n = write_option(9, &mut nak);
+        assert!(b.rx_option_nacked(&nak[..n]));
+        let n = b.rx_option(&mut data);
+        assert_eq!(parse_option(&data[..n]), Some(9));
+        assert_eq!(a.tx_option(&data[..n], &mut []), OptionVerdict::Ack);
+        a.reset_tx();
+        b.reset_rx();
+        let mut long = [0; 1000];
+        for (i, x) in long.iter_mut().enumerate() {
+            *x = (i * 13 % 251) as u8;
+        }
+        assert!(transfer(a, b, &long).unwrap() < long.len());
+        assert!(transfer(a, b, &long).unwrap() < 100);
+    }
+}
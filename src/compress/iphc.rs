@@ -0,0 +1,982 @@
+//! IP Header Compression, RFC 2507, negotiated with IPv4CP and IPv6CP as in RFC 2509.
+//!
+//! Packets are grouped into streams, each with a context whose identifier (CID) is carried in
+//! every packet. TCP headers are compressed like with Van Jacobson compression, but over IPv4
+//! and IPv6. Headers of other streams, e.g. UDP, only carry the fields that change randomly,
+//! and their context is refreshed with a full header every now and then, as packets may be lost.
+
+use core::ops::Range;
+
+use super::{
+    apply_tcp_changes, be16, ihl, restore_header, set_ip_checksum, tcp_changes, tcp_compressible,
+    Reader, NEW_I,
+};
+use crate::time::{Duration, Instant};
+use crate::wire::ProtocolType;
+
+/// Contexts for TCP streams, and for non-TCP streams, in each direction.
+///
+/// Set at compile time with the `iphc-contexts-*` features. 0 if none is enabled, making
+/// IPHC unavailable.
+pub(crate) const MAX_CONTEXTS: usize = if cfg!(feature = "iphc-contexts-16") {
+    16
+} else if cfg!(feature = "iphc-contexts-8") {
+    8
+} else if cfg!(feature = "iphc-contexts-4") {
+    4
+} else if cfg!(feature = "iphc-contexts-2") {
+    2
+} else if cfg!(feature = "iphc-contexts-1") {
+    1
+} else {
+    0
+};
+
+/// Largest header we can compress or decompress: 60 bytes of IPv4 header and 60 of TCP header.
+pub(crate) const MAX_HEADER: usize = 120;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+// First length field of full headers: non-TCP context, and 16-bit CID in the second
+// length field. The rest of the byte is the generation.
+const FULL_NON_TCP: u8 = 0x80;
+const FULL_CID16: u8 = 0x40;
+
+// Compressed non-TCP headers, in the byte with the generation: 16-bit CID, and header data
+// following the random fields.
+const CID16: u8 = 0x80;
+const DATA: u8 = 0x40;
+const GENERATION_MASK: u8 = 0x3f;
+
+// Change mask bits of compressed TCP headers, besides those of RFC 1144: the R-octet with
+// the reserved bits, and the TCP options are present.
+const R_OCTET: u8 = 0x80;
+const OPTIONS: u8 = 0x40;
+
+// CONTEXT_STATE packets (RFC 2507 section 10.2): type of the listed contexts, and the flag
+// of each entry telling the context is invalid.
+const CONTEXT_STATE_TCP: u8 = 3;
+const CONTEXT_INVALID: u8 = 0x80;
+
+/// Longest CONTEXT_STATE packet we send: the type, the count and an entry per TCP context.
+pub(crate) const CONTEXT_STATE_LEN: usize = 2 + 2 * MAX_CONTEXTS;
+
+/// IPHC parameters, from the IP-Compression-Protocol option.
+///
+/// They're for the receiving side: in a Configure-Request, what the sender can decompress.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Params {
+    /// Largest CID of TCP contexts.
+    pub tcp_space: u16,
+    /// Largest CID of non-TCP contexts.
+    pub non_tcp_space: u16,
+    /// Most compressed non-TCP headers that may be sent between full headers.
+    pub f_max_period: u16,
+    /// Most seconds between full headers of a non-TCP stream.
+    pub f_max_time: u16,
+    /// Largest header that may be compressed.
+    pub max_header: u16,
+}
+
+impl Params {
+    /// What we can decompress.
+    pub const OURS: Params = Params {
+        tcp_space: MAX_CONTEXTS.saturating_sub(1) as u16,
+        non_tcp_space: MAX_CONTEXTS.saturating_sub(1) as u16,
+        f_max_period: 256,
+        f_max_time: 5,
+        max_header: MAX_HEADER as u16,
+    };
+
+    /// Parse IP-Compression-Protocol option data. Returns None if it's not IPHC.
+    ///
+    /// Suboptions are ignored: they enable compressing other protocols, e.g. RTP, which we don't.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let proto = u16::from(ProtocolType::IPHCFullHeader).to_be_bytes();
+        match *data {
+            [a, b, ref params @ ..] if [a, b] == proto && params.len() >= 10 => Some(Self {
+                tcp_space: be16(&params[0..]),
+                non_tcp_space: be16(&params[2..]),
+                f_max_period: be16(&params[4..]),
+                f_max_time: be16(&params[6..]),
+                max_header: be16(&params[8..]),
+            }),
+            _ => None,
+        }
+    }
+
+    /// IP-Compression-Protocol option data, without suboptions.
+    pub fn option_data(&self) -> [u8; 12] {
+        let mut data = [0; 12];
+        data[0..2].copy_from_slice(&u16::from(ProtocolType::IPHCFullHeader).to_be_bytes());
+        data[2..4].copy_from_slice(&self.tcp_space.to_be_bytes());
+        data[4..6].copy_from_slice(&self.non_tcp_space.to_be_bytes());
+        data[6..8].copy_from_slice(&self.f_max_period.to_be_bytes());
+        data[8..10].copy_from_slice(&self.f_max_time.to_be_bytes());
+        data[10..12].copy_from_slice(&self.max_header.to_be_bytes());
+        data
+    }
+
+    /// Adopt the parameters the peer suggested in a Configure-Nak of ours, but never more
+    /// contexts or larger headers than we asked for.
+    pub fn nacked(self, nak: Params) -> Params {
+        Params {
+            tcp_space: self.tcp_space.min(nak.tcp_space),
+            non_tcp_space: self.non_tcp_space.min(nak.non_tcp_space),
+            f_max_period: nak.f_max_period,
+            f_max_time: nak.f_max_time,
+            max_header: self.max_header.min(nak.max_header),
+        }
+    }
+}
+
+/// How a packet must be sent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum PacketType {
+    /// Unchanged, as a regular IPv4 or IPv6 packet.
+    Regular,
+    /// With the full header, to set up the state of a context.
+    FullHeader,
+    /// With a compressed TCP header.
+    CompressedTcp,
+    /// With a compressed non-TCP header.
+    CompressedNonTcp,
+}
+
+/// Last header seen on a stream.
+#[derive(Copy, Clone)]
+struct Context {
+    header: [u8; MAX_HEADER],
+    /// Length of the header, 0 if the context is unused.
+    len: usize,
+    /// Length of the IP header.
+    ip_len: usize,
+    /// When the context was last used, to reuse the least recently used one.
+    last_used: u32,
+    /// Generation of a non-TCP context, changed whenever a header differs from the last one.
+    generation: u8,
+    /// Compressed headers sent since the last full header, for a non-TCP context.
+    compressed: u16,
+    /// Compressed headers to send before the next full header. It doubles after every full
+    /// header, up to F_MAX_PERIOD, so that the peer gets a new context quickly even if packets
+    /// are lost (compression slow-start, RFC 2507 section 7.5).
+    period: u16,
+    /// When the last full header was sent.
+    full_at: Instant,
+}
+
+impl Context {
+    const EMPTY: Context = Context {
+        header: [0; MAX_HEADER],
+        len: 0,
+        ip_len: 0,
+        last_used: 0,
+        generation: 0,
+        compressed: 0,
+        period: 0,
+        full_at: Instant::ZERO,
+    };
+
+    fn header(&self) -> &[u8] {
+        &self.header[..self.len]
+    }
+
+    fn save(&mut self, pkt: &[u8], h: &Headers, clock: u32) {
+        self.header[..h.len].copy_from_slice(&pkt[..h.len]);
+        self.len = h.len;
+        self.ip_len = h.ip_len;
+        self.last_used = clock;
+    }
+
+    /// Whether `pkt` belongs to this context's stream: same addresses, protocol and ports.
+    fn matches(&self, pkt: &[u8], h: &Headers) -> bool {
+        let old = self.header();
+        if old.is_empty() || old[0] >> 4 != pkt[0] >> 4 || protocol(old) != h.protocol {
+            return false;
+        }
+        let addresses = if pkt[0] >> 4 == 4 { 12..20 } else { 8..40 };
+        let ports = match h.protocol {
+            TCP | UDP => h.ip_len..h.ip_len + 4,
+            _ => 0..0,
+        };
+        old[addresses.clone()] == pkt[addresses] && old[self.ip_len..][..ports.len()] == pkt[ports]
+    }
+
+    /// Whether the IP fields of `pkt` that can't be compressed are the same as in this context.
+    fn same_ip_fields(&self, pkt: &[u8], h: &Headers) -> bool {
+        let old = self.header();
+        self.ip_len == h.ip_len
+            && if pkt[0] >> 4 == 4 {
+                pkt[0..2] == old[0..2]
+                    && pkt[6..10] == old[6..10]
+                    && pkt[20..h.ip_len] == old[20..h.ip_len]
+            } else {
+                pkt[0..4] == old[0..4] && pkt[6..8] == old[6..8]
+            }
+    }
+}
+
+/// Compresses the headers of the packets we send.
+pub(crate) struct Compressor {
+    tcp: [Context; MAX_CONTEXTS],
+    non_tcp: [Context; MAX_CONTEXTS],
+    /// Parameters the peer asked for with IPv4CP and IPv6CP. None if not negotiated.
+    ipv4: Option<Params>,
+    ipv6: Option<Params>,
+    clock: u32,
+}
+
+impl Compressor {
+    pub const fn new() -> Self {
+        Self {
+            tcp: [Context::EMPTY; MAX_CONTEXTS],
+            non_tcp: [Context::EMPTY; MAX_CONTEXTS],
+            ipv4: None,
+            ipv6: None,
+            clock: 0,
+        }
+    }
+
+    /// Start compressing IPv4 packets with the parameters negotiated with IPv4CP, or stop if None.
+    pub fn set_ipv4(&mut self, params: Option<Params>) {
+        self.ipv4 = params;
+        self.clear();
+    }
+
+    /// Start compressing IPv6 packets with the parameters negotiated with IPv6CP, or stop if None.
+    pub fn set_ipv6(&mut self, params: Option<Params>) {
+        self.ipv6 = params;
+        self.clear();
+    }
+
+    /// Forget all contexts, e.g. because a packet could not be sent after compressing it.
+    ///
+    /// The next packet of each stream is sent with a full header, resynchronizing the peer.
+    pub fn clear(&mut self) {
+        self.tcp = [Context::EMPTY; MAX_CONTEXTS];
+        self.non_tcp = [Context::EMPTY; MAX_CONTEXTS];
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ipv4.is_some() || self.ipv6.is_some()
+    }
+
+    /// The peer sent a CONTEXT_STATE packet: some of its TCP contexts are out of sync.
+    ///
+    /// Those get a full header next, or all of them if the packet can't be parsed. Non-TCP
+    /// contexts recover with the periodic full headers.
+    pub fn context_state(&mut self, data: &[u8]) {
+        match *data {
+            [CONTEXT_STATE_TCP, count, ref entries @ ..] if entries.len() >= 2 * count as usize => {
+                for entry in entries.chunks_exact(2).take(count as usize) {
+                    if entry[1] & CONTEXT_INVALID != 0 {
+                        debug!("IPHC: peer lost TCP context {}", entry[0]);
+                        if let Some(ctx) = self.tcp.get_mut(entry[0] as usize) {
+                            *ctx = Context::EMPTY;
+                        }
+                    }
+                }
+            }
+            [1 | 2, ..] => {}
+            _ => {
+                debug!("IPHC: peer lost TCP contexts");
+                self.tcp = [Context::EMPTY; MAX_CONTEXTS];
+            }
+        }
+    }
+
+    /// Number of TCP and non-TCP contexts we can use. The CID spaces are shared by IPv4 and IPv6.
+    fn contexts(&self) -> (usize, usize) {
+        [self.ipv4, self.ipv6].iter().flatten().fold(
+            (MAX_CONTEXTS, MAX_CONTEXTS),
+            |(tcp, non_tcp), params| {
+                (
+                    tcp.min(params.tcp_space as usize + 1),
+                    non_tcp.min(params.non_tcp_space as usize + 1),
+                )
+            },
+        )
+    }
+
+    /// Compress the header of an IPv4 or IPv6 packet.
+    ///
+    /// The header to send is written to `out[..n]`, to be followed by `pkt[consumed..]`.
+    /// Returns the packet type, `n` and `consumed`.
+    pub fn compress(
+        &mut self,
+        pkt: &[u8],
+        out: &mut [u8; MAX_HEADER],
+        now: Instant,
+    ) -> (PacketType, usize, usize) {
+        let Some(h) = parse_headers(pkt) else {
+            return (PacketType::Regular, 0, 0);
+        };
+        let params = if pkt[0] >> 4 == 4 {
+            self.ipv4
+        } else {
+            self.ipv6
+        };
+        match params {
+            Some(params) if h.len <= params.max_header as usize => {
+                self.clock = self.clock.wrapping_add(1);
+                if h.protocol == TCP {
+                    self.compress_tcp(pkt, &h, out)
+                } else {
+                    self.compress_non_tcp(pkt, &h, &params, out, now)
+                }
+            }
+            _ => (PacketType::Regular, 0, 0),
+        }
+    }
+
+    fn compress_tcp(
+        &mut self,
+        pkt: &[u8],
+        h: &Headers,
+        out: &mut [u8; MAX_HEADER],
+    ) -> (PacketType, usize, usize) {
+        let th = &pkt[h.ip_len..];
+        let (num_contexts, _) = self.contexts();
+        let contexts = &mut self.tcp[..num_contexts];
+        if contexts.is_empty() || !tcp_compressible(th) {
+            return (PacketType::Regular, 0, 0);
+        }
+
+        let Some(cid) = contexts.iter().position(|ctx| ctx.matches(pkt, h)) else {
+            let cid = least_recently_used(contexts, self.clock);
+            return full_header(&mut contexts[cid], cid, 0, pkt, h, self.clock, out);
+        };
+        let ctx = &mut contexts[cid];
+        if !ctx.same_ip_fields(pkt, h) {
+            return full_header(ctx, cid, 0, pkt, h, self.clock, out);
+        }
+
+        let old = ctx.header();
+        let old_data_len = packet_len(old) - ctx.len;
+        let changes = tcp_changes(th, &old[ctx.ip_len..], pkt.len() - h.len, old_data_len);
+        let Some((mut changes, mut deltas)) = changes else {
+            return full_header(ctx, cid, 0, pkt, h, self.clock, out);
+        };
+        if pkt[0] >> 4 == 4 {
+            let delta_i = be16(&pkt[4..]).wrapping_sub(be16(&old[4..]));
+            if delta_i != 1 {
+                deltas.encodez(delta_i);
+                changes |= NEW_I;
+            }
+        }
+
+        out[0] = cid as u8;
+        out[1] = changes;
+        out[2..4].copy_from_slice(&th[16..18]);
+        let n = 4 + deltas.as_bytes().len();
+        out[4..n].copy_from_slice(deltas.as_bytes());
+
+        ctx.save(pkt, h, self.clock);
+        (PacketType::CompressedTcp, n, h.len)
+    }
+
+    fn compress_non_tcp(
+        &mut self,
+        pkt: &[u8],
+        h: &Headers,
+        params: &Params,
+        out: &mut [u8; MAX_HEADER],
+        now: Instant,
+    ) -> (PacketType, usize, usize) {
+        let (_, num_contexts) = self.contexts();
+        let contexts = &mut self.non_tcp[..num_contexts];
+        if contexts.is_empty() {
+            return (PacketType::Regular, 0, 0);
+        }
+
+        let found = contexts.iter().position(|ctx| ctx.matches(pkt, h));
+        let cid = found.unwrap_or_else(|| least_recently_used(contexts, self.clock));
+        let ctx = &mut contexts[cid];
+
+        // The UDP checksum is sent in compressed headers only if it's enabled.
+        let checksum = udp_checksum(pkt, h);
+        let changed = found.is_none()
+            || !ctx.same_ip_fields(pkt, h)
+            || checksum.map(|c| c == [0, 0]) != udp_checksum(ctx.header(), h).map(|c| c == [0, 0]);
+
+        if changed {
+            ctx.generation = (ctx.generation + 1) & GENERATION_MASK;
+            ctx.period = 1.min(params.f_max_period);
+            return self.full_non_tcp(cid, pkt, h, now, out);
+        }
+        let refresh_at = ctx.full_at + Duration::from_secs(params.f_max_time as u64);
+        if ctx.compressed >= ctx.period || now >= refresh_at {
+            ctx.period = ctx.period.saturating_mul(2).min(params.f_max_period);
+            return self.full_non_tcp(cid, pkt, h, now, out);
+        }
+
+        out[0] = cid as u8;
+        out[1] = ctx.generation;
+        let mut n = 2;
+        if pkt[0] >> 4 == 4 {
+            out[n..n + 2].copy_from_slice(&pkt[4..6]);
+            n += 2;
+        }
+        if let Some(checksum) = checksum.filter(|c| *c != [0, 0]) {
+            out[n..n + 2].copy_from_slice(&checksum);
+            n += 2;
+        }
+
+        ctx.compressed += 1;
+        ctx.last_used = self.clock;
+        (PacketType::CompressedNonTcp, n, h.len)
+    }
+
+    fn full_non_tcp(
+        &mut self,
+        cid: usize,
+        pkt: &[u8],
+        h: &Headers,
+        now: Instant,
+        out: &mut [u8; MAX_HEADER],
+    ) -> (PacketType, usize, usize) {
+        let ctx = &mut self.non_tcp[cid];
+        ctx.compressed = 0;
+        ctx.full_at = now;
+        let first = FULL_NON_TCP | ctx.generation;
+        full_header(ctx, cid, first, pkt, h, self.clock, out)
+    }
+}
+
+/// Send the full header of a packet, saving it in its context.
+///
+/// The CID and the first byte of the first length field are carried in the length field of
+/// the IP header.
+fn full_header(
+    ctx: &mut Context,
+    cid: usize,
+    first: u8,
+    pkt: &[u8],
+    h: &Headers,
+    clock: u32,
+    out: &mut [u8; MAX_HEADER],
+) -> (PacketType, usize, usize) {
+    ctx.save(pkt, h, clock);
+    out[..h.len].copy_from_slice(&pkt[..h.len]);
+    let field = length_field(pkt);
+    out[field] = first;
+    out[field + 1] = cid as u8;
+    (PacketType::FullHeader, h.len, h.len)
+}
+
+/// Find a context for a new stream: an unused one, or else the least recently used.
+fn least_recently_used(contexts: &[Context], clock: u32) -> usize {
+    contexts
+        .iter()
+        .position(|ctx| ctx.len == 0)
+        .or_else(|| (0..contexts.len()).min_by_key(|&i| clock.wrapping_sub(contexts[i].last_used)))
+        .map_or(0, |i| i)
+}
+
+/// Decompresses the headers of the packets we receive.
+pub(crate) struct Decompressor {
+    tcp: [Context; MAX_CONTEXTS],
+    non_tcp: [Context; MAX_CONTEXTS],
+    /// Whether the peer agreed to compress IPv4 and IPv6 packets.
+    ipv4: bool,
+    ipv6: bool,
+    /// TCP contexts that may be out of sync after a frame was lost, a bit per CID. Their
+    /// COMPRESSED_TCP packets are dropped until the peer sends a full header.
+    invalid_tcp: u16,
+    /// Whether to tell the peer about the invalid contexts.
+    send_context_state: bool,
+}
+
+impl Decompressor {
+    pub const fn new() -> Self {
+        Self {
+            tcp: [Context::EMPTY; MAX_CONTEXTS],
+            non_tcp: [Context::EMPTY; MAX_CONTEXTS],
+            ipv4: false,
+            ipv6: false,
+            invalid_tcp: 0,
+            send_context_state: false,
+        }
+    }
+
+    /// Start or stop decompressing IPv4 packets, as negotiated with IPv4CP.
+    pub fn set_ipv4(&mut self, enabled: bool) {
+        self.ipv4 = enabled;
+        self.clear_if_disabled();
+    }
+
+    /// Start or stop decompressing IPv6 packets, as negotiated with IPv6CP.
+    pub fn set_ipv6(&mut self, enabled: bool) {
+        self.ipv6 = enabled;
+        self.clear_if_disabled();
+    }
+
+    /// Forget all contexts once no protocol uses them. They're shared by IPv4 and IPv6, the
+    /// peer may still be using some while the other protocol is renegotiated.
+    fn clear_if_disabled(&mut self) {
+        if !self.is_enabled() {
+            self.tcp = [Context::EMPTY; MAX_CONTEXTS];
+            self.non_tcp = [Context::EMPTY; MAX_CONTEXTS];
+            self.invalid_tcp = 0;
+            self.send_context_state = false;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ipv4 || self.ipv6
+    }
+
+    /// A frame was received with errors. It may have been one of ours, so the TCP contexts in
+    /// use are out of sync until the peer sends their full header again.
+    pub fn toss(&mut self) {
+        for (cid, ctx) in self.tcp.iter().enumerate() {
+            if ctx.len != 0 {
+                self.invalid_tcp |= 1 << cid;
+                self.send_context_state = true;
+            }
+        }
+    }
+
+    fn is_invalid(&self, cid: usize) -> bool {
+        cid < self.tcp.len() && self.invalid_tcp & 1 << cid != 0
+    }
+
+    /// Write the CONTEXT_STATE packet listing the invalid TCP contexts to `buf`, if the peer
+    /// must be told about them (RFC 2507 section 10.2).
+    ///
+    /// Returns its length.
+    pub fn context_state(&mut self, buf: &mut [u8; CONTEXT_STATE_LEN]) -> Option<usize> {
+        if !core::mem::take(&mut self.send_context_state)
+            || !self.is_enabled()
+            || self.invalid_tcp == 0
+        {
+            return None;
+        }
+        buf[0] = CONTEXT_STATE_TCP;
+        let mut n = 2;
+        for cid in (0..MAX_CONTEXTS).filter(|&cid| self.is_invalid(cid)) {
+            buf[n] = cid as u8;
+            buf[n + 1] = CONTEXT_INVALID;
+            n += 2;
+        }
+        buf[1] = (n / 2 - 1) as u8;
+        Some(n)
+    }
+
+    /// Handle a FULL_HEADER packet, restoring its length fields in place.
+    ///
+    /// Returns false if it's invalid.
+    pub fn full_header(&mut self, pkt: &mut [u8]) -> bool {
+        let ok = self.restore_full_header(pkt).is_some();
+        if !ok {
+            warn!("IPHC: invalid FULL_HEADER packet");
+        }
+        ok
+    }
+
+    fn restore_full_header(&mut self, pkt: &mut [u8]) -> Option<()> {
+        let (enabled, min_len) = match pkt.first()? >> 4 {
+            4 => (self.ipv4, 20),
+            6 => (self.ipv6, 40),
+            _ => return None,
+        };
+        let ip_len = ip_header_len(pkt);
+        if !enabled || pkt.len() < min_len || ip_len < min_len || pkt.len() < ip_len {
+            return None;
+        }
+        let field = length_field(pkt);
+        let first = pkt[field];
+        let cid = if first & (FULL_NON_TCP | FULL_CID16) == FULL_NON_TCP | FULL_CID16 {
+            // 16-bit CID, in the UDP length field.
+            let udp = pkt
+                .get(ip_len..ip_len + 8)
+                .filter(|_| protocol(pkt) == UDP)?;
+            be16(&udp[4..]) as usize
+        } else {
+            pkt[field + 1] as usize
+        };
+
+        // The other headers are parsed with the lengths restored.
+        set_lengths(pkt, pkt.len());
+        let h = parse_headers(pkt)?;
+
+        let ctx = if first & FULL_NON_TCP == 0 {
+            let ctx = self.tcp.get_mut(cid).filter(|_| h.protocol == TCP)?;
+            self.invalid_tcp &= !(1 << cid);
+            ctx
+        } else {
+            let ctx = self.non_tcp.get_mut(cid).filter(|_| h.protocol != TCP)?;
+            ctx.generation = first & GENERATION_MASK;
+            ctx
+        };
+        ctx.save(pkt, &h, 0);
+        Some(())
+    }
+
+    /// Decompress the COMPRESSED_TCP packet at `buf[range]`, in place.
+    ///
+    /// The restored header is larger than the compressed one, so the data is moved towards the
+    /// end of `buf`. Returns the range of the restored packet, or None if it must be dropped.
+    pub fn compressed_tcp(&mut self, buf: &mut [u8], range: Range<usize>) -> Option<Range<usize>> {
+        let pkt = &buf[range.clone()];
+        if pkt
+            .first()
+            .is_some_and(|&cid| self.is_invalid(cid as usize))
+        {
+            // Expected after losing a frame, until the peer gets our CONTEXT_STATE. Tell it
+            // again in case that was lost too.
+            debug!("IPHC: dropping COMPRESSED_TCP packet with invalid context");
+            self.send_context_state = true;
+            return None;
+        }
+        let mut r = Reader { data: pkt, pos: 0 };
+        let Some((cid, mut header)) = self.decode_tcp(&mut r) else {
+            warn!("IPHC: invalid COMPRESSED_TCP packet");
+            return None;
+        };
+
+        let hlen = self.tcp[cid].len;
+        set_lengths(&mut header[..hlen], hlen + pkt.len() - r.pos);
+        let compressed_len = r.pos;
+        // Only update the context once the packet is known to be delivered.
+        let range = restore_header(buf, range, compressed_len, &header[..hlen])?;
+        self.tcp[cid].header = header;
+        Some(range)
+    }
+
+    /// Decode a compressed TCP header, applying the changes to a copy of its context's header.
+    ///
+    /// Returns the CID and the new header.
+    fn decode_tcp(&self, r: &mut Reader<'_>) -> Option<(usize, [u8; MAX_HEADER])> {
+        let cid = r.u8()? as usize;
+        let changes = r.u8()?;
+        let ctx = self.tcp.get(cid).filter(|ctx| ctx.len != 0)?;
+        let ipv4 = ctx.header[0] >> 4 == 4;
+        // Changes of the reserved bits and options aren't supported, we'd never send them.
+        if changes & (R_OCTET | OPTIONS) != 0 || (!ipv4 && changes & NEW_I != 0) {
+            return None;
+        }
+
+        let mut h = ctx.header;
+        let t = ctx.ip_len;
+        let checksum = r.u16()?;
+        h[t + 16..t + 18].copy_from_slice(&checksum.to_be_bytes());
+        let old_data_len = packet_len(ctx.header()) - ctx.len;
+        apply_tcp_changes(changes, &mut h[t..], old_data_len, r)?;
+
+        if ipv4 {
+            let delta_i = if changes & NEW_I != 0 { r.delta()? } else { 1 };
+            let id = be16(&h[4..]).wrapping_add(delta_i);
+            h[4..6].copy_from_slice(&id.to_be_bytes());
+        }
+        Some((cid, h))
+    }
+
+    /// Decompress the COMPRESSED_NON_TCP packet at `buf[range]`, in place, like
+    /// [`compressed_tcp()`](Self::compressed_tcp).
+    pub fn compressed_non_tcp(
+        &mut self,
+        buf: &mut [u8],
+        range: Range<usize>,
+    ) -> Option<Range<usize>> {
+        let pkt = &buf[range.clone()];
+        let mut r = Reader { data: pkt, pos: 0 };
+        let Some(cid) = self.decode_non_tcp(&mut r) else {
+            // Expected after losing the full header of a new generation, until the next one.
+            debug!("IPHC: dropping COMPRESSED_NON_TCP packet with unknown context");
+            return None;
+        };
+
+        let ctx = &mut self.non_tcp[cid];
+        let len = ctx.len + pkt.len() - r.pos;
+        set_lengths(&mut ctx.header[..ctx.len], len);
+        let compressed_len = r.pos;
+        restore_header(buf, range, compressed_len, ctx.header())
+    }
+
+    /// Decode a compressed non-TCP header, writing the random fields into its context's header.
+    ///
+    /// Returns the CID.
+    fn decode_non_tcp(&mut self, r: &mut Reader<'_>) -> Option<usize> {
+        let first = r.u8()?;
+        let flags = r.u8()?;
+        let cid = if flags & CID16 != 0 {
+            u16::from_be_bytes([first, r.u8()?]) as usize
+        } else {
+            first as usize
+        };
+        let ctx = self.non_tcp.get_mut(cid).filter(|ctx| ctx.len != 0)?;
+        if flags & DATA != 0 || flags & GENERATION_MASK != ctx.generation {
+            return None;
+        }
+
+        let t = ctx.ip_len;
+        if ctx.header[0] >> 4 == 4 {
+            let id = r.u16()?;
+            ctx.header[4..6].copy_from_slice(&id.to_be_bytes());
+        }
+        if protocol(ctx.header()) == UDP && ctx.header[t + 6..t + 8] != [0, 0] {
+            let checksum = r.u16()?;
+            ctx.header[t + 6..t + 8].copy_from_slice(&checksum.to_be_bytes());
+        }
+        Some(cid)
+    }
+}
+
+/// The compressible headers of a packet.
+struct Headers {
+    /// Length of the IP header.
+    ip_len: usize,
+    /// Length of the IP header and the TCP or UDP header, if any.
+    len: usize,
+    /// Protocol following the IP header.
+    protocol: u8,
+}
+
+/// Parse the headers of a packet that can be compressed. Returns None if it can't.
+///
+/// Only the IP header and a TCP or UDP header directly following it are compressed. IPv6
+/// extension headers are left in the payload.
+fn parse_headers(pkt: &[u8]) -> Option<Headers> {
+    match pkt.first()? >> 4 {
+        // Fragments, and packets whose length we couldn't restore, can't be compressed.
+        4 if pkt.len() >= 20
+            && ihl(pkt) >= 20
+            && be16(&pkt[6..]) & 0x3fff == 0
+            && be16(&pkt[2..]) as usize == pkt.len() => {}
+        6 if pkt.len() >= 40 && be16(&pkt[4..]) as usize + 40 == pkt.len() => {}
+        _ => return None,
+    }
+
+    let ip_len = ip_header_len(pkt);
+    let protocol = protocol(pkt);
+    let len = match protocol {
+        TCP => match *pkt.get(ip_len + 12)? >> 4 {
+            n if n >= 5 => ip_len + n as usize * 4,
+            _ => return None,
+        },
+        UDP => ip_len + 8,
+        _ => ip_len,
+    };
+    if len > pkt.len() || len > MAX_HEADER {
+        return None;
+    }
+    Some(Headers {
+        ip_len,
+        len,
+        protocol,
+    })
+}
+
+fn ip_header_len(pkt: &[u8]) -> usize {
+    if pkt[0] >> 4 == 4 {
+        ihl(pkt)
+    } else {
+        40
+    }
+}
+
+/// UDP checksum of a packet, None if it's not UDP.
+fn udp_checksum(pkt: &[u8], h: &Headers) -> Option<[u8; 2]> {
+    match h.protocol {
+        UDP => Some([pkt[h.ip_len + 6], pkt[h.ip_len + 7]]),
+        _ => None,
+    }
+}
+
+/// Protocol following the IP header.
+fn protocol(pkt: &[u8]) -> u8 {
+    if pkt[0] >> 4 == 4 {
+        pkt[9]
+    } else {
+        pkt[6]
+    }
+}
+
+/// Offset of the first length field: IPv4 Total Length or IPv6 Payload Length.
+fn length_field(pkt: &[u8]) -> usize {
+    if pkt[0] >> 4 == 4 {
+        2
+    } else {
+        4
+    }
+}
+
+/// Length of the packet, from the header's length field.
+fn packet_len(header: &[u8]) -> usize {
+    if header[0] >> 4 == 4 {
+        be16(&header[2..]) as usize
+    } else {
+        be16(&header[4..]) as usize + 40
+    }
+}
+
+/// Set the length fields of a header for a packet of `len` bytes, which are inferred from the
+/// packet's length: IPv4 Total Length and header checksum, IPv6 Payload Length, UDP Length.
+fn set_lengths(header: &mut [u8], len: usize) {
+    let ip_len = ip_header_len(header);
+    if header[0] >> 4 == 4 {
+        header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        set_ip_checksum(&mut header[..ip_len]);
+    } else {
+        header[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+    }
+    if protocol(header) == UDP && header.len() >= ip_len + 8 {
+        header[ip_len + 4..ip_len + 6].copy_from_slice(&((len - ip_len) as u16).to_be_bytes());
+    }
+}
+
+// IPHC is only available with one of the `iphc-contexts-*` features.
+#[cfg(all(
+    test,
+    any(
+        feature = "iphc-contexts-1",
+        feature = "iphc-contexts-2",
+        feature = "iphc-contexts-4",
+        feature = "iphc-contexts-8",
+        feature = "iphc-contexts-16"
+    )
+))]
+mod tests {
+    use super::*;
+    use crate::compress::tcp4;
+
+    fn setup() -> (Compressor, Decompressor) {
+        let mut c = Compressor::new();
+        let mut d = Decompressor::new();
+        c.set_ipv4(Some(Params::OURS));
+        d.set_ipv4(true);
+        (c, d)
+    }
+
+    /// Compress `pkt` into `frame`, the way PPPoS sends it. Returns the packet type and length.
+    fn send(c: &mut Compressor, pkt: &[u8], frame: &mut [u8]) -> (PacketType, usize) {
+        let mut header = [0; MAX_HEADER];
+        let (ty, n, consumed) = c.compress(pkt, &mut header, Instant::ZERO);
+        frame[..n].copy_from_slice(&header[..n]);
+        let len = n + pkt.len() - consumed;
+        frame[n..len].copy_from_slice(&pkt[consumed..]);
+        (ty, len)
+    }
+
+    /// Decompress the frame at `buf[..len]`, returning the restored packet.
+    fn receive<'a>(
+        d: &mut Decompressor,
+        ty: PacketType,
+        buf: &'a mut [u8],
+        len: usize,
+    ) -> Option<&'a [u8]> {
+        let range = match ty {
+            PacketType::Regular => 0..len,
+            PacketType::FullHeader => d.full_header(&mut buf[..len]).then_some(0..len)?,
+            PacketType::CompressedTcp => d.compressed_tcp(buf, 0..len)?,
+            PacketType::CompressedNonTcp => d.compressed_non_tcp(buf, 0..len)?,
+        };
+        Some(&buf[range])
+    }
+
+    /// The i-th segment of a bulk transfer, acking some data now and then.
+    fn segment(buf: &mut [u8], i: u32) -> usize {
+        let data = [i as u8; 50];
+        let data_len = [50, 50, 20, 0][i as usize % 4];
+        let seq = 1000 + (0..i).map(|j| [50, 50, 20, 0][j as usize % 4]).sum::<u32>();
+        let ack = 5000 + i / 3 * 300;
+        tcp4(buf, (7 * i) as u16, seq, ack, &data[..data_len])
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut c, mut d) = setup();
+        for i in 0..40 {
+            let mut pkt = [0; 256];
+            let len = segment(&mut pkt, i);
+            let mut buf = [0; 256];
+            let (ty, n) = send(&mut c, &pkt[..len], &mut buf);
+            let expected = if i == 0 {
+                PacketType::FullHeader
+            } else {
+                PacketType::CompressedTcp
+            };
+            assert_eq!(ty, expected);
+            assert!(n < len || i == 0);
+            assert_eq!(receive(&mut d, ty, &mut buf, n), Some(&pkt[..len]));
+        }
+    }
+
+    #[test]
+    fn lost_packet() {
+        let (mut c, mut d) = setup();
+        let mut context_state = [0; CONTEXT_STATE_LEN];
+        let mut buf = [0; 256];
+        for i in 0..6 {
+            let mut pkt = [0; 256];
+            let len = segment(&mut pkt, i);
+            let (ty, n) = send(&mut c, &pkt[..len], &mut buf);
+            let received = match i {
+                // Lost on the link: the frame had errors.
+                1 => {
+                    d.toss();
+                    let n = d.context_state(&mut context_state).unwrap();
+                    assert_eq!(
+                        context_state[..n],
+                        [CONTEXT_STATE_TCP, 1, 0, CONTEXT_INVALID]
+                    );
+                    continue;
+                }
+                // Sent before the peer gets the CONTEXT_STATE.
+                2 => None,
+                3 => {
+                    assert_eq!(ty, PacketType::FullHeader);
+                    Some(&pkt[..len])
+                }
+                _ => Some(&pkt[..len]),
+            };
+            assert_eq!(receive(&mut d, ty, &mut buf, n), received, "packet {}", i);
+            if i == 2 {
+                // Sent again after dropping a packet.
+                let n = d.context_state(&mut context_state).unwrap();
+                c.context_state(&context_state[..n]);
+            }
+        }
+        // In sync again, nothing to report.
+        assert_eq!(d.context_state(&mut context_state), None);
+    }
+
+    #[test]
+    fn malformed_full_header() {
+        let (mut c, mut d) = setup();
+        let mut pkt = [0; 256];
+        let len = segment(&mut pkt, 0);
+        let mut buf = [0; 256];
+        let (_, n) = send(&mut c, &pkt[..len], &mut buf);
+
+        // IHL too small, and larger than the packet.
+        for (first, len) in [(0x40, n), (0x44, n), (0x4f, 40)] {
+            let mut frame = buf;
+            frame[0] = first;
+            assert!(!d.full_header(&mut frame[..len]));
+        }
+        assert!(!d.full_header(&mut buf[..10]));
+        assert!(d.full_header(&mut buf[..n]));
+    }
+
+    #[test]
+    fn no_room_to_decompress() {
+        let (mut c, mut d) = setup();
+        let mut buf = [0; 256];
+        for i in 0..3 {
+            let mut pkt = [0; 256];
+            let len = segment(&mut pkt, i);
+            let (ty, n) = send(&mut c, &pkt[..len], &mut buf);
+            if i == 1 {
+                // The dropped packet must not update the context.
+                let mut frame = buf;
+                assert_eq!(d.compressed_tcp(&mut frame[..n], 0..n), None);
+            }
+            assert_eq!(receive(&mut d, ty, &mut buf, n), Some(&pkt[..len]));
+        }
+    }
+}
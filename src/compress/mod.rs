@@ -0,0 +1,318 @@
+//! Compression of the network-layer packets carried over the link.
+//!
+//! Header compression state lives next to the framing, and is only used once the corresponding
+//! option has been negotiated by the network control protocol. Packet compression algorithms
+//! negotiated with CCP implement [`Compressor`](crate::Compressor).
+
+#[cfg(feature = "deflate")]
+mod deflate;
+pub(crate) mod iphc;
+mod predictor;
+pub(crate) mod vj;
+
+#[cfg(feature = "deflate")]
+pub use self::deflate::Deflate;
+pub use self::predictor::Predictor1;
+
+use core::ops::Range;
+
+// TCP change mask bits, RFC 1144 section 3.2.2. IPHC uses the same ones for TCP.
+const NEW_I: u8 = 0x20;
+const TCP_PUSH_BIT: u8 = 0x10;
+const NEW_S: u8 = 0x08;
+const NEW_A: u8 = 0x04;
+const NEW_W: u8 = 0x02;
+const NEW_U: u8 = 0x01;
+
+// Combinations of changes that can't happen, used to encode common cases.
+/// Echoed interactive traffic: sequence and ack numbers grew by the previous packet's length.
+const SPECIAL_I: u8 = NEW_S | NEW_W | NEW_U;
+/// Unidirectional data transfer: the sequence number grew by the previous packet's length.
+const SPECIAL_D: u8 = NEW_S | NEW_A | NEW_W | NEW_U;
+const SPECIALS_MASK: u8 = NEW_S | NEW_A | NEW_W | NEW_U;
+
+// TCP flags.
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PUSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_URG: u8 = 0x20;
+
+/// Whether a TCP segment can be compressed: connection setup and teardown are rare, they're
+/// not worth it.
+fn tcp_compressible(th: &[u8]) -> bool {
+    th[13] & (TCP_SYN | TCP_FIN | TCP_RST | TCP_ACK) == TCP_ACK
+}
+
+/// Encode the changes of the TCP header `th` from `old_th`, the previous one of its connection.
+///
+/// `data_len` and `old_data_len` are the lengths of the segments' data. Returns the change mask
+/// and the deltas, or None if the changes can't be encoded and the full header must be sent.
+fn tcp_changes(
+    th: &[u8],
+    old_th: &[u8],
+    data_len: usize,
+    old_data_len: usize,
+) -> Option<(u8, Deltas)> {
+    // The data offset, the flags other than PUSH and URG, and the options can't be encoded as
+    // changes. The TCP checksum is always sent.
+    let tcp_len = (th[12] >> 4) as usize * 4;
+    if th[12] != old_th[12]
+        || th[13] & !(TCP_PUSH | TCP_URG) != old_th[13] & !(TCP_PUSH | TCP_URG)
+        || th[20..tcp_len] != old_th[20..tcp_len]
+    {
+        return None;
+    }
+
+    let mut deltas = Deltas::new();
+    let mut changes = 0;
+
+    if th[13] & TCP_URG != 0 {
+        deltas.encode(be16(&th[18..]));
+        changes |= NEW_U;
+    } else if th[18..20] != old_th[18..20] {
+        // The urgent pointer changed without URG set: can't encode that.
+        return None;
+    }
+
+    let delta_w = be16(&th[14..]).wrapping_sub(be16(&old_th[14..]));
+    if delta_w != 0 {
+        deltas.encodez(delta_w);
+        changes |= NEW_W;
+    }
+
+    let delta_a = be32(&th[8..]).wrapping_sub(be32(&old_th[8..]));
+    if delta_a != 0 {
+        if delta_a > 0xffff {
+            return None;
+        }
+        deltas.encode(delta_a as u16);
+        changes |= NEW_A;
+    }
+
+    let delta_s = be32(&th[4..]).wrapping_sub(be32(&old_th[4..]));
+    if delta_s != 0 {
+        if delta_s > 0xffff {
+            return None;
+        }
+        deltas.encode(delta_s as u16);
+        changes |= NEW_S;
+    }
+
+    let old_data_len = old_data_len as u32;
+    match changes {
+        // Nothing changed. If this segment has data and the last one didn't, it's likely
+        // data following an ack, send it compressed. Otherwise it's probably a retransmission
+        // or a window probe: send the full header, in case the peer missed the last one.
+        0 if data_len != 0 && old_data_len == 0 => {}
+        0 | SPECIAL_I | SPECIAL_D => return None,
+        _ if changes == NEW_S | NEW_A && delta_s == delta_a && delta_s == old_data_len => {
+            changes = SPECIAL_I;
+            deltas.clear();
+        }
+        NEW_S if delta_s == old_data_len => {
+            changes = SPECIAL_D;
+            deltas.clear();
+        }
+        _ => {}
+    }
+
+    if th[13] & TCP_PUSH != 0 {
+        changes |= TCP_PUSH_BIT;
+    }
+    Some((changes, deltas))
+}
+
+/// Apply the changes encoded by [`tcp_changes`] to the TCP header `th`, reading the deltas
+/// from `r`.
+///
+/// `old_data_len` is the length of the previous segment's data.
+fn apply_tcp_changes(
+    changes: u8,
+    th: &mut [u8],
+    old_data_len: usize,
+    r: &mut Reader<'_>,
+) -> Option<()> {
+    if changes & TCP_PUSH_BIT != 0 {
+        th[13] |= TCP_PUSH;
+    } else {
+        th[13] &= !TCP_PUSH;
+    }
+
+    let old_data_len = old_data_len as u32;
+    let mut seq = be32(&th[4..]);
+    let mut ack = be32(&th[8..]);
+    match changes & SPECIALS_MASK {
+        SPECIAL_I => {
+            seq = seq.wrapping_add(old_data_len);
+            ack = ack.wrapping_add(old_data_len);
+        }
+        SPECIAL_D => seq = seq.wrapping_add(old_data_len),
+        _ => {
+            if changes & NEW_U != 0 {
+                th[13] |= TCP_URG;
+                th[18..20].copy_from_slice(&r.delta()?.to_be_bytes());
+            } else {
+                th[13] &= !TCP_URG;
+            }
+            if changes & NEW_W != 0 {
+                let window = be16(&th[14..]).wrapping_add(r.delta()?);
+                th[14..16].copy_from_slice(&window.to_be_bytes());
+            }
+            if changes & NEW_A != 0 {
+                ack = ack.wrapping_add(r.delta()? as u32);
+            }
+            if changes & NEW_S != 0 {
+                seq = seq.wrapping_add(r.delta()? as u32);
+            }
+        }
+    }
+    th[4..8].copy_from_slice(&seq.to_be_bytes());
+    th[8..12].copy_from_slice(&ack.to_be_bytes());
+    Some(())
+}
+
+/// Replace the compressed header of the packet at `buf[range]`, `compressed_len` bytes long,
+/// with the restored `header`.
+///
+/// The restored header is larger, so the data is moved towards the end of `buf`. Returns the
+/// range of the restored packet, or None if `buf` is too small.
+fn restore_header(
+    buf: &mut [u8],
+    range: Range<usize>,
+    compressed_len: usize,
+    header: &[u8],
+) -> Option<Range<usize>> {
+    let start = range.start;
+    let len = header.len() + range.len() - compressed_len;
+    if start + len > buf.len() {
+        warn!("no room to decompress packet");
+        return None;
+    }
+    buf.copy_within(start + compressed_len..range.end, start + header.len());
+    buf[start..start + header.len()].copy_from_slice(header);
+    Some(start..start + len)
+}
+
+/// Encoded changes of a compressed header.
+struct Deltas {
+    buf: [u8; 15],
+    len: usize,
+}
+
+impl Deltas {
+    fn new() -> Self {
+        Self {
+            buf: [0; 15],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Encode a non-zero change: 1 byte if it fits, or else 0 followed by 2 bytes.
+    fn encode(&mut self, n: u16) {
+        if n == 0 || n >= 256 {
+            self.push(0);
+            self.push((n >> 8) as u8);
+        }
+        self.push(n as u8);
+    }
+
+    /// Encode a change that may be zero.
+    fn encodez(&mut self, n: u16) {
+        if n >= 256 {
+            self.push(0);
+            self.push((n >> 8) as u8);
+        }
+        self.push(n as u8);
+    }
+
+    fn push(&mut self, b: u8) {
+        self.buf[self.len] = b;
+        self.len += 1;
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    /// Decode a change encoded with [`Deltas::encode`] or [`Deltas::encodez`].
+    fn delta(&mut self) -> Option<u16> {
+        match self.u8()? {
+            0 => self.u16(),
+            b => Some(b as u16),
+        }
+    }
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Length of an IPv4 header.
+fn ihl(pkt: &[u8]) -> usize {
+    (pkt[0] & 0x0f) as usize * 4
+}
+
+/// Recompute the checksum of an IPv4 header.
+fn set_ip_checksum(header: &mut [u8]) {
+    header[10..12].fill(0);
+    let mut sum: u32 = header.chunks(2).map(|c| be16(c) as u32).sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    header[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+/// Build an IPv4 TCP segment from port 1234 to 80 in `buf`, for tests. Returns its length.
+#[cfg(test)]
+pub(crate) fn tcp4(buf: &mut [u8], id: u16, seq: u32, ack: u32, data: &[u8]) -> usize {
+    let len = 40 + data.len();
+    buf[..40].fill(0);
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[4..6].copy_from_slice(&id.to_be_bytes());
+    buf[8] = 64;
+    buf[9] = 6;
+    buf[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    buf[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    set_ip_checksum(&mut buf[..20]);
+
+    let th = &mut buf[20..40];
+    th[0..2].copy_from_slice(&1234u16.to_be_bytes());
+    th[2..4].copy_from_slice(&80u16.to_be_bytes());
+    th[4..8].copy_from_slice(&seq.to_be_bytes());
+    th[8..12].copy_from_slice(&ack.to_be_bytes());
+    th[12] = 5 << 4;
+    th[13] = TCP_ACK | TCP_PUSH;
+    th[14..16].copy_from_slice(&8192u16.to_be_bytes());
+    // Not a real checksum, but it's carried as is.
+    th[16..18].copy_from_slice(&(seq as u16 ^ id).to_be_bytes());
+    buf[40..len].copy_from_slice(data);
+    len
+}
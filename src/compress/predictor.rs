@@ -0,0 +1,273 @@
+//! Predictor type 1 packet compression, RFC 1978.
+//!
+//! Each byte is guessed from a hash of the bytes before it, with a table of the byte that last
+//! followed each hash. Bytes guessed right are sent as a single bit.
+
+use core::cell::RefCell;
+
+use crate::pppos::crc::crc16;
+use crate::{Compressor, OptionVerdict};
+
+/// CCP option type of Predictor type 1.
+const OPTION_TYPE: u8 = 1;
+/// Size of the guess table, indexed by a 16-bit hash.
+const TABLE_SIZE: usize = 1 << 16;
+/// Length field bit: the data is compressed.
+const COMPRESSED: u16 = 0x8000;
+/// Length of the length field and the CRC around the data.
+const OVERHEAD: usize = 4;
+
+/// State of one direction.
+struct Predictor {
+    table: [u8; TABLE_SIZE],
+    hash: u16,
+}
+
+impl Predictor {
+    const fn new() -> Self {
+        Self {
+            table: [0; TABLE_SIZE],
+            hash: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.table.fill(0);
+        self.hash = 0;
+    }
+
+    /// Feed the next byte, returning whether it was guessed right.
+    fn guess(&mut self, b: u8) -> bool {
+        let slot = &mut self.table[self.hash as usize];
+        let hit = *slot == b;
+        *slot = b;
+        self.hash = self.hash << 4 ^ b as u16;
+        hit
+    }
+
+    /// Compress `input` into `out`, returning the compressed length.
+    ///
+    /// The whole input is fed to the table even if it doesn't fit: the result is then larger
+    /// than `out`, and the packet must be sent uncompressed.
+    fn compress(&mut self, input: impl Iterator<Item = u8>, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        let mut flags_pos = 0;
+        for (i, b) in input.enumerate() {
+            if i % 8 == 0 {
+                flags_pos = n;
+                if let Some(flags) = out.get_mut(n) {
+                    *flags = 0;
+                }
+                n += 1;
+            }
+            if self.guess(b) {
+                if let Some(flags) = out.get_mut(flags_pos) {
+                    *flags |= 1 << (i % 8);
+                }
+            } else {
+                if let Some(o) = out.get_mut(n) {
+                    *o = b;
+                }
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// Decompress `input` into `out`, which has the length of the original data.
+    ///
+    /// Returns None if the input doesn't match the length.
+    fn decompress(&mut self, input: &[u8], out: &mut [u8]) -> Option<()> {
+        let mut input = input.iter().copied();
+        let mut flags = 0;
+        for (i, o) in out.iter_mut().enumerate() {
+            if i % 8 == 0 {
+                flags = input.next()?;
+            }
+            let b = if flags & 1 << (i % 8) != 0 {
+                self.table[self.hash as usize]
+            } else {
+                input.next()?
+            };
+            self.guess(b);
+            *o = b;
+        }
+        input.next().is_none().then_some(())
+    }
+}
+
+/// Predictor type 1 compression, RFC 1978, to use with [`Config::compressors`](crate::Config::compressors).
+///
+/// It's fast and simple, but compresses less than e.g. Deflate. It needs a 64 KiB table for
+/// each direction, as the hash used to index them is fixed by the RFC.
+pub struct Predictor1 {
+    tx: RefCell<Predictor>,
+    rx: RefCell<Predictor>,
+}
+
+impl Predictor1 {
+    /// Create a new Predictor1.
+    pub const fn new() -> Self {
+        Self {
+            tx: RefCell::new(Predictor::new()),
+            rx: RefCell::new(Predictor::new()),
+        }
+    }
+}
+
+impl Default for Predictor1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compressor for Predictor1 {
+    fn option_type(&self) -> u8 {
+        OPTION_TYPE
+    }
+
+    fn tx_option(&self, data: &[u8], _nak: &mut [u8]) -> OptionVerdict {
+        if data.is_empty() {
+            OptionVerdict::Ack
+        } else {
+            OptionVerdict::Reject
+        }
+    }
+
+    fn reset_tx(&self) {
+        self.tx.borrow_mut().reset();
+    }
+
+    fn reset_rx(&self) {
+        self.rx.borrow_mut().reset();
+    }
+
+    fn compress(&self, protocol: u16, data: &[u8], out: &mut [u8]) -> Option<usize> {
+        // The Protocol field is compressed along with the data. The packet can always be sent
+        // uncompressed in a compressed datagram if it fits, otherwise it's sent as is, without
+        // touching the table so the peer stays in sync.
+        let protocol = protocol.to_be_bytes();
+        let len = protocol.len() + data.len();
+        if len + OVERHEAD > out.len() || len as u16 & COMPRESSED != 0 {
+            return None;
+        }
+
+        let input = protocol.iter().chain(data).copied();
+        let end = out.len() - 2;
+        let n = self.tx.borrow_mut().compress(input, &mut out[2..end]);
+        let length = if n < len {
+            len as u16 | COMPRESSED
+        } else {
+            out[2..][..protocol.len()].copy_from_slice(&protocol);
+            out[2 + protocol.len()..][..data.len()].copy_from_slice(data);
+            len as u16
+        };
+        out[..2].copy_from_slice(&length.to_be_bytes());
+
+        // The CRC covers the length and the uncompressed data.
+        let crc = crc16(0xffff, &(len as u16).to_be_bytes());
+        let crc = crc16(crc16(crc, &protocol), data) ^ 0xffff;
+        let n = 2 + n.min(len);
+        out[n..n + 2].copy_from_slice(&crc.to_le_bytes());
+        Some(n + 2)
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> Option<(u16, usize)> {
+        if data.len() < OVERHEAD {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[0], data[1]]);
+        let len = (length & !COMPRESSED) as usize;
+        if len < 2 || len > out.len() {
+            return None;
+        }
+        let (payload, crc) = data[2..].split_at(data.len() - OVERHEAD);
+
+        let mut rx = self.rx.borrow_mut();
+        if length & COMPRESSED != 0 {
+            rx.decompress(payload, &mut out[..len])?;
+        } else {
+            // Uncompressed data still updates the table, like on the compressor's side.
+            if payload.len() != len {
+                return None;
+            }
+            for &b in payload {
+                rx.guess(b);
+            }
+            out[..len].copy_from_slice(payload);
+        }
+
+        let fcs = crc16(0xffff, &(len as u16).to_be_bytes());
+        if crc16(crc16(fcs, &out[..len]), crc) != 0xf0b8 {
+            return None;
+        }
+        let protocol = u16::from_be_bytes([out[0], out[1]]);
+        out.copy_within(2..len, 0);
+        Some((protocol, len - 2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n";
+
+    /// Compress `data` with `tx` and decompress it with `rx`.
+    fn transfer(tx: &Predictor1, rx: &Predictor1, data: &[u8]) -> Option<(u16, usize)> {
+        let mut frame = [0; 128];
+        let n = tx.compress(0x0021, data, &mut frame).unwrap();
+        let mut out = [0; 128];
+        let res = rx.decompress(&frame[..n], &mut out)?;
+        assert_eq!(&out[..res.1], data);
+        Some(res)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (a, b) = (Predictor1::new(), Predictor1::new());
+        a.reset_tx();
+        b.reset_rx();
+        let mut frame = [0; 128];
+        let mut sizes = [0; 3];
+        for size in &mut sizes {
+            *size = a.compress(0x0021, PACKET, &mut frame).unwrap();
+            let mut out = [0; 128];
+            assert_eq!(
+                b.decompress(&frame[..*size], &mut out),
+                Some((0x0021, PACKET.len()))
+            );
+            assert_eq!(&out[..PACKET.len()], PACKET);
+        }
+        // Sent as is at first, then guessed from the previous packets.
+        assert_eq!(sizes[0], 2 + PACKET.len() + OVERHEAD);
+        assert!(sizes[2] < PACKET.len() / 4);
+    }
+
+    #[test]
+    fn too_large() {
+        let a = Predictor1::new();
+        a.reset_tx();
+        let mut frame = [0; 64];
+        assert_eq!(a.compress(0x0021, PACKET, &mut frame), None);
+    }
+
+    #[test]
+    fn lost_packet() {
+        let (a, b) = (Predictor1::new(), Predictor1::new());
+        a.reset_tx();
+        b.reset_rx();
+        assert!(transfer(&a, &b, PACKET).is_some());
+
+        // Lost on the link: the peer's table is out of sync.
+        let mut frame = [0; 128];
+        a.compress(0x0021, b"POST /form HTTP/1.1\r\n\r\n", &mut frame);
+        assert_eq!(transfer(&a, &b, PACKET), None);
+
+        // Reset-Request, then Reset-Ack.
+        a.reset_tx();
+        b.reset_rx();
+        assert!(transfer(&a, &b, PACKET).is_some());
+        assert!(transfer(&a, &b, PACKET).is_some());
+    }
+}
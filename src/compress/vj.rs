@@ -0,0 +1,466 @@
+//! Van Jacobson TCP/IP header compression, RFC 1144.
+//!
+//! Only the headers of established TCP connections over IPv4 are compressed: the first
+//! packet of a connection is sent as Uncompressed-TCP to set up the state of its slot on
+//! both sides, and the following ones as Compressed-TCP, encoding only the changes.
+
+use core::ops::Range;
+
+use super::{
+    apply_tcp_changes, be16, ihl, restore_header, set_ip_checksum, tcp_changes, tcp_compressible,
+    Reader, NEW_I,
+};
+
+/// Most connection slots we keep state for, in each direction.
+pub(crate) const MAX_SLOTS: usize = 16;
+/// Largest TCP/IP header we can compress: 60 bytes of IPv4 header and 60 of TCP header.
+pub(crate) const MAX_HEADER: usize = 120;
+
+/// Change mask bit: the slot number is present.
+const NEW_C: u8 = 0x40;
+
+/// How a packet must be sent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum PacketType {
+    /// Unchanged, as a regular IPv4 packet.
+    Ip,
+    /// With the full header, to set up the state of a slot.
+    UncompressedTcp,
+    /// With the compressed header.
+    CompressedTcp,
+}
+
+/// Last TCP/IP header seen on a connection.
+#[derive(Copy, Clone)]
+struct Slot {
+    header: [u8; MAX_HEADER],
+    /// Length of the header, 0 if the slot is unused.
+    len: usize,
+    /// When the slot was last used, to reuse the least recently used one.
+    last_used: u32,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        header: [0; MAX_HEADER],
+        len: 0,
+        last_used: 0,
+    };
+}
+
+/// Compresses the headers of the packets we send.
+pub(crate) struct Compressor {
+    slots: [Slot; MAX_SLOTS],
+    /// Slots the peer can receive with. 0 if compression is off.
+    num_slots: usize,
+    /// Whether the slot number may be omitted if it's the same as in the last packet.
+    compress_slot_id: bool,
+    /// Slot of the last packet sent.
+    last_sent: Option<usize>,
+    clock: u32,
+}
+
+impl Compressor {
+    pub const fn new() -> Self {
+        Self {
+            slots: [Slot::EMPTY; MAX_SLOTS],
+            num_slots: 0,
+            compress_slot_id: false,
+            last_sent: None,
+            clock: 0,
+        }
+    }
+
+    /// Start compressing with the parameters negotiated with the peer, or stop if `num_slots` is 0.
+    pub fn reset(&mut self, num_slots: usize, compress_slot_id: bool) {
+        self.num_slots = num_slots.min(MAX_SLOTS);
+        self.compress_slot_id = compress_slot_id;
+        self.clear();
+    }
+
+    /// Forget all connections, e.g. because a packet could not be sent after compressing it.
+    ///
+    /// The next packet of each connection is sent uncompressed, resynchronizing the peer.
+    pub fn clear(&mut self) {
+        self.slots = [Slot::EMPTY; MAX_SLOTS];
+        self.last_sent = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.num_slots != 0
+    }
+
+    /// Compress the header of an IPv4 packet.
+    ///
+    /// The header to send is written to `out[..n]`, to be followed by `pkt[consumed..]`.
+    /// Returns the packet type, `n` and `consumed`.
+    pub fn compress(
+        &mut self,
+        pkt: &[u8],
+        out: &mut [u8; MAX_HEADER],
+    ) -> (PacketType, usize, usize) {
+        let Some(hlen) = tcp_header_len(pkt) else {
+            return (PacketType::Ip, 0, 0);
+        };
+        if !self.is_enabled() {
+            return (PacketType::Ip, 0, 0);
+        }
+        let th = &pkt[ihl(pkt)..];
+        if !tcp_compressible(th) {
+            return (PacketType::Ip, 0, 0);
+        }
+
+        self.clock = self.clock.wrapping_add(1);
+        let Some(i) = self.find(pkt) else {
+            let i = self.least_recently_used();
+            return self.uncompressed(i, pkt, hlen, out);
+        };
+
+        let old = &self.slots[i].header;
+        let old_th = &old[ihl(old)..];
+
+        // IP fields that can't be encoded as changes must be the same. The header checksum
+        // is recomputed by the peer.
+        if pkt[0..2] != old[0..2]
+            || pkt[6..10] != old[6..10]
+            || pkt[20..ihl(pkt)] != old[20..ihl(old)]
+        {
+            return self.uncompressed(i, pkt, hlen, out);
+        }
+        let old_data_len = (be16(&old[2..]) as usize).saturating_sub(self.slots[i].len);
+        let Some((mut changes, mut deltas)) =
+            tcp_changes(th, old_th, pkt.len() - hlen, old_data_len)
+        else {
+            return self.uncompressed(i, pkt, hlen, out);
+        };
+
+        let delta_i = be16(&pkt[4..]).wrapping_sub(be16(&old[4..]));
+        if delta_i != 1 {
+            deltas.encodez(delta_i);
+            changes |= NEW_I;
+        }
+
+        let mut n = 0;
+        if self.compress_slot_id && self.last_sent == Some(i) {
+            out[0] = changes;
+            n += 1;
+        } else {
+            out[0] = changes | NEW_C;
+            out[1] = i as u8;
+            n += 2;
+        }
+        out[n..n + 2].copy_from_slice(&th[16..18]);
+        n += 2;
+        out[n..n + deltas.len].copy_from_slice(deltas.as_bytes());
+        n += deltas.len;
+
+        self.save(i, pkt, hlen);
+        (PacketType::CompressedTcp, n, hlen)
+    }
+
+    /// Send the full header, with the slot number in place of the IP protocol.
+    fn uncompressed(
+        &mut self,
+        i: usize,
+        pkt: &[u8],
+        hlen: usize,
+        out: &mut [u8; MAX_HEADER],
+    ) -> (PacketType, usize, usize) {
+        self.save(i, pkt, hlen);
+        out[..hlen].copy_from_slice(&pkt[..hlen]);
+        out[9] = i as u8;
+        (PacketType::UncompressedTcp, hlen, hlen)
+    }
+
+    fn save(&mut self, i: usize, pkt: &[u8], hlen: usize) {
+        let slot = &mut self.slots[i];
+        slot.header[..hlen].copy_from_slice(&pkt[..hlen]);
+        slot.len = hlen;
+        slot.last_used = self.clock;
+        self.last_sent = Some(i);
+    }
+
+    /// Find the slot of the packet's connection.
+    fn find(&self, pkt: &[u8]) -> Option<usize> {
+        let th = &pkt[ihl(pkt)..];
+        self.slots[..self.num_slots].iter().position(|slot| {
+            let old = &slot.header;
+            slot.len != 0 && old[12..20] == pkt[12..20] && old[ihl(old)..][..4] == th[..4]
+        })
+    }
+
+    /// Find a slot for a new connection: an unused one, or else the least recently used.
+    fn least_recently_used(&self) -> usize {
+        let slots = &self.slots[..self.num_slots];
+        slots
+            .iter()
+            .position(|slot| slot.len == 0)
+            .or_else(|| {
+                (0..slots.len()).min_by_key(|&i| self.clock.wrapping_sub(slots[i].last_used))
+            })
+            .map_or(0, |i| i)
+    }
+}
+
+/// Decompresses the headers of the packets we receive.
+pub(crate) struct Decompressor {
+    slots: [Slot; MAX_SLOTS],
+    /// Slots we told the peer we can receive with. 0 if decompression is off.
+    num_slots: usize,
+    /// Slot of the last packet received.
+    last_received: Option<usize>,
+    /// Whether a packet may have been lost: Compressed-TCP packets are dropped until the
+    /// slot number is sent again.
+    toss: bool,
+}
+
+impl Decompressor {
+    pub const fn new() -> Self {
+        Self {
+            slots: [Slot::EMPTY; MAX_SLOTS],
+            num_slots: 0,
+            last_received: None,
+            toss: false,
+        }
+    }
+
+    /// Start decompressing with the number of slots negotiated, or stop if it's 0.
+    pub fn reset(&mut self, num_slots: usize) {
+        self.num_slots = num_slots.min(MAX_SLOTS);
+        self.slots = [Slot::EMPTY; MAX_SLOTS];
+        self.last_received = None;
+        self.toss = false;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.num_slots != 0
+    }
+
+    /// A frame was received with errors. It may have been one of ours, so the state is out
+    /// of sync until the peer sends the slot number again.
+    pub fn toss(&mut self) {
+        self.toss = true;
+    }
+
+    /// Handle an Uncompressed-TCP packet, restoring its IP protocol field in place.
+    ///
+    /// Returns false if it's invalid.
+    pub fn uncompressed(&mut self, pkt: &mut [u8]) -> bool {
+        let Some(&i) = pkt.get(9) else {
+            self.toss = true;
+            return false;
+        };
+        let i = i as usize;
+        pkt[9] = 6; // TCP
+        match tcp_header_len(pkt) {
+            Some(hlen) if i < self.num_slots => {
+                let slot = &mut self.slots[i];
+                slot.header[..hlen].copy_from_slice(&pkt[..hlen]);
+                slot.len = hlen;
+                self.last_received = Some(i);
+                self.toss = false;
+                true
+            }
+            _ => {
+                warn!("VJ: invalid Uncompressed-TCP packet");
+                self.toss = true;
+                false
+            }
+        }
+    }
+
+    /// Decompress the Compressed-TCP packet at `buf[range]`, in place.
+    ///
+    /// The restored header is larger than the compressed one, so the data is moved towards the
+    /// end of `buf`. Returns the range of the restored packet, or None if it must be dropped.
+    pub fn compressed(&mut self, buf: &mut [u8], range: Range<usize>) -> Option<Range<usize>> {
+        let pkt = &buf[range.clone()];
+        let mut r = Reader { data: pkt, pos: 0 };
+
+        let Some((i, header, hlen)) = self.decode(&mut r) else {
+            if !self.toss {
+                warn!("VJ: invalid Compressed-TCP packet");
+                self.toss = true;
+            }
+            return None;
+        };
+
+        let len = hlen + pkt.len() - r.pos;
+        let slot = &mut self.slots[i];
+        slot.header = header;
+        slot.header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        set_ip_checksum(&mut slot.header[..ihl(&header)]);
+
+        let compressed_len = r.pos;
+        restore_header(buf, range, compressed_len, &slot.header[..hlen])
+    }
+
+    /// Decode the compressed header, applying the changes to a copy of its slot's header.
+    ///
+    /// Returns the slot number, the new header and its length.
+    fn decode(&mut self, r: &mut Reader<'_>) -> Option<(usize, [u8; MAX_HEADER], usize)> {
+        let changes = r.u8()?;
+        if changes & NEW_C != 0 {
+            let i = r.u8()? as usize;
+            if i >= self.num_slots {
+                return None;
+            }
+            self.last_received = Some(i);
+            self.toss = false;
+        } else if self.toss {
+            return None;
+        }
+
+        let i = self.last_received?;
+        let slot = &self.slots[i];
+        if slot.len == 0 {
+            return None;
+        }
+        let hlen = slot.len;
+        let mut h = slot.header;
+        let t = ihl(&h);
+
+        let checksum = r.u16()?;
+        h[t + 16..t + 18].copy_from_slice(&checksum.to_be_bytes());
+        let data_len = (be16(&h[2..]) as usize).saturating_sub(hlen);
+        apply_tcp_changes(changes, &mut h[t..], data_len, r)?;
+
+        let delta_i = if changes & NEW_I != 0 { r.delta()? } else { 1 };
+        let id = be16(&h[4..]).wrapping_add(delta_i);
+        h[4..6].copy_from_slice(&id.to_be_bytes());
+
+        Some((i, h, hlen))
+    }
+}
+
+/// Length of the TCP/IP header of a packet that can be compressed, None if it can't.
+fn tcp_header_len(pkt: &[u8]) -> Option<usize> {
+    if pkt.len() < 40 || pkt[0] >> 4 != 4 || pkt[9] != 6 {
+        return None;
+    }
+    // Fragments, and packets whose length we couldn't restore.
+    if be16(&pkt[6..]) & 0x3fff != 0 || be16(&pkt[2..]) as usize != pkt.len() {
+        return None;
+    }
+    let ihl = ihl(pkt);
+    let th = pkt.get(ihl..)?;
+    let tcp_len = (*th.get(12)? >> 4) as usize * 4;
+    let hlen = ihl + tcp_len;
+    if ihl < 20 || tcp_len < 20 || hlen > pkt.len() || hlen > MAX_HEADER {
+        return None;
+    }
+    Some(hlen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::tcp4;
+
+    /// Compress `pkt` into `frame`, the way PPPoS sends it. Returns the packet type and length.
+    fn send(c: &mut Compressor, pkt: &[u8], frame: &mut [u8]) -> (PacketType, usize) {
+        let mut header = [0; MAX_HEADER];
+        let (ty, n, consumed) = c.compress(pkt, &mut header);
+        frame[..n].copy_from_slice(&header[..n]);
+        let len = n + pkt.len() - consumed;
+        frame[n..len].copy_from_slice(&pkt[consumed..]);
+        (ty, len)
+    }
+
+    /// Decompress the frame at `buf[..len]`, returning the restored packet.
+    fn receive<'a>(
+        d: &mut Decompressor,
+        ty: PacketType,
+        buf: &'a mut [u8],
+        len: usize,
+    ) -> Option<&'a [u8]> {
+        let range = match ty {
+            PacketType::Ip => 0..len,
+            PacketType::UncompressedTcp => d.uncompressed(&mut buf[..len]).then_some(0..len)?,
+            PacketType::CompressedTcp => d.compressed(buf, 0..len)?,
+        };
+        Some(&buf[range])
+    }
+
+    /// The i-th segment of a bulk transfer, with some of the fields changing along the way.
+    fn segment(buf: &mut [u8], i: u32) -> usize {
+        let data = [i as u8; 100];
+        let data_len = [0, 1, 10, 100][i as usize % 4];
+        let seq = 1000 + (0..i).map(|j| [0, 1, 10, 100][j as usize % 4]).sum::<u32>();
+        let ack = 5000 + i / 3 * 200;
+        let id = (100 + i + i / 5 * 2) as u16;
+        tcp4(buf, id, seq, ack, &data[..data_len])
+    }
+
+    #[test]
+    fn round_trip() {
+        for compress_slot_id in [false, true] {
+            let mut c = Compressor::new();
+            let mut d = Decompressor::new();
+            c.reset(4, compress_slot_id);
+            d.reset(4);
+
+            for i in 0..40 {
+                let mut pkt = [0; 256];
+                let len = segment(&mut pkt, i);
+                let mut buf = [0; 256];
+                let (ty, n) = send(&mut c, &pkt[..len], &mut buf);
+                let expected = if i == 0 {
+                    PacketType::UncompressedTcp
+                } else {
+                    PacketType::CompressedTcp
+                };
+                assert_eq!(ty, expected);
+                assert_eq!(receive(&mut d, ty, &mut buf, n), Some(&pkt[..len]));
+            }
+        }
+    }
+
+    #[test]
+    fn not_compressed() {
+        let mut c = Compressor::new();
+        c.reset(4, true);
+        let mut pkt = [0; 256];
+        let len = segment(&mut pkt, 0);
+        let mut out = [0; MAX_HEADER];
+
+        // SYN
+        pkt[33] |= 0x02;
+        assert_eq!(c.compress(&pkt[..len], &mut out).0, PacketType::Ip);
+        // Fragment
+        pkt[33] &= !0x02;
+        pkt[6] = 0x20;
+        assert_eq!(c.compress(&pkt[..len], &mut out).0, PacketType::Ip);
+    }
+
+    #[test]
+    fn lost_packet() {
+        let mut c = Compressor::new();
+        let mut d = Decompressor::new();
+        c.reset(4, true);
+        d.reset(4);
+
+        let mut buf = [0; 256];
+        for i in 0..6 {
+            let mut pkt = [0; 256];
+            let len = segment(&mut pkt, i);
+            if i == 4 {
+                // The sender resynchronizes, as after failing to send a packet.
+                c.clear();
+            }
+            let (ty, n) = send(&mut c, &pkt[..len], &mut buf);
+            let received = match i {
+                // Lost on the link: the frame had errors.
+                1 => {
+                    d.toss();
+                    continue;
+                }
+                // Compressed without the slot number, can't be decompressed.
+                2 | 3 => None,
+                _ => Some(&pkt[..len]),
+            };
+            assert_eq!(receive(&mut d, ty, &mut buf, n), received, "packet {}", i);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! MD4, RFC 1320. Only used for the NT password hash of MS-CHAPv2.
+
+use super::Blocks;
+
+/// MD4 hasher.
+#[derive(Clone)]
+pub struct Md4 {
+    state: [u32; 4],
+    blocks: Blocks,
+}
+
+impl Md4 {
+    pub fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            blocks: Blocks::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |block| process(state, block));
+    }
+
+    pub fn finalize(mut self) -> [u8; 16] {
+        let bits = self.blocks.len.wrapping_mul(8);
+        let state = &mut self.state;
+        self.blocks
+            .finish(bits.to_le_bytes(), |block| process(state, block));
+
+        let mut out = [0; 16];
+        for (o, s) in out.chunks_exact_mut(4).zip(self.state) {
+            o.copy_from_slice(&s.to_le_bytes());
+        }
+        out
+    }
+}
+
+fn process(state: &mut [u32; 4], block: &[u8; 64]) {
+    let mut x = [0u32; 16];
+    for (x, b) in x.iter_mut().zip(block.chunks_exact(4)) {
+        *x = u32::from_le_bytes(b.try_into().unwrap());
+    }
+
+    let [mut a, mut b, mut c, mut d] = *state;
+
+    // Round 1
+    for &i in &[0, 4, 8, 12] {
+        let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+        a = a.wrapping_add(f(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+        d = d
+            .wrapping_add(f(a, b, c))
+            .wrapping_add(x[i + 1])
+            .rotate_left(7);
+        c = c
+            .wrapping_add(f(d, a, b))
+            .wrapping_add(x[i + 2])
+            .rotate_left(11);
+        b = b
+            .wrapping_add(f(c, d, a))
+            .wrapping_add(x[i + 3])
+            .rotate_left(19);
+    }
+
+    // Round 2
+    for i in 0..4 {
+        let g = |x: u32, y: u32, z: u32| ((x & y) | (x & z) | (y & z)).wrapping_add(0x5a827999);
+        a = a.wrapping_add(g(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+        d = d
+            .wrapping_add(g(a, b, c))
+            .wrapping_add(x[i + 4])
+            .rotate_left(5);
+        c = c
+            .wrapping_add(g(d, a, b))
+            .wrapping_add(x[i + 8])
+            .rotate_left(9);
+        b = b
+            .wrapping_add(g(c, d, a))
+            .wrapping_add(x[i + 12])
+            .rotate_left(13);
+    }
+
+    // Round 3
+    for &i in &[0, 2, 1, 3] {
+        let h = |x: u32, y: u32, z: u32| (x ^ y ^ z).wrapping_add(0x6ed9eba1);
+        a = a.wrapping_add(h(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+        d = d
+            .wrapping_add(h(a, b, c))
+            .wrapping_add(x[i + 8])
+            .rotate_left(9);
+        c = c
+            .wrapping_add(h(d, a, b))
+            .wrapping_add(x[i + 4])
+            .rotate_left(11);
+        b = b
+            .wrapping_add(h(c, d, a))
+            .wrapping_add(x[i + 12])
+            .rotate_left(15);
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hex;
+
+    fn md4(data: &[u8]) -> [u8; 16] {
+        let mut md4 = Md4::new();
+        md4.update(data);
+        md4.finalize()
+    }
+
+    // RFC 1320 appendix A.5.
+    #[test]
+    fn test_vectors() {
+        assert_eq!(md4(b""), hex("31d6cfe0d16ae931b73c59d7e0c089c0"));
+        assert_eq!(md4(b"abc"), hex("a448017aaf21d8525fc10ae87aa6729d"));
+        assert_eq!(
+            md4(b"message digest"),
+            hex("d9130a8164549fe818874806e1c7014b")
+        );
+        assert_eq!(
+            md4(b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789"),
+            hex("043f8582f241db351ce627e153e7f0e4")
+        );
+        assert_eq!(
+            md4(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            ),
+            hex("e33b4ddc9c38f2199c3e7b164fcc0536")
+        );
+    }
+}
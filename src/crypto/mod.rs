@@ -0,0 +1,68 @@
+//! Hash and cipher primitives needed for authentication.
+//!
+//! These are small, unoptimized implementations, only meant to process a handful of
+//! short messages per connection.
+
+mod des;
+mod md4;
+mod md5;
+mod sha1;
+
+pub use self::des::des_encrypt;
+pub use self::md4::Md4;
+pub use self::md5::Md5;
+pub use self::sha1::Sha1;
+
+/// Block buffering and length padding shared by the MD4, MD5 and SHA-1 hashes.
+#[derive(Clone)]
+struct Blocks {
+    /// Partial block not yet processed.
+    buf: [u8; 64],
+    /// Total bytes hashed so far.
+    len: u64,
+}
+
+impl Blocks {
+    const fn new() -> Self {
+        Self {
+            buf: [0; 64],
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8], mut process: impl FnMut(&[u8; 64])) {
+        let mut pos = (self.len % 64) as usize;
+        self.len += data.len() as u64;
+
+        while !data.is_empty() {
+            let n = data.len().min(64 - pos);
+            self.buf[pos..][..n].copy_from_slice(&data[..n]);
+            data = &data[n..];
+            pos += n;
+            if pos == 64 {
+                process(&self.buf);
+                pos = 0;
+            }
+        }
+    }
+
+    /// Append the padding and the message length in bits, processing the last blocks.
+    fn finish(&mut self, len_bytes: [u8; 8], mut process: impl FnMut(&[u8; 64])) {
+        self.update(&[0x80], &mut process);
+        while self.len % 64 != 56 {
+            self.update(&[0], &mut process);
+        }
+        self.update(&len_bytes, &mut process);
+    }
+}
+
+/// Decode a hex string, for test vectors.
+#[cfg(test)]
+pub(crate) fn hex<const N: usize>(s: &str) -> [u8; N] {
+    let mut out = [0; N];
+    assert_eq!(s.len(), 2 * N);
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+    }
+    out
+}
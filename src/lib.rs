@@ -5,11 +5,22 @@
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+mod compress;
+mod crypto;
 mod ppp;
 pub mod pppos;
+mod rand;
+mod time;
 mod wire;
 
-pub use ppp::{Config, Ipv4Status, Phase, Status};
+#[cfg(feature = "deflate")]
+pub use compress::Deflate;
+pub use compress::Predictor1;
+pub use ppp::{
+    AddressPool, AuthMessage, AuthProtocol, AuthType, Authenticator, Compressor, Config,
+    Ipv4Status, Ipv6Status, LinkFailure, OptionVerdict, Phase, Status,
+};
+pub use time::{Duration, Instant};
 
 /// Invalid state error.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
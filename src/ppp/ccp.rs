@@ -0,0 +1,289 @@
+use super::option_fsm::{Protocol, Verdict};
+use super::{Compressor, Config, OptionVerdict};
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType, MAX_OPTIONS, MAX_OPTION_LEN};
+
+/// Whether packets of `protocol` are compressed once CCP is up: network-layer protocols,
+/// except compressed datagrams themselves (RFC 1962 section 4).
+pub(crate) fn compressible(protocol: u16) -> bool {
+    protocol < 0x4000 && protocol != ProtocolType::CompressedDatagram.into() && protocol != 0x00fb
+}
+
+/// Compression Control Protocol, RFC 1962.
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct CCP<'a> {
+    /// Algorithms we can use, most preferred first.
+    compressors: &'a [&'a dyn Compressor],
+    /// Algorithms we no longer request for receiving, because the peer rejected them, by index.
+    rejected: u8,
+    /// Algorithm the peer asked us to compress with.
+    tx: Option<usize>,
+    /// Algorithm we decompress with, chosen when CCP comes up from `acked`.
+    rx: Option<usize>,
+    /// First of our algorithms in the Configure-Ack of our last request.
+    acked: Option<usize>,
+    /// Option data we last sent in a Configure-Nak.
+    nak: [u8; MAX_OPTION_LEN],
+
+    restart_timeout: Duration,
+    /// Identifier of our last Reset-Request.
+    reset_id: u8,
+    /// Whether we're waiting for the peer to acknowledge a Reset-Request.
+    resetting: bool,
+    /// When to retransmit the Reset-Request. None if it hasn't been sent yet.
+    reset_at: Option<Instant>,
+}
+
+impl<'a> CCP<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        Self {
+            compressors: config.compressors,
+            rejected: 0,
+            tx: None,
+            rx: None,
+            acked: None,
+            nak: [0; MAX_OPTION_LEN],
+            restart_timeout: config.restart_timeout,
+            reset_id: 0,
+            resetting: false,
+            reset_at: None,
+        }
+    }
+
+    /// Whether CCP is negotiated at all, i.e. there are algorithms to offer.
+    pub fn enabled(&self) -> bool {
+        !self.compressors.is_empty()
+    }
+
+    /// Algorithm for compressing the packets we send. None if the peer didn't ask for any.
+    pub fn tx(&self) -> Option<&'a dyn Compressor> {
+        self.tx.map(|i| self.compressors[i])
+    }
+
+    /// Algorithm for decompressing the packets we receive. None if the peer rejected all of
+    /// ours, or while waiting for a Reset-Ack.
+    pub fn rx(&self) -> Option<&'a dyn Compressor> {
+        self.rx
+            .filter(|_| !self.resetting)
+            .map(|i| self.compressors[i])
+    }
+
+    /// This-Layer-Up: start compressing and decompressing with empty histories.
+    ///
+    /// The peer may ack several of our algorithms, and doesn't tell which one it compresses
+    /// with. We decompress with the first one in its Configure-Ack, which is our most preferred
+    /// since the Ack repeats our request; a peer using another one gets its packets dropped.
+    pub fn up(&mut self) {
+        self.rx = self.acked;
+        self.resetting = false;
+        if let Some(c) = self.tx() {
+            info!("CCP: compressing with option type {:?}", c.option_type());
+            c.reset_tx();
+        }
+        if let Some(c) = self.rx() {
+            info!("CCP: decompressing with option type {:?}", c.option_type());
+            c.reset_rx();
+        }
+    }
+
+    /// This-Layer-Down or This-Layer-Finished: stop compressing and decompressing.
+    pub fn down(&mut self) {
+        self.tx = None;
+        self.rx = None;
+        self.resetting = false;
+    }
+
+    /// A Compressed Datagram couldn't be decompressed: ask the peer to reset its history.
+    pub fn decompress_failed(&mut self) {
+        if !self.resetting {
+            self.resetting = true;
+            self.reset_id = self.reset_id.wrapping_add(1);
+            self.reset_at = None;
+        }
+    }
+
+    /// The peer couldn't decompress one of our packets: reset the history and acknowledge.
+    pub fn received_reset_request(&mut self, id: u8) -> Packet<'static> {
+        debug!("CCP: peer requested a reset");
+        if let Some(c) = self.tx() {
+            c.reset_tx();
+        }
+        Packet {
+            proto: ProtocolType::CCP,
+            payload: Payload::PPP(Code::ResetAck, id, PPPPayload::Raw(&mut [])),
+        }
+    }
+
+    /// The peer reset its history: start decompressing again.
+    pub fn received_reset_ack(&mut self, id: u8) {
+        if !self.resetting || id != self.reset_id {
+            debug!("CCP: ignoring unexpected Reset-Ack");
+            return;
+        }
+        self.resetting = false;
+        if let Some(c) = self.rx() {
+            c.reset_rx();
+        }
+    }
+
+    /// When [`poll()`](Self::poll) must be called next to retransmit the Reset-Request.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.reset_at.filter(|_| self.resetting)
+    }
+
+    /// Send the Reset-Request, until the peer acknowledges it.
+    pub fn poll(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        if !self.resetting || self.reset_at.is_some_and(|at| now < at) {
+            return;
+        }
+        self.reset_at = Some(now + self.restart_timeout);
+        tx(Packet {
+            proto: ProtocolType::CCP,
+            payload: Payload::PPP(Code::ResetReq, self.reset_id, PPPPayload::Raw(&mut [])),
+        });
+    }
+
+    /// Our algorithm with option type `code`, by index, if we still request it.
+    fn own_compressor(&self, code: u8) -> Option<usize> {
+        (0..self.compressors.len().min(MAX_OPTIONS))
+            .find(|&i| self.rejected & 1 << i == 0 && self.compressors[i].option_type() == code)
+    }
+}
+
+impl<'a> Protocol for CCP<'a> {
+    fn protocol(&self) -> ProtocolType {
+        ProtocolType::CCP
+    }
+
+    fn peer_options_start(&mut self) {
+        self.tx = None;
+    }
+
+    fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict<'_> {
+        trace!("CCP: rx option {:?} {:?}", code, data);
+        let Some(i) = self
+            .compressors
+            .iter()
+            .position(|c| c.option_type() == code)
+        else {
+            return Verdict::Rej;
+        };
+        // We compress with a single algorithm: the first acceptable one the peer asked for.
+        if self.tx.is_some() {
+            return Verdict::Rej;
+        }
+        match self.compressors[i].tx_option(data, &mut self.nak) {
+            OptionVerdict::Ack => {
+                self.tx = Some(i);
+                Verdict::Ack
+            }
+            OptionVerdict::Nak(n) => Verdict::Nack(&self.nak[..n.min(MAX_OPTION_LEN)]),
+            OptionVerdict::Reject => Verdict::Rej,
+        }
+    }
+
+    fn own_options_start(&mut self) {
+        self.rejected = 0;
+    }
+
+    fn own_options(&mut self, mut f: impl FnMut(u8, &[u8])) {
+        // Only the Ack of this request counts.
+        self.acked = None;
+        for i in 0..self.compressors.len().min(MAX_OPTIONS) {
+            if self.rejected & 1 << i == 0 {
+                let c = self.compressors[i];
+                let mut data = [0; MAX_OPTION_LEN];
+                let n = c.rx_option(&mut data).min(MAX_OPTION_LEN);
+                f(c.option_type(), &data[..n]);
+            }
+        }
+    }
+
+    fn own_option_acked(&mut self, code: u8, _data: &[u8]) {
+        if self.acked.is_none() {
+            self.acked = self.own_compressor(code);
+        }
+    }
+
+    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool, _peer_rej: bool) {
+        trace!("CCP nak {:?} {:?} {:?}", code, data, is_rej);
+        if let Some(i) = self.own_compressor(code) {
+            if is_rej || !self.compressors[i].rx_option_nacked(data) {
+                self.rejected |= 1 << i;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An algorithm that only has an option type.
+    struct Dummy(u8);
+
+    impl Compressor for Dummy {
+        fn option_type(&self) -> u8 {
+            self.0
+        }
+        fn tx_option(&self, _data: &[u8], _nak: &mut [u8]) -> OptionVerdict {
+            OptionVerdict::Ack
+        }
+        fn reset_tx(&self) {}
+        fn reset_rx(&self) {}
+        fn compress(&self, _protocol: u16, _data: &[u8], _out: &mut [u8]) -> Option<usize> {
+            None
+        }
+        fn decompress(&self, _data: &[u8], _out: &mut [u8]) -> Option<(u16, usize)> {
+            None
+        }
+    }
+
+    /// Negotiate our algorithms, the peer acking `acked`, and return the one we decompress with.
+    fn rx_after_ack(acked: &[u8]) -> Option<u8> {
+        let config = Config {
+            compressors: &[&Dummy(1), &Dummy(2), &Dummy(3)],
+            ..Default::default()
+        };
+        let mut ccp = CCP::new(&config);
+        ccp.own_options_start();
+        ccp.own_options(|_, _| {});
+        for &code in acked {
+            ccp.own_option_acked(code, &[]);
+        }
+        ccp.up();
+        ccp.rx().map(|c| c.option_type())
+    }
+
+    #[test]
+    fn rx_from_ack() {
+        assert_eq!(rx_after_ack(&[1, 2, 3]), Some(1));
+        assert_eq!(rx_after_ack(&[3]), Some(3));
+        // Not ours.
+        assert_eq!(rx_after_ack(&[4, 2]), Some(2));
+        assert_eq!(rx_after_ack(&[]), None);
+    }
+
+    #[test]
+    fn rx_after_reject() {
+        let config = Config {
+            compressors: &[&Dummy(1), &Dummy(2)],
+            ..Default::default()
+        };
+        let mut ccp = CCP::new(&config);
+        ccp.own_options_start();
+        ccp.own_option_nacked(1, &[], true, true);
+        let mut requested = 0;
+        ccp.own_options(|code, _| requested = code);
+        assert_eq!(requested, 2);
+        ccp.own_option_acked(2, &[]);
+        ccp.up();
+        assert_eq!(ccp.rx().map(|c| c.option_type()), Some(2));
+
+        // Renegotiation requests the rejected algorithm again.
+        ccp.own_options_start();
+        let mut count = 0;
+        ccp.own_options(|_, _| count += 1);
+        assert_eq!(count, 2);
+    }
+}
@@ -0,0 +1,569 @@
+use super::mschapv2;
+use super::{AuthMessage, Authenticator, Config};
+use crate::crypto::Md5;
+use crate::rand::Rand;
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType, HEADER_LEN, MAX_PACKET_LEN};
+
+// CHAP packet codes, numbered like the LCP ones.
+const CHALLENGE: Code = Code::ConfigureReq;
+const RESPONSE: Code = Code::ConfigureAck;
+const SUCCESS: Code = Code::ConfigureNack;
+const FAILURE: Code = Code::ConfigureRej;
+
+/// Length of an MS-CHAPv2 Response value: Peer-Challenge, 8 reserved bytes, NT-Response and Flags.
+const MSCHAPV2_RESPONSE_LEN: usize = 16 + 8 + 24 + 1;
+/// Longest name we send in a Challenge, so it fits in a packet with the 16-byte value.
+const MAX_CHALLENGE_NAME_LEN: usize = MAX_PACKET_LEN - HEADER_LEN - 1 - 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    Closed,
+    /// Waiting for a Challenge.
+    Listen,
+    /// Waiting for Success or Failure.
+    RespSent,
+    Opened,
+    /// The peer sent a Failure, or it failed to authenticate itself with MS-CHAPv2.
+    Failed,
+    /// The authenticator sent no Challenge, or no Success or Failure after our Response.
+    TimedOut,
+}
+
+/// CHAP algorithm, from the LCP Authentication-Protocol option.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Algorithm {
+    /// MD5, RFC 1994.
+    MD5,
+    /// MS-CHAPv2, RFC 2759.
+    MSCHAPv2,
+}
+
+/// CHAP (RFC 1994), as the peer being authenticated.
+///
+/// There's no retransmission timer: the authenticator retransmits its Challenge if it
+/// doesn't get our Response. We give up if it hasn't for as long as Max-Configure PAP
+/// retransmissions would take.
+#[allow(clippy::upper_case_acronyms)]
+pub struct CHAP<'a> {
+    state: State,
+    algorithm: Algorithm,
+
+    username: &'a [u8],
+    password: &'a [u8],
+
+    /// Identifier of the last Challenge we responded to.
+    id: u8,
+    response: [u8; MSCHAPV2_RESPONSE_LEN],
+    response_len: usize,
+    /// MS-CHAPv2 authenticator response we expect in the Success message.
+    auth_response: [u8; 20],
+    /// Error code from the last MS-CHAPv2 Failure message.
+    error_code: Option<u32>,
+    /// Message from the last Failure.
+    message: AuthMessage,
+
+    /// How long to wait for a Challenge, or for the reply to our Response.
+    wait_timeout: Duration,
+    /// Deadline for the authenticator's next packet. None if not waiting for one.
+    timer: Option<Instant>,
+}
+
+impl<'a> CHAP<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        Self {
+            state: State::Closed,
+            algorithm: Algorithm::MD5,
+            username: config.username,
+            password: config.password,
+            id: 0,
+            response: [0; MSCHAPV2_RESPONSE_LEN],
+            response_len: 0,
+            auth_response: [0; 20],
+            error_code: None,
+            message: AuthMessage::EMPTY,
+            wait_timeout: Duration::from_millis(
+                config
+                    .auth_restart_timeout
+                    .as_millis()
+                    .saturating_mul(config.max_configure.max(1) as u64),
+            ),
+            timer: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Error code the peer sent in the MS-CHAPv2 Failure message, e.g. 691 for bad credentials.
+    pub fn error_code(&self) -> Option<u32> {
+        self.error_code
+    }
+
+    /// Message the peer sent in the Failure.
+    pub fn message(&self) -> AuthMessage {
+        self.message
+    }
+
+    pub fn open(&mut self, algorithm: Algorithm, now: Instant) {
+        assert!(self.state == State::Closed);
+        self.state = State::Listen;
+        self.algorithm = algorithm;
+        self.error_code = None;
+        self.message = AuthMessage::EMPTY;
+        self.timer = Some(now + self.wait_timeout);
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+        self.timer = None;
+    }
+
+    /// When the authenticator's next packet is due, if we're waiting for one.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Give up if the authenticator's next packet didn't arrive in time.
+    pub fn poll(&mut self, now: Instant) {
+        match self.timer {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        self.timer = None;
+        if matches!(self.state, State::Listen | State::RespSent) {
+            warn!("CHAP: authenticator not responding, giving up");
+            debug!("CHAP: state {:?} -> {:?}", self.state, State::TimedOut);
+            self.state = State::TimedOut;
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        pkt: &mut [u8],
+        now: Instant,
+        rand: &mut Rand,
+        mut tx: impl FnMut(Packet<'_>),
+    ) {
+        if pkt.len() < 6 {
+            warn!("CHAP packet too short");
+            return;
+        }
+        let code = Code::from(pkt[2]);
+        let id = pkt[3];
+        let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
+        if len < 4 || len + 2 > pkt.len() {
+            warn!("CHAP packet len invalid");
+            return;
+        }
+        let data = &pkt[6..len + 2];
+
+        debug!("CHAP: rx {:?}", code);
+        let old_state = self.state;
+        match (code, self.state) {
+            (CHALLENGE, State::Listen | State::RespSent | State::Opened) => {
+                let Some((&value_len, rest)) = data.split_first() else {
+                    warn!("CHAP: Challenge too short");
+                    return;
+                };
+                let Some(value) = rest.get(..value_len as usize) else {
+                    warn!("CHAP: Challenge too short");
+                    return;
+                };
+                if !self.compute_response(id, value, rand) {
+                    return;
+                }
+                if HEADER_LEN + 1 + self.response_len + self.username.len() > MAX_PACKET_LEN {
+                    warn!("CHAP: username too long to fit in a Response");
+                    self.state = State::Failed;
+                    self.timer = None;
+                    return;
+                }
+                self.id = id;
+
+                // A Challenge while Opened is a periodic re-challenge: stay Opened unless it fails.
+                if self.state != State::Opened {
+                    self.state = State::RespSent;
+                    self.timer = Some(now + self.wait_timeout);
+                }
+
+                debug!("CHAP: tx {:?}", RESPONSE);
+                tx(Packet {
+                    proto: ProtocolType::CHAP,
+                    payload: Payload::PPP(
+                        RESPONSE,
+                        id,
+                        PPPPayload::CHAP(&self.response[..self.response_len], self.username),
+                    ),
+                });
+            }
+            (SUCCESS, State::RespSent) if id == self.id => {
+                if self.algorithm == Algorithm::MSCHAPv2
+                    && parse_auth_response(data) != Some(self.auth_response)
+                {
+                    warn!("CHAP: invalid MS-CHAPv2 authenticator response");
+                    self.state = State::Failed;
+                } else {
+                    self.state = State::Opened;
+                }
+                self.timer = None;
+            }
+            (FAILURE, State::RespSent | State::Opened) if id == self.id => {
+                if self.algorithm == Algorithm::MSCHAPv2 {
+                    self.error_code = parse_error_code(data);
+                    self.message = AuthMessage::new(parse_failure_text(data));
+                } else {
+                    self.message = AuthMessage::new(data);
+                }
+                warn!("CHAP: authentication failed, error {:?}", self.error_code);
+                self.state = State::Failed;
+                self.timer = None;
+            }
+            _ => {}
+        }
+
+        if old_state != self.state {
+            debug!("CHAP: state {:?} -> {:?}", old_state, self.state);
+        }
+    }
+
+    /// Compute the Response value for a Challenge. Returns false if the Challenge is invalid.
+    fn compute_response(&mut self, id: u8, challenge: &[u8], rand: &mut Rand) -> bool {
+        match self.algorithm {
+            Algorithm::MD5 => {
+                self.response[..16].copy_from_slice(&md5_response(id, self.password, challenge));
+                self.response_len = 16;
+            }
+            Algorithm::MSCHAPv2 => {
+                let Ok(auth_challenge) = <&[u8; 16]>::try_from(challenge) else {
+                    warn!("CHAP: MS-CHAPv2 Challenge must be 16 bytes");
+                    return false;
+                };
+
+                let mut peer_challenge = [0; 16];
+                rand.fill(&mut peer_challenge);
+                let nt_response = mschapv2::generate_nt_response(
+                    auth_challenge,
+                    &peer_challenge,
+                    self.username,
+                    self.password,
+                );
+                self.auth_response = mschapv2::generate_authenticator_response(
+                    self.password,
+                    &nt_response,
+                    &peer_challenge,
+                    auth_challenge,
+                    self.username,
+                );
+
+                self.response = [0; MSCHAPV2_RESPONSE_LEN];
+                self.response[..16].copy_from_slice(&peer_challenge);
+                self.response[24..48].copy_from_slice(&nt_response);
+                self.response_len = MSCHAPV2_RESPONSE_LEN;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ServerState {
+    Closed,
+    /// Waiting for the Response to our Challenge.
+    ChalSent,
+    Opened,
+    /// The peer's Response was wrong, or it didn't send any.
+    Failed,
+}
+
+/// CHAP with MD5 as the authenticator, checking the peer's Responses with the secrets
+/// from the [`Authenticator`].
+pub struct CHAPServer<'a> {
+    state: ServerState,
+    authenticator: Option<&'a dyn Authenticator>,
+    name: &'a [u8],
+
+    id: u8,
+    challenge: [u8; 16],
+
+    restart_timeout: Duration,
+    rechallenge_interval: Option<Duration>,
+    /// Deadline for retransmitting the Challenge, or for the next re-challenge when Opened.
+    timer: Option<Instant>,
+    max_configure: u8,
+    /// Challenges left to send before giving up.
+    restart_count: u8,
+}
+
+impl<'a> CHAPServer<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        Self {
+            state: ServerState::Closed,
+            authenticator: config.authenticator,
+            name: &config.username[..config.username.len().min(MAX_CHALLENGE_NAME_LEN)],
+            id: 0,
+            challenge: [0; 16],
+            restart_timeout: config.auth_restart_timeout,
+            rechallenge_interval: config.rechallenge_interval,
+            timer: None,
+            max_configure: config.max_configure,
+            restart_count: 0,
+        }
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    pub fn open(&mut self, now: Instant, rand: &mut Rand) -> Packet<'_> {
+        assert!(self.state == ServerState::Closed);
+        self.send_challenge(now, rand)
+    }
+
+    pub fn close(&mut self) {
+        self.state = ServerState::Closed;
+        self.timer = None;
+    }
+
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Retransmit the Challenge if no Response arrived in time, or re-challenge the peer.
+    pub fn poll(&mut self, now: Instant, rand: &mut Rand, mut tx: impl FnMut(Packet<'_>)) {
+        match self.timer {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+
+        match self.state {
+            ServerState::ChalSent if self.restart_count == 0 => {
+                warn!(
+                    "CHAP: no Response after {:?} Challenges",
+                    self.max_configure
+                );
+                self.state = ServerState::Failed;
+                self.timer = None;
+            }
+            ServerState::ChalSent => {
+                debug!("CHAP: timeout, retransmitting Challenge");
+                self.restart_count -= 1;
+                self.timer = Some(now + self.restart_timeout);
+                tx(self.challenge_packet());
+            }
+            ServerState::Opened => {
+                debug!("CHAP: re-challenging peer");
+                tx(self.send_challenge(now, rand));
+            }
+            _ => self.timer = None,
+        }
+    }
+
+    pub fn handle(&mut self, pkt: &mut [u8], now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        if pkt.len() < 6 {
+            warn!("CHAP packet too short");
+            return;
+        }
+        let code = Code::from(pkt[2]);
+        let id = pkt[3];
+        let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
+        if len < 4 || len + 2 > pkt.len() {
+            warn!("CHAP packet len invalid");
+            return;
+        }
+        let data = &pkt[6..len + 2];
+
+        if code != RESPONSE || id != self.id {
+            return;
+        }
+        let old_state = self.state;
+        match self.state {
+            ServerState::ChalSent => {
+                let Some((&value_len, rest)) = data.split_first() else {
+                    warn!("CHAP: Response too short");
+                    return;
+                };
+                let Some((value, name)) = rest.split_at_checked(value_len as usize) else {
+                    warn!("CHAP: Response too short");
+                    return;
+                };
+
+                let expected = self
+                    .authenticator
+                    .and_then(|a| a.chap_secret(name))
+                    .map(|secret| md5_response(id, secret, &self.challenge));
+                if expected.is_some_and(|expected| value == expected) {
+                    self.state = ServerState::Opened;
+                    self.timer = self.rechallenge_interval.map(|interval| now + interval);
+                } else {
+                    warn!("CHAP: peer sent a wrong Response");
+                    self.state = ServerState::Failed;
+                    self.timer = None;
+                }
+            }
+            // The peer retransmitted its Response, our Success got lost.
+            ServerState::Opened => {}
+            _ => return,
+        }
+        if old_state != self.state {
+            debug!("CHAP: server state {:?} -> {:?}", old_state, self.state);
+        }
+
+        let mut granted = *b"Access granted";
+        let mut denied = *b"Access denied";
+        let (code, msg): (_, &mut [u8]) = match self.state {
+            ServerState::Opened => (SUCCESS, &mut granted),
+            _ => (FAILURE, &mut denied),
+        };
+        debug!("CHAP: tx {:?}", code);
+        tx(Packet {
+            proto: ProtocolType::CHAP,
+            payload: Payload::PPP(code, id, PPPPayload::Raw(msg)),
+        });
+    }
+
+    fn send_challenge(&mut self, now: Instant, rand: &mut Rand) -> Packet<'_> {
+        self.state = ServerState::ChalSent;
+        self.id = self.id.wrapping_add(1);
+        rand.fill(&mut self.challenge);
+        self.restart_count = self.max_configure.saturating_sub(1);
+        self.timer = Some(now + self.restart_timeout);
+        self.challenge_packet()
+    }
+
+    fn challenge_packet(&self) -> Packet<'_> {
+        debug!("CHAP: tx {:?}", CHALLENGE);
+        Packet {
+            proto: ProtocolType::CHAP,
+            payload: Payload::PPP(
+                CHALLENGE,
+                self.id,
+                PPPPayload::CHAP(&self.challenge, self.name),
+            ),
+        }
+    }
+}
+
+/// CHAP-MD5 Response value, also used by EAP MD5-Challenge.
+pub(super) fn md5_response(id: u8, secret: &[u8], challenge: &[u8]) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    md5.update(&[id]);
+    md5.update(secret);
+    md5.update(challenge);
+    md5.finalize()
+}
+
+/// Parse the `S=<40 hex digits>` authenticator response from an MS-CHAPv2 Success message.
+fn parse_auth_response(msg: &[u8]) -> Option<[u8; 20]> {
+    let hex = msg.strip_prefix(b"S=")?.get(..40)?;
+    let mut res = [0; 20];
+    for (r, digits) in res.iter_mut().zip(hex.chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *r = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(res)
+}
+
+/// Parse the `E=<error code>` field from an MS-CHAPv2 Failure message.
+fn parse_error_code(msg: &[u8]) -> Option<u32> {
+    let digits = msg.strip_prefix(b"E=")?;
+    let len = digits.iter().take_while(|b| b.is_ascii_digit()).count();
+    core::str::from_utf8(&digits[..len]).ok()?.parse().ok()
+}
+
+/// Get the `M=<message>` text from an MS-CHAPv2 Failure message. It's the last field.
+fn parse_failure_text(msg: &[u8]) -> &[u8] {
+    msg.windows(3)
+        .position(|w| w == b" M=")
+        .map_or(&[], |pos| &msg[pos + 3..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Challenge packet with a 16-byte value, including the protocol field.
+    fn challenge(id: u8) -> [u8; 2 + 4 + 1 + 16] {
+        let mut pkt = [0; 2 + 4 + 1 + 16];
+        pkt[..2].copy_from_slice(&(ProtocolType::CHAP as u16).to_be_bytes());
+        pkt[2] = u8::from(CHALLENGE);
+        pkt[3] = id;
+        pkt[4..6].copy_from_slice(&(4 + 1 + 16u16).to_be_bytes());
+        pkt[6] = 16;
+        pkt
+    }
+
+    /// Handle a Challenge, returning the length of the Response sent, if any.
+    fn respond(chap: &mut CHAP<'_>, now: Instant) -> Option<usize> {
+        let mut len = None;
+        chap.handle(&mut challenge(1), now, &mut Rand::new(1), |pkt| {
+            len = Some(pkt.buffer_len())
+        });
+        len
+    }
+
+    #[test]
+    fn long_username() {
+        let name = [b'a'; 200];
+        for (len, ok) in [(105, true), (106, false), (200, false)] {
+            let config = Config {
+                username: &name[..len],
+                ..Default::default()
+            };
+            let mut chap = CHAP::new(&config);
+            chap.open(Algorithm::MD5, Instant::ZERO);
+            let res = respond(&mut chap, Instant::ZERO);
+            assert_eq!(res.is_some(), ok);
+            if let Some(len) = res {
+                assert!(len <= MAX_PACKET_LEN);
+            }
+            let state = if ok { State::RespSent } else { State::Failed };
+            assert_eq!(chap.state(), state);
+
+            let mut server = CHAPServer::new(&config);
+            let pkt = server.open(Instant::ZERO, &mut Rand::new(1));
+            assert!(pkt.buffer_len() <= MAX_PACKET_LEN);
+        }
+    }
+
+    #[test]
+    fn long_username_mschapv2() {
+        let name = [b'a'; 200];
+        for (len, ok) in [(72, true), (73, false)] {
+            let config = Config {
+                username: &name[..len],
+                ..Default::default()
+            };
+            let mut chap = CHAP::new(&config);
+            chap.open(Algorithm::MSCHAPv2, Instant::ZERO);
+            let res = respond(&mut chap, Instant::ZERO);
+            assert_eq!(res, ok.then_some(MAX_PACKET_LEN));
+            let state = if ok { State::RespSent } else { State::Failed };
+            assert_eq!(chap.state(), state);
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let config = Config {
+            auth_restart_timeout: Duration::from_secs(3),
+            max_configure: 10,
+            ..Default::default()
+        };
+        let mut chap = CHAP::new(&config);
+        chap.open(Algorithm::MD5, Instant::ZERO);
+        assert_eq!(chap.poll_at(), Some(Instant::from_secs(30)));
+
+        // Each Challenge restarts the timer.
+        respond(&mut chap, Instant::from_secs(20)).unwrap();
+        chap.poll(Instant::from_secs(30));
+        assert_eq!(chap.state(), State::RespSent);
+        chap.poll(Instant::from_secs(50));
+        assert_eq!(chap.state(), State::TimedOut);
+        assert_eq!(chap.poll_at(), None);
+    }
+}
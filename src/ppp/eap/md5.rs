@@ -0,0 +1,33 @@
+use super::{Credentials, Method};
+use crate::ppp::chap::md5_response;
+
+/// MD5-Challenge method, RFC 3748 section 5.4. Same computation as CHAP-MD5.
+pub struct Md5Challenge;
+
+impl Method for Md5Challenge {
+    fn method_type(&self) -> u8 {
+        4
+    }
+
+    fn respond(
+        &mut self,
+        id: u8,
+        data: &[u8],
+        credentials: &Credentials,
+        resp: &mut [u8],
+    ) -> Option<usize> {
+        let (&value_len, rest) = data.split_first()?;
+        let challenge = rest.get(..value_len as usize)?;
+
+        let name = credentials.username;
+        let len = 1 + 16 + name.len();
+        if len > resp.len() {
+            warn!("EAP: MD5-Challenge response too long");
+            return None;
+        }
+        resp[0] = 16;
+        resp[1..17].copy_from_slice(&md5_response(id, credentials.password, challenge));
+        resp[17..len].copy_from_slice(name);
+        Some(len)
+    }
+}
@@ -0,0 +1,263 @@
+//! EAP (RFC 3748), as the peer being authenticated.
+
+mod md5;
+
+use self::md5::Md5Challenge;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType};
+
+// EAP packet codes, numbered like the LCP ones.
+const REQUEST: Code = Code::ConfigureReq;
+const RESPONSE: Code = Code::ConfigureAck;
+const SUCCESS: Code = Code::ConfigureNack;
+const FAILURE: Code = Code::ConfigureRej;
+
+// Types handled by EAP itself rather than by a method.
+const TYPE_IDENTITY: u8 = 1;
+const TYPE_NOTIFICATION: u8 = 2;
+const TYPE_NAK: u8 = 3;
+
+/// Max length of the Type-Data in our Responses.
+const MAX_RESPONSE_LEN: usize = 96;
+
+/// Credentials EAP methods authenticate with, from the [`Config`].
+pub(crate) struct Credentials<'a> {
+    pub(crate) username: &'a [u8],
+    pub(crate) password: &'a [u8],
+}
+
+/// An EAP authentication method.
+pub(crate) trait Method {
+    /// EAP Type of the method.
+    fn method_type(&self) -> u8;
+
+    /// Handle a Request of this method's Type.
+    ///
+    /// `data` is the Type-Data of the Request. Writes the Type-Data of the Response to `resp`,
+    /// and returns its length. Returns None if the Request is invalid and must be dropped.
+    fn respond(
+        &mut self,
+        id: u8,
+        data: &[u8],
+        credentials: &Credentials,
+        resp: &mut [u8],
+    ) -> Option<usize>;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    Closed,
+    /// Answering Requests, waiting for Success or Failure.
+    Listen,
+    Opened,
+    /// The peer sent a Failure.
+    Failed,
+    /// The authenticator sent no Request, Success or Failure in time.
+    TimedOut,
+}
+
+/// EAP as the peer being authenticated.
+///
+/// There's no retransmission timer: the authenticator retransmits its Requests if it
+/// doesn't get our Response. We give up if it hasn't for as long as Max-Configure PAP
+/// retransmissions would take.
+#[allow(clippy::upper_case_acronyms)]
+pub struct EAP<'a> {
+    state: State,
+    credentials: Credentials<'a>,
+
+    md5: Md5Challenge,
+
+    /// Type and Type-Data of the last Response.
+    resp: [u8; 1 + MAX_RESPONSE_LEN],
+
+    /// How long to wait for the authenticator's next packet.
+    wait_timeout: Duration,
+    /// Deadline for the authenticator's next packet. None if not waiting for one.
+    timer: Option<Instant>,
+}
+
+impl<'a> EAP<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        Self {
+            state: State::Closed,
+            credentials: Credentials {
+                username: config.username,
+                password: config.password,
+            },
+            md5: Md5Challenge,
+            resp: [0; 1 + MAX_RESPONSE_LEN],
+            wait_timeout: Duration::from_millis(
+                config
+                    .auth_restart_timeout
+                    .as_millis()
+                    .saturating_mul(config.max_configure.max(1) as u64),
+            ),
+            timer: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn open(&mut self, now: Instant) {
+        assert!(self.state == State::Closed);
+        self.state = State::Listen;
+        self.timer = Some(now + self.wait_timeout);
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+        self.timer = None;
+    }
+
+    /// When the authenticator's next packet is due, if we're waiting for one.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Give up if the authenticator's next packet didn't arrive in time.
+    pub fn poll(&mut self, now: Instant) {
+        match self.timer {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        self.timer = None;
+        if self.state == State::Listen {
+            warn!("EAP: authenticator not responding, giving up");
+            debug!("EAP: state {:?} -> {:?}", self.state, State::TimedOut);
+            self.state = State::TimedOut;
+        }
+    }
+
+    pub fn handle(&mut self, pkt: &mut [u8], now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        if pkt.len() < 6 {
+            warn!("EAP packet too short");
+            return;
+        }
+        let code = Code::from(pkt[2]);
+        let id = pkt[3];
+        let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
+        if len < 4 || len + 2 > pkt.len() {
+            warn!("EAP packet len invalid");
+            return;
+        }
+        let data = &pkt[6..len + 2];
+
+        debug!("EAP: rx {:?}", code);
+        let old_state = self.state;
+        match (code, self.state) {
+            (REQUEST, State::Listen) => {
+                let Some((&typ, data)) = data.split_first() else {
+                    warn!("EAP: Request without Type");
+                    return;
+                };
+                let Some(len) = self.respond(id, typ, data) else {
+                    return;
+                };
+                self.timer = Some(now + self.wait_timeout);
+
+                debug!("EAP: tx Response type {:?}", self.resp[0]);
+                tx(Packet {
+                    proto: ProtocolType::EAP,
+                    payload: Payload::PPP(RESPONSE, id, PPPPayload::Raw(&mut self.resp[..1 + len])),
+                });
+            }
+            (SUCCESS, State::Listen) => {
+                self.state = State::Opened;
+                self.timer = None;
+            }
+            (FAILURE, State::Listen) => {
+                warn!("EAP: authentication failed");
+                self.state = State::Failed;
+                self.timer = None;
+            }
+            _ => {}
+        }
+
+        if old_state != self.state {
+            debug!("EAP: state {:?} -> {:?}", old_state, self.state);
+        }
+    }
+
+    /// Build the Response to a Request of Type `typ` in `self.resp`.
+    ///
+    /// Returns the length of the Type-Data, or None if the Request must be dropped.
+    fn respond(&mut self, id: u8, typ: u8, data: &[u8]) -> Option<usize> {
+        let Self {
+            credentials,
+            md5,
+            resp,
+            ..
+        } = self;
+        let (resp_type, resp_data) = resp.split_first_mut().unwrap();
+        let mut methods: [&mut dyn Method; 1] = [md5];
+
+        *resp_type = typ;
+        match typ {
+            TYPE_IDENTITY => {
+                let username = credentials.username;
+                if username.len() > resp_data.len() {
+                    warn!("EAP: identity too long");
+                    return None;
+                }
+                resp_data[..username.len()].copy_from_slice(username);
+                Some(username.len())
+            }
+            TYPE_NOTIFICATION => {
+                info!("EAP: notification: {:?}", data);
+                Some(0)
+            }
+            // Only sent in Responses.
+            TYPE_NAK => None,
+            _ => match methods.iter_mut().find(|m| m.method_type() == typ) {
+                Some(method) => method.respond(id, data, credentials, resp_data),
+                None => {
+                    // Steer the authenticator to the methods we support.
+                    debug!("EAP: unsupported method {:?}, sending Nak", typ);
+                    *resp_type = TYPE_NAK;
+                    for (r, m) in resp_data.iter_mut().zip(&methods) {
+                        *r = m.method_type();
+                    }
+                    Some(methods.len())
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Handle an Identity Request, returning whether a Response was sent.
+    fn request_identity(eap: &mut EAP<'_>, now: Instant) -> bool {
+        let mut pkt = [0xc2, 0x27, u8::from(REQUEST), 1, 0, 5, TYPE_IDENTITY];
+        let mut sent = false;
+        eap.handle(&mut pkt, now, |_| sent = true);
+        sent
+    }
+
+    #[test]
+    fn timeout() {
+        let config = Config {
+            auth_restart_timeout: Duration::from_secs(3),
+            max_configure: 10,
+            ..Default::default()
+        };
+        let mut eap = EAP::new(&config);
+        eap.open(Instant::ZERO);
+        assert_eq!(eap.poll_at(), Some(Instant::from_secs(30)));
+
+        // Each Request restarts the timer.
+        assert!(request_identity(&mut eap, Instant::from_secs(20)));
+        eap.poll(Instant::from_secs(30));
+        assert_eq!(eap.state(), State::Listen);
+        eap.poll(Instant::from_secs(50));
+        assert_eq!(eap.state(), State::TimedOut);
+        assert_eq!(eap.poll_at(), None);
+    }
+}
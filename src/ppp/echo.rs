@@ -0,0 +1,145 @@
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType, MAX_PACKET_LEN};
+
+/// LCP Echo-Request keepalive, to detect a dead peer.
+pub(crate) struct Echo {
+    interval: Option<Duration>,
+    max_failures: u8,
+
+    id: u8,
+    /// Echo-Requests sent without getting a reply.
+    failures: u8,
+    /// When the last Echo-Request was sent.
+    sent_at: Option<Instant>,
+    /// When to send the next Echo-Request. None if the keepalive is stopped.
+    next: Option<Instant>,
+    /// Round-trip time measured from the last Echo-Reply.
+    rtt: Option<Duration>,
+}
+
+impl Echo {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            interval: config.echo_interval,
+            max_failures: config.echo_failure,
+            id: 0,
+            failures: 0,
+            sent_at: None,
+            next: None,
+            rtt: None,
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Start sending Echo-Requests, when LCP goes up.
+    pub fn start(&mut self, now: Instant) {
+        self.failures = 0;
+        self.sent_at = None;
+        self.rtt = None;
+        self.next = self.interval.map(|interval| now + interval);
+    }
+
+    /// Stop sending Echo-Requests, when LCP goes down.
+    pub fn stop(&mut self) {
+        self.next = None;
+        self.sent_at = None;
+    }
+
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.next
+    }
+
+    /// Send the next Echo-Request if it's due.
+    ///
+    /// Returns true if the peer failed to reply to too many requests.
+    pub fn poll(&mut self, now: Instant, magic: u32, mut tx: impl FnMut(Packet<'_>)) -> bool {
+        let (Some(next), Some(interval)) = (self.next, self.interval) else {
+            return false;
+        };
+        if next > now {
+            return false;
+        }
+
+        if self.max_failures != 0 && self.failures >= self.max_failures {
+            warn!(
+                "LCP: no reply to {:?} Echo-Requests, peer is dead",
+                self.failures
+            );
+            self.stop();
+            return true;
+        }
+
+        self.id = self.id.wrapping_add(1);
+        self.failures = self.failures.saturating_add(1);
+        self.sent_at = Some(now);
+        self.next = Some(now + interval);
+
+        let mut data = magic.to_be_bytes();
+        trace!("LCP: tx EchoReq id {:?}", self.id);
+        tx(Packet {
+            proto: ProtocolType::LCP,
+            payload: Payload::PPP(Code::EchoReq, self.id, PPPPayload::Raw(&mut data)),
+        });
+        false
+    }
+
+    /// Build the Echo-Reply for a received Echo-Request, containing our Magic-Number.
+    ///
+    /// `pkt` includes the protocol field. Returns None if it must not be answered.
+    pub fn received_request<'a>(&mut self, pkt: &'a mut [u8], magic: u32) -> Option<Packet<'a>> {
+        let len = match pkt.get(4..6) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => 0,
+        };
+        if len < 8 || 2 + len > pkt.len() {
+            warn!("LCP: EchoReq too short");
+            return None;
+        }
+        if magic != 0 && pkt[6..10] == magic.to_be_bytes() {
+            warn!("LCP: received our own EchoReq, link is looped back");
+            return None;
+        }
+
+        // Drop any padding after the packet, and truncate the data so the reply fits in our
+        // buffer.
+        let len = len.min(MAX_PACKET_LEN - 2);
+        let pkt = &mut pkt[..2 + len];
+        pkt[2] = Code::EchoReply as u8;
+        pkt[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        pkt[6..10].copy_from_slice(&magic.to_be_bytes());
+        Some(Packet {
+            proto: ProtocolType::LCP,
+            payload: Payload::Raw(&mut pkt[2..]),
+        })
+    }
+
+    /// Handle a received Echo-Reply.
+    ///
+    /// `pkt` includes the protocol field.
+    pub fn received_reply(&mut self, pkt: &[u8], magic: u32, now: Instant) {
+        if self.next.is_none() || pkt.len() < 10 {
+            return;
+        }
+        if magic != 0 && pkt[6..10] == magic.to_be_bytes() {
+            warn!("LCP: received our own EchoReply, link is looped back");
+            return;
+        }
+
+        let id = pkt[3];
+
+        // Only the reply to our latest request shows the peer is alive, and gives the
+        // round-trip time.
+        if id == self.id {
+            self.failures = 0;
+            if let Some(sent_at) = self.sent_at.take() {
+                let rtt = now - sent_at;
+                trace!("LCP: echo rtt {:?} ms", rtt.as_millis());
+                self.rtt = Some(rtt);
+            }
+        }
+    }
+}
@@ -3,6 +3,8 @@ use core::net::Ipv4Addr;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
 use super::option_fsm::{Protocol, Verdict};
+use super::{AddressPool, Config};
+use crate::compress::{iphc, vj};
 use crate::wire::ProtocolType;
 
 #[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug)]
@@ -11,9 +13,14 @@ use crate::wire::ProtocolType;
 enum OptionCode {
     #[num_enum(default)]
     Unknown = 0,
+    /// Deprecated IP-Addresses option, RFC 1172.
+    IpAddresses = 1,
+    IpCompressionProtocol = 2,
     IpAddress = 3,
     Dns1 = 129,
+    Nbns1 = 130,
     Dns2 = 131,
+    Nbns2 = 132,
 }
 
 struct IpOption {
@@ -22,9 +29,9 @@ struct IpOption {
 }
 
 impl IpOption {
-    fn new() -> Self {
+    fn new(address: Ipv4Addr) -> Self {
         Self {
-            address: Ipv4Addr::UNSPECIFIED,
+            address,
             is_rejected: false,
         }
     }
@@ -53,6 +60,13 @@ impl IpOption {
     }
 }
 
+/// IP-Compression-Protocol option data for Van Jacobson compression: the protocol, then
+/// Max-Slot-Id and Comp-Slot-Id.
+fn vj_option(max_slot_id: u8, compress_slot_id: bool) -> [u8; 4] {
+    let proto = u16::from(ProtocolType::VJCompressedTCP).to_be_bytes();
+    [proto[0], proto[1], max_slot_id, compress_slot_id as u8]
+}
+
 /// Status of the IPv4 connection.
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -63,24 +77,90 @@ pub struct Ipv4Status {
     pub peer_address: Option<Ipv4Addr>,
     /// DNS servers provided by the peer.
     pub dns_servers: [Option<Ipv4Addr>; 2],
+    /// NBNS (WINS) servers provided by the peer.
+    pub nbns_servers: [Option<Ipv4Addr>; 2],
 }
 
-pub(crate) struct IPv4CP {
+pub(crate) struct IPv4CP<'a> {
     peer_address: Ipv4Addr,
+    /// Address the peer must use, as sent in Configure-Naks. None accepts any.
+    expected_peer_address: Option<[u8; 4]>,
+    /// Whether to take the address the peer suggests for us in a Configure-Nak.
+    accept_local: bool,
+
+    pool: Option<&'a dyn AddressPool>,
+    /// Address allocated from the pool for the peer.
+    allocated_peer_address: Option<[u8; 4]>,
+    /// IP-Address or IP-Addresses option data we last sent in a Configure-Nak.
+    peer_address_nak: [u8; 8],
+    /// DNS and NBNS servers to provide to the peer, as sent in Configure-Naks.
+    peer_dns_servers: [Option<[u8; 4]>; 2],
+    peer_nbns_servers: [Option<[u8; 4]>; 2],
 
     address: IpOption,
+    /// Whether to request our address with the deprecated IP-Addresses option, because
+    /// the peer rejected IP-Address.
+    use_ip_addresses: bool,
     dns_server_1: IpOption,
     dns_server_2: IpOption,
+    /// Whether to request NBNS servers, from the config.
+    request_nbns: bool,
+    nbns_server_1: IpOption,
+    nbns_server_2: IpOption,
+
+    /// VJ compression slots we can use in each direction, from the config. 0 if disabled.
+    vj_slots: u8,
+    /// Max-Slot-Id and Comp-Slot-Id we request for receiving. None if disabled or rejected.
+    vj_rx: Option<(u8, bool)>,
+    /// Max-Slot-Id and Comp-Slot-Id the peer requested for receiving, i.e. our sending.
+    vj_tx: Option<(u8, bool)>,
+    /// Whether IPHC is enabled in the config. It's preferred over VJ.
+    iphc: bool,
+    /// IPHC parameters we request for receiving. None if disabled or rejected.
+    iphc_rx: Option<iphc::Params>,
+    /// IPHC parameters the peer requested for receiving, i.e. our sending.
+    iphc_tx: Option<iphc::Params>,
+    /// IP-Compression-Protocol option data we last sent in a Configure-Nak.
+    compression_nak: [u8; 12],
 }
 
-impl IPv4CP {
-    pub fn new() -> Self {
+impl<'a> IPv4CP<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        let iphc = config.iphc && iphc::MAX_CONTEXTS != 0;
         Self {
             peer_address: Ipv4Addr::UNSPECIFIED,
+            expected_peer_address: config.ipv4_peer_address.map(|addr| addr.octets()),
+            // Without an address of our own, we need the peer's.
+            accept_local: config.ipv4_address.is_none() || config.ipv4_accept_local,
 
-            address: IpOption::new(),
-            dns_server_1: IpOption::new(),
-            dns_server_2: IpOption::new(),
+            pool: config.address_pool,
+            allocated_peer_address: None,
+            peer_address_nak: [0; 8],
+            peer_dns_servers: config
+                .peer_dns_servers
+                .map(|addr| addr.map(|addr| addr.octets())),
+            peer_nbns_servers: config
+                .peer_nbns_servers
+                .map(|addr| addr.map(|addr| addr.octets())),
+
+            address: IpOption::new(config.ipv4_address.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+            use_ip_addresses: false,
+            dns_server_1: IpOption::new(Ipv4Addr::UNSPECIFIED),
+            dns_server_2: IpOption::new(Ipv4Addr::UNSPECIFIED),
+            request_nbns: config.request_nbns_servers,
+            nbns_server_1: IpOption::new(Ipv4Addr::UNSPECIFIED),
+            nbns_server_2: IpOption::new(Ipv4Addr::UNSPECIFIED),
+
+            vj_slots: config.vj_slots.min(vj::MAX_SLOTS as u8),
+            vj_rx: match config.vj_slots {
+                0 => None,
+                n => Some((n.min(vj::MAX_SLOTS as u8) - 1, true)),
+            },
+            vj_tx: None,
+            iphc,
+            iphc_rx: iphc.then_some(iphc::Params::OURS),
+            iphc_tx: None,
+            compression_nak: [0; 12],
         }
     }
 
@@ -95,35 +175,201 @@ impl IPv4CP {
             address: self.address.get(),
             peer_address,
             dns_servers: [self.dns_server_1.get(), self.dns_server_2.get()],
+            nbns_servers: [self.nbns_server_1.get(), self.nbns_server_2.get()],
+        }
+    }
+
+    /// Number of slots and whether the slot number may be omitted, for compressing the packets
+    /// we send. 0 slots if the peer didn't ask for VJ compression.
+    pub fn vj_tx_params(&self) -> (usize, bool) {
+        match self.vj_tx {
+            Some((max_slot_id, compress_slot_id)) => (
+                (max_slot_id as usize + 1).min(self.vj_slots as usize),
+                compress_slot_id,
+            ),
+            None => (0, false),
+        }
+    }
+
+    /// Number of slots for decompressing the packets we receive. 0 if the peer rejected
+    /// VJ compression.
+    pub fn vj_rx_slots(&self) -> usize {
+        self.vj_rx
+            .map_or(0, |(max_slot_id, _)| max_slot_id as usize + 1)
+    }
+
+    /// IPHC parameters for compressing the packets we send. None if the peer didn't ask for IPHC.
+    pub fn iphc_tx_params(&self) -> Option<iphc::Params> {
+        self.iphc_tx
+    }
+
+    /// Whether the peer agreed to send us IPHC packets.
+    pub fn iphc_rx_enabled(&self) -> bool {
+        self.iphc_rx.is_some()
+    }
+
+    /// Return the peer's address to the pool, if it was allocated from it.
+    pub fn release_peer_address(&mut self) {
+        if let (Some(pool), Some(addr)) = (self.pool, self.allocated_peer_address.take()) {
+            debug!("IPv4CP: releasing peer address {:?}", Ipv4Addr::from(addr));
+            pool.release(Ipv4Addr::from(addr));
+        }
+    }
+
+    /// Address the peer must use: the configured one, or else one from the pool.
+    ///
+    /// Returns Err if the pool is exhausted.
+    fn wanted_peer_address(&mut self) -> Result<Option<[u8; 4]>, ()> {
+        if self.expected_peer_address.is_some() {
+            return Ok(self.expected_peer_address);
+        }
+        let Some(pool) = self.pool else {
+            return Ok(None);
+        };
+        if self.allocated_peer_address.is_none() {
+            match pool.allocate() {
+                Some(addr) => {
+                    debug!("IPv4CP: allocated peer address {:?}", addr);
+                    self.allocated_peer_address = Some(addr.octets());
+                }
+                None => {
+                    warn!("IPv4CP: address pool exhausted");
+                    return Err(());
+                }
+            }
         }
+        Ok(self.allocated_peer_address)
     }
 }
 
-impl Protocol for IPv4CP {
+/// Verdict on the peer asking for a server address, e.g. a DNS server: Nak it with ours if
+/// it's different.
+fn provide_server<'a>(server: &'a Option<[u8; 4]>, data: &[u8]) -> Verdict<'a> {
+    match server {
+        Some(addr) if addr[..] == *data => Verdict::Ack,
+        Some(addr) => Verdict::Nack(addr),
+        None => Verdict::Rej,
+    }
+}
+
+impl Protocol for IPv4CP<'_> {
     fn protocol(&self) -> ProtocolType {
         ProtocolType::IPv4CP
     }
 
-    fn peer_options_start(&mut self) {}
+    fn peer_options_start(&mut self) {
+        // If the peer doesn't send its address, it's the one we expect, if any.
+        self.peer_address = match self.expected_peer_address {
+            Some(addr) => Ipv4Addr::from(addr),
+            None => Ipv4Addr::UNSPECIFIED,
+        };
+        self.vj_tx = None;
+        self.iphc_tx = None;
+    }
 
     fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict {
         let opt = OptionCode::from(code);
         trace!("IPv4CP: rx option {:?} {:?} {:?}", code, opt, data);
         match opt {
             OptionCode::IpAddress => match <[u8; 4]>::try_from(data) {
+                Ok(data) => match self.wanted_peer_address() {
+                    Ok(Some(wanted)) if wanted != data => {
+                        self.peer_address_nak[..4].copy_from_slice(&wanted);
+                        Verdict::Nack(&self.peer_address_nak[..4])
+                    }
+                    Ok(_) => {
+                        self.peer_address = Ipv4Addr::from(data);
+                        Verdict::Ack
+                    }
+                    Err(()) => Verdict::Rej,
+                },
+                Err(_) => Verdict::Rej,
+            },
+            // The peer's address, then the one it wants us to use. Only the first is negotiated
+            // here, ours is negotiated with our own request.
+            OptionCode::IpAddresses => match <[u8; 8]>::try_from(data) {
                 Ok(data) => {
-                    self.peer_address = Ipv4Addr::from(data);
-                    Verdict::Ack
+                    let (src, dst) = data.split_at(4);
+                    let src: [u8; 4] = unwrap!(src.try_into());
+                    match self.wanted_peer_address() {
+                        Ok(Some(wanted)) if wanted != src => {
+                            self.peer_address_nak[..4].copy_from_slice(&wanted);
+                            self.peer_address_nak[4..].copy_from_slice(dst);
+                            Verdict::Nack(&self.peer_address_nak)
+                        }
+                        Ok(_) => {
+                            self.peer_address = Ipv4Addr::from(src);
+                            Verdict::Ack
+                        }
+                        Err(()) => Verdict::Rej,
+                    }
                 }
                 Err(_) => Verdict::Rej,
             },
-            _ => Verdict::Rej,
+            OptionCode::IpCompressionProtocol if self.vj_slots == 0 && !self.iphc => Verdict::Rej,
+            OptionCode::IpCompressionProtocol => match (data, iphc::Params::parse(data)) {
+                (&[0x00, 0x2d, max_slot_id, compress_slot_id], _) if self.vj_slots != 0 => {
+                    self.vj_tx = Some((max_slot_id, compress_slot_id != 0));
+                    Verdict::Ack
+                }
+                (_, Some(params)) if self.iphc => {
+                    self.iphc_tx = Some(params);
+                    Verdict::Ack
+                }
+                // Another protocol, or the old form of VJ without the slot ids (RFC 1172).
+                // Suggest the one we prefer.
+                _ if self.iphc => {
+                    self.compression_nak = iphc::Params::OURS.option_data();
+                    Verdict::Nack(&self.compression_nak)
+                }
+                _ => {
+                    self.compression_nak[..4].copy_from_slice(&vj_option(self.vj_slots - 1, true));
+                    Verdict::Nack(&self.compression_nak[..4])
+                }
+            },
+            OptionCode::Dns1 => provide_server(&self.peer_dns_servers[0], data),
+            OptionCode::Dns2 => provide_server(&self.peer_dns_servers[1], data),
+            OptionCode::Nbns1 => provide_server(&self.peer_nbns_servers[0], data),
+            OptionCode::Nbns2 => provide_server(&self.peer_nbns_servers[1], data),
+            OptionCode::Unknown => Verdict::Rej,
         }
     }
 
+    fn own_options_start(&mut self) {
+        self.address.is_rejected = false;
+        self.use_ip_addresses = false;
+        self.dns_server_1.is_rejected = false;
+        self.dns_server_2.is_rejected = false;
+        self.nbns_server_1.is_rejected = false;
+        self.nbns_server_2.is_rejected = false;
+        self.vj_rx = match self.vj_slots {
+            0 => None,
+            n => Some((n - 1, true)),
+        };
+        self.iphc_rx = self.iphc.then_some(iphc::Params::OURS);
+    }
+
     fn own_options(&mut self, mut f: impl FnMut(u8, &[u8])) {
         if !self.address.is_rejected {
-            f(OptionCode::IpAddress.into(), &self.address.address.octets());
+            if self.use_ip_addresses {
+                let mut data = [0; 8];
+                data[..4].copy_from_slice(&self.address.address.octets());
+                data[4..].copy_from_slice(&self.peer_address.octets());
+                f(OptionCode::IpAddresses.into(), &data);
+            } else {
+                f(OptionCode::IpAddress.into(), &self.address.address.octets());
+            }
+        }
+        if let Some(params) = self.iphc_rx {
+            f(
+                OptionCode::IpCompressionProtocol.into(),
+                &params.option_data(),
+            );
+        } else if let Some((max_slot_id, compress_slot_id)) = self.vj_rx {
+            f(
+                OptionCode::IpCompressionProtocol.into(),
+                &vj_option(max_slot_id, compress_slot_id),
+            );
         }
         if !self.dns_server_1.is_rejected {
             f(OptionCode::Dns1.into(), &self.dns_server_1.address.octets());
@@ -131,16 +377,64 @@ impl Protocol for IPv4CP {
         if !self.dns_server_2.is_rejected {
             f(OptionCode::Dns2.into(), &self.dns_server_2.address.octets());
         }
+        if self.request_nbns && !self.nbns_server_1.is_rejected {
+            f(
+                OptionCode::Nbns1.into(),
+                &self.nbns_server_1.address.octets(),
+            );
+        }
+        if self.request_nbns && !self.nbns_server_2.is_rejected {
+            f(
+                OptionCode::Nbns2.into(),
+                &self.nbns_server_2.address.octets(),
+            );
+        }
     }
 
-    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool) {
+    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool, peer_rej: bool) {
         let opt = OptionCode::from(code);
         trace!("IPv4CP nak {:?} {:?} {:?} {:?}", code, opt, data, is_rej);
         match opt {
             OptionCode::Unknown => {}
+            OptionCode::IpAddress | OptionCode::IpAddresses if !is_rej && !self.accept_local => {
+                debug!("IPv4CP: ignoring Nak of our address")
+            }
+            // Older peers may only know the deprecated option. Naks past Max-Failure don't
+            // mean that, just that the peer wants another address.
+            OptionCode::IpAddress if peer_rej && !self.use_ip_addresses => {
+                debug!("IPv4CP: IP-Address rejected, falling back to IP-Addresses");
+                self.use_ip_addresses = true;
+            }
             OptionCode::IpAddress => self.address.nacked(data, is_rej),
+            // Our address comes first, the second one is the peer's.
+            OptionCode::IpAddresses if !is_rej => match data.get(..4) {
+                Some(data) => self.address.nacked(data, false),
+                None => self.address.is_rejected = true,
+            },
+            OptionCode::IpAddresses => self.address.nacked(data, true),
+            // Follow the peer's suggestion if we support it, or else stop asking.
+            OptionCode::IpCompressionProtocol => {
+                self.iphc_rx = match (iphc::Params::parse(data), self.iphc_rx) {
+                    (Some(nak), Some(params)) if !is_rej => Some(params.nacked(nak)),
+                    _ => None,
+                };
+                self.vj_rx = match (data, self.vj_rx) {
+                    // Use fewer slots if the peer wants to, but never more.
+                    (
+                        &[0x00, 0x2d, nak_max_slot_id, nak_compress_slot_id],
+                        Some((max_slot_id, compress_slot_id)),
+                    ) if !is_rej => Some((
+                        max_slot_id.min(nak_max_slot_id),
+                        compress_slot_id && nak_compress_slot_id != 0,
+                    )),
+                    // Rejected, or the peer only wants another protocol.
+                    _ => None,
+                }
+            }
             OptionCode::Dns1 => self.dns_server_1.nacked(data, is_rej),
             OptionCode::Dns2 => self.dns_server_2.nacked(data, is_rej),
+            OptionCode::Nbns1 => self.nbns_server_1.nacked(data, is_rej),
+            OptionCode::Nbns2 => self.nbns_server_2.nacked(data, is_rej),
         }
     }
 }
@@ -0,0 +1,255 @@
+use core::net::Ipv6Addr;
+
+use num_enum::{FromPrimitive, IntoPrimitive};
+
+use super::option_fsm::{Protocol, Verdict};
+use super::Config;
+use crate::compress::iphc;
+use crate::rand::Rand;
+use crate::wire::ProtocolType;
+
+#[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+enum OptionCode {
+    #[num_enum(default)]
+    Unknown = 0,
+    InterfaceId = 1,
+    Ipv6CompressionProtocol = 2,
+}
+
+type InterfaceId = [u8; 8];
+
+/// Status of the IPv6 connection.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Ipv6Status {
+    /// Our link-local address, formed from the negotiated Interface-Identifier.
+    pub address: Ipv6Addr,
+    /// The peer's link-local address. None if the peer didn't send its Interface-Identifier.
+    pub peer_address: Option<Ipv6Addr>,
+}
+
+/// IPv6 Control Protocol, RFC 5072.
+pub(crate) struct IPv6CP {
+    /// Whether IPv6CP is enabled in the config.
+    enabled: bool,
+
+    /// Our Interface-Identifier.
+    iid: InterfaceId,
+    iid_rej: bool,
+    /// The peer's Interface-Identifier, all zeros if it didn't send one.
+    peer_iid: InterfaceId,
+    /// Interface-Identifier the peer last asked for, even if we didn't Ack it.
+    peer_iid_req: InterfaceId,
+    /// Interface-Identifier we last suggested to the peer in a Configure-Nak.
+    peer_iid_nak: InterfaceId,
+    rand: Rand,
+
+    /// Whether IPHC is enabled in the config.
+    iphc: bool,
+    /// IPHC parameters we request for receiving. None if disabled or rejected.
+    iphc_rx: Option<iphc::Params>,
+    /// IPHC parameters the peer requested for receiving, i.e. our sending.
+    iphc_tx: Option<iphc::Params>,
+    /// IPv6-Compression-Protocol option data we last sent in a Configure-Nak.
+    iphc_nak: [u8; 12],
+}
+
+impl IPv6CP {
+    pub fn new(config: &Config) -> Self {
+        // Use a different sequence than LCP's Magic-Numbers.
+        let mut rand = Rand::new(config.random_seed ^ u64::from(u16::from(ProtocolType::IPv6CP)));
+        let iphc = config.iphc && iphc::MAX_CONTEXTS != 0;
+        Self {
+            enabled: config.ipv6,
+            iid: new_iid(&mut rand),
+            iid_rej: false,
+            peer_iid: [0; 8],
+            peer_iid_req: [0; 8],
+            peer_iid_nak: [0; 8],
+            rand,
+            iphc,
+            iphc_rx: iphc.then_some(iphc::Params::OURS),
+            iphc_tx: None,
+            iphc_nak: [0; 12],
+        }
+    }
+
+    /// Whether IPv6CP is negotiated at all.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the peer insists on the same Interface-Identifier as ours, so both ends would
+    /// have the same link-local address. This happens if Max-Failure turned our Naks of its
+    /// identifier into a Reject.
+    pub fn duplicate_iid(&self) -> bool {
+        self.peer_iid_req == self.iid
+    }
+
+    pub fn status(&self) -> Ipv6Status {
+        Ipv6Status {
+            address: link_local(self.iid),
+            peer_address: if self.peer_iid == [0; 8] {
+                None
+            } else {
+                Some(link_local(self.peer_iid))
+            },
+        }
+    }
+
+    /// IPHC parameters for compressing the packets we send. None if the peer didn't ask for IPHC.
+    pub fn iphc_tx_params(&self) -> Option<iphc::Params> {
+        self.iphc_tx
+    }
+
+    /// Whether the peer agreed to send us IPHC packets.
+    pub fn iphc_rx_enabled(&self) -> bool {
+        self.iphc_rx.is_some()
+    }
+}
+
+/// Generate a random, non-zero Interface-Identifier.
+fn new_iid(rand: &mut Rand) -> InterfaceId {
+    loop {
+        let mut iid = [0; 8];
+        rand.fill(&mut iid);
+        // Clear the universal/local bit: it's not derived from a globally unique MAC
+        // address (RFC 4291 appendix A).
+        iid[0] &= !0x02;
+        if iid != [0; 8] {
+            return iid;
+        }
+    }
+}
+
+/// Link-local address for an Interface-Identifier, `fe80::<iid>`.
+fn link_local(iid: InterfaceId) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets[0] = 0xfe;
+    octets[1] = 0x80;
+    octets[8..].copy_from_slice(&iid);
+    Ipv6Addr::from(octets)
+}
+
+impl Protocol for IPv6CP {
+    fn protocol(&self) -> ProtocolType {
+        ProtocolType::IPv6CP
+    }
+
+    fn peer_options_start(&mut self) {
+        self.peer_iid = [0; 8];
+        self.iphc_tx = None;
+    }
+
+    fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict<'_> {
+        let opt = OptionCode::from(code);
+        trace!("IPv6CP: rx option {:?} {:?} {:?}", code, opt, data);
+        match opt {
+            OptionCode::InterfaceId => match InterfaceId::try_from(data) {
+                Ok(iid) => {
+                    self.peer_iid_req = iid;
+                    // RFC 5072 section 4.1: the identifiers must be non-zero and differ.
+                    // Suggest another one to the peer.
+                    if iid == [0; 8] || iid == self.iid {
+                        loop {
+                            self.peer_iid_nak = new_iid(&mut self.rand);
+                            if self.peer_iid_nak != self.iid {
+                                break;
+                            }
+                        }
+                        Verdict::Nack(&self.peer_iid_nak)
+                    } else {
+                        self.peer_iid = iid;
+                        Verdict::Ack
+                    }
+                }
+                Err(_) => Verdict::Rej,
+            },
+            OptionCode::Ipv6CompressionProtocol if !self.iphc => Verdict::Rej,
+            OptionCode::Ipv6CompressionProtocol => match iphc::Params::parse(data) {
+                Some(params) => {
+                    self.iphc_tx = Some(params);
+                    Verdict::Ack
+                }
+                None => {
+                    self.iphc_nak = iphc::Params::OURS.option_data();
+                    Verdict::Nack(&self.iphc_nak)
+                }
+            },
+            OptionCode::Unknown => Verdict::Rej,
+        }
+    }
+
+    fn own_options_start(&mut self) {
+        self.iid_rej = false;
+        self.iphc_rx = self.iphc.then_some(iphc::Params::OURS);
+    }
+
+    fn own_options(&mut self, mut f: impl FnMut(u8, &[u8])) {
+        if !self.iid_rej {
+            f(OptionCode::InterfaceId.into(), &self.iid);
+        }
+        if let Some(params) = self.iphc_rx {
+            f(
+                OptionCode::Ipv6CompressionProtocol.into(),
+                &params.option_data(),
+            );
+        }
+    }
+
+    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool, _peer_rej: bool) {
+        let opt = OptionCode::from(code);
+        trace!("IPv6CP nak {:?} {:?} {:?} {:?}", code, opt, data, is_rej);
+        match opt {
+            OptionCode::Unknown => {}
+            // Keep using our own identifier, the peer just won't know it.
+            OptionCode::InterfaceId if is_rej => self.iid_rej = true,
+            OptionCode::InterfaceId => match InterfaceId::try_from(data) {
+                // Take the peer's suggestion, unless it collides with its own.
+                Ok(iid) if iid != [0; 8] && iid != self.peer_iid => self.iid = iid,
+                _ => self.iid = new_iid(&mut self.rand),
+            },
+            // Follow the peer's suggestion if it's IPHC, or else stop asking.
+            OptionCode::Ipv6CompressionProtocol => {
+                self.iphc_rx = match (iphc::Params::parse(data), self.iphc_rx) {
+                    (Some(nak), Some(params)) if !is_rej => Some(params.nacked(nak)),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_iid() {
+        let mut ipv6cp = IPv6CP::new(&Config::default());
+        let iid = ipv6cp.iid;
+        let code = OptionCode::InterfaceId.into();
+        assert!(!ipv6cp.duplicate_iid());
+
+        // Still a duplicate once the peer stops sending it, after our Naks turned into a Reject.
+        ipv6cp.peer_options_start();
+        assert!(matches!(
+            ipv6cp.peer_option_received(code, &iid),
+            Verdict::Nack(_)
+        ));
+        ipv6cp.peer_options_start();
+        assert!(ipv6cp.duplicate_iid());
+        assert_eq!(ipv6cp.status().peer_address, None);
+
+        let mut other = iid;
+        other[7] ^= 1;
+        ipv6cp.peer_options_start();
+        assert!(matches!(
+            ipv6cp.peer_option_received(code, &other),
+            Verdict::Ack
+        ));
+        assert!(!ipv6cp.duplicate_iid());
+    }
+}
@@ -1,51 +1,191 @@
 use num_enum::{FromPrimitive, IntoPrimitive};
 
 use super::option_fsm::{Protocol, Verdict};
+use super::{AuthProtocol, Config};
+use crate::rand::Rand;
 use crate::wire::ProtocolType;
 
+/// Consecutive Configure-Naks of our Magic-Number proving loopback after which the
+/// link is declared looped back, or Max-Failure if lower, before the Naks turn into Rejects.
+const LOOPBACK_FAIL: u8 = 3;
+
+/// Maximum-Receive-Unit assumed when it's not negotiated.
+pub(crate) const DEFAULT_MRU: u16 = 1500;
+/// Smallest Maximum-Receive-Unit we accept from the peer.
+const MIN_MRU: u16 = 128;
+const MIN_MRU_NAK: [u8; 2] = MIN_MRU.to_be_bytes();
+
 #[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 enum Option {
     #[num_enum(default)]
     Unknown = 0,
+    Mru = 1,
     Asyncmap = 2,
     Auth = 3,
+    Magic = 5,
+    Pfc = 7,
+    Acfc = 8,
 }
 
+/// Authentication protocol we authenticate ourselves to the peer with.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AuthType {
-    None = 0,
-    PAP = 0xc023,
+    /// No authentication.
+    None,
+    /// Password Authentication Protocol, RFC 1334.
+    PAP,
+    /// CHAP with MD5, RFC 1994.
+    CHAP,
+    /// CHAP with MS-CHAPv2, RFC 2759.
+    MSCHAPv2,
+    /// Extensible Authentication Protocol, RFC 3748.
+    EAP,
+}
+
+impl AuthType {
+    /// Data of the Authentication-Protocol option requesting this type.
+    fn option_data(self) -> &'static [u8] {
+        match self {
+            AuthType::None => &[],
+            AuthType::PAP => &[0xc0, 0x23],
+            AuthType::CHAP => &[0xc2, 0x23, 0x05],
+            AuthType::MSCHAPv2 => &[0xc2, 0x23, 0x81],
+            AuthType::EAP => &[0xc2, 0x27],
+        }
+    }
 }
 
-pub(crate) struct LCP {
+pub(crate) struct LCP<'a> {
     pub auth: AuthType,
+    /// Types we accept to authenticate with, most preferred first.
+    auth_types: &'a [AuthType],
+    /// Protocol the peer must authenticate with us with, if any.
+    authenticate_peer: core::option::Option<AuthProtocol>,
+    /// Whether the peer rejected authenticating.
+    authenticate_peer_rej: bool,
+
+    /// Largest MRU we can receive, from the config.
+    max_mru: u16,
+    /// MRU we request.
+    mru: u16,
+    mru_rej: bool,
+    /// MRU of the peer, i.e. the largest packet we may send.
+    pub peer_mru: u16,
 
     pub asyncmap_remote: u32,
     pub asyncmap: u32,
     pub asyncmap_rej: bool,
+
+    magic: u32,
+    magic_rej: bool,
+    /// Magic-Number we last sent in a Configure-Nak, to detect it being looped back to us.
+    magic_nak: [u8; 4],
+    loop_count: u8,
+    loopback_fail: u8,
+    looped_back: bool,
+    rand: Rand,
+
+    pfc_rej: bool,
+    acfc_rej: bool,
+    /// Whether the peer accepts frames with a compressed protocol field.
+    pub peer_pfc: bool,
+    /// Whether the peer accepts frames without address and control fields.
+    pub peer_acfc: bool,
 }
 
-impl LCP {
-    pub fn new() -> Self {
+impl<'a> LCP<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        let mut rand = Rand::new(config.random_seed);
         Self {
             auth: AuthType::None,
+            auth_types: config.auth_types,
+            authenticate_peer: config.authenticator.map(|_| config.peer_auth_protocol),
+            authenticate_peer_rej: false,
+
+            max_mru: config.mru,
+            mru: config.mru,
+            mru_rej: false,
+            peer_mru: DEFAULT_MRU,
+
             asyncmap_remote: 0xFFFFFFFF,
             asyncmap: 0x00000000,
             asyncmap_rej: false,
+
+            magic: new_magic(&mut rand),
+            magic_rej: false,
+            magic_nak: [0; 4],
+            loop_count: 0,
+            loopback_fail: LOOPBACK_FAIL.min(config.max_failure).max(1),
+            looped_back: false,
+            rand,
+
+            pfc_rej: false,
+            acfc_rej: false,
+            peer_pfc: false,
+            peer_acfc: false,
+        }
+    }
+
+    /// Our negotiated Magic-Number, or 0 if the peer rejected it.
+    pub fn magic(&self) -> u32 {
+        if self.magic_rej {
+            0
+        } else {
+            self.magic
+        }
+    }
+
+    /// Whether the link was detected to be looped back.
+    ///
+    /// Clears the flag, so loopback is reported once.
+    pub fn take_looped_back(&mut self) -> bool {
+        if self.looped_back {
+            self.loop_count = 0;
+        }
+        core::mem::take(&mut self.looped_back)
+    }
+
+    /// Whether the authentication type the peer asked for, possibly none, is acceptable.
+    pub fn auth_acceptable(&self) -> bool {
+        self.auth_types.contains(&self.auth)
+    }
+
+    /// Protocol the peer must authenticate with us with, once LCP is up.
+    pub fn authenticate_peer(&self) -> core::option::Option<AuthProtocol> {
+        self.authenticate_peer
+    }
+
+    /// Whether the peer refused to authenticate with us.
+    ///
+    /// Clears the flag, so authentication is requested again on the next negotiation.
+    pub fn take_authenticate_peer_rej(&mut self) -> bool {
+        core::mem::take(&mut self.authenticate_peer_rej)
+    }
+}
+
+fn new_magic(rand: &mut Rand) -> u32 {
+    // Zero is not a valid Magic-Number.
+    loop {
+        let magic = rand.rand_u32();
+        if magic != 0 {
+            return magic;
         }
     }
 }
 
-impl Protocol for LCP {
+impl Protocol for LCP<'_> {
     fn protocol(&self) -> ProtocolType {
         ProtocolType::LCP
     }
 
     fn peer_options_start(&mut self) {
         self.auth = AuthType::None;
+        self.peer_mru = DEFAULT_MRU;
+        self.peer_pfc = false;
+        self.peer_acfc = false;
     }
 
     fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict {
@@ -53,6 +193,18 @@ impl Protocol for LCP {
         trace!("LCP: rx option {:?} {:?} {:?}", code, opt, data);
         match opt {
             Option::Unknown => Verdict::Rej,
+            Option::Mru => match <[u8; 2]>::try_from(data) {
+                Ok(data) => {
+                    let mru = u16::from_be_bytes(data);
+                    if mru < MIN_MRU {
+                        Verdict::Nack(&MIN_MRU_NAK)
+                    } else {
+                        self.peer_mru = mru;
+                        Verdict::Ack
+                    }
+                }
+                Err(_) => Verdict::Rej,
+            },
             Option::Asyncmap => {
                 if data.len() == 4 {
                     self.asyncmap_remote = u32::from_be_bytes(data.try_into().unwrap());
@@ -62,26 +214,103 @@ impl Protocol for LCP {
                 }
             }
             Option::Auth => {
-                if data == &[0xc0, 0x23] {
-                    self.auth = AuthType::PAP;
-                    Verdict::Ack
-                } else {
-                    Verdict::Nack(&[0xc0, 0x23])
+                let auth = [
+                    AuthType::PAP,
+                    AuthType::CHAP,
+                    AuthType::MSCHAPv2,
+                    AuthType::EAP,
+                ]
+                .into_iter()
+                .find(|auth| auth.option_data() == data);
+                match auth {
+                    Some(auth) if self.auth_types.contains(&auth) => {
+                        self.auth = auth;
+                        Verdict::Ack
+                    }
+                    // Suggest the one we prefer, or refuse to authenticate if we accept none.
+                    _ => match self.auth_types.iter().find(|auth| **auth != AuthType::None) {
+                        Some(auth) => Verdict::Nack(auth.option_data()),
+                        None => Verdict::Rej,
+                    },
+                }
+            }
+            Option::Magic => match <[u8; 4]>::try_from(data) {
+                Ok(data) => {
+                    let magic = u32::from_be_bytes(data);
+                    if magic == 0 || (!self.magic_rej && magic == self.magic) {
+                        // Either invalid, or the same as ours so the link may be looped back.
+                        // Nak with a different number: if we get it back in a Nak, it is.
+                        self.magic_nak = new_magic(&mut self.rand).to_be_bytes();
+                        Verdict::Nack(&self.magic_nak)
+                    } else {
+                        Verdict::Ack
+                    }
                 }
+                Err(_) => Verdict::Rej,
+            },
+            Option::Pfc if data.is_empty() => {
+                self.peer_pfc = true;
+                Verdict::Ack
+            }
+            Option::Acfc if data.is_empty() => {
+                self.peer_acfc = true;
+                Verdict::Ack
             }
+            Option::Pfc | Option::Acfc => Verdict::Rej,
         }
     }
 
+    fn own_options_start(&mut self) {
+        self.mru = self.max_mru;
+        self.mru_rej = false;
+        self.asyncmap = 0x00000000;
+        self.asyncmap_rej = false;
+        self.magic_rej = false;
+        self.authenticate_peer_rej = false;
+        self.pfc_rej = false;
+        self.acfc_rej = false;
+    }
+
     fn own_options(&mut self, mut f: impl FnMut(u8, &[u8])) {
+        // The default MRU need not be requested.
+        if !self.mru_rej && self.mru != DEFAULT_MRU {
+            f(Option::Mru.into(), &self.mru.to_be_bytes());
+        }
         if !self.asyncmap_rej {
             f(Option::Asyncmap.into(), &self.asyncmap.to_be_bytes());
         }
+        if !self.magic_rej {
+            f(Option::Magic.into(), &self.magic.to_be_bytes());
+        }
+        if !self.authenticate_peer_rej {
+            match self.authenticate_peer {
+                None => {}
+                Some(AuthProtocol::PAP) => f(Option::Auth.into(), &[0xc0, 0x23]),
+                Some(AuthProtocol::CHAP) => f(Option::Auth.into(), &[0xc2, 0x23, 0x05]),
+            }
+        }
+        if !self.pfc_rej {
+            f(Option::Pfc.into(), &[]);
+        }
+        if !self.acfc_rej {
+            f(Option::Acfc.into(), &[]);
+        }
     }
 
-    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool) {
+    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool, _peer_rej: bool) {
         let opt = Option::from(code);
         trace!("LCP nak {:?} {:?} {:?} {:?}", code, opt, data, is_rej);
         match opt {
+            Option::Mru => match <[u8; 2]>::try_from(data) {
+                // Only go down: we can't receive frames larger than our buffer.
+                Ok(data) if !is_rej => {
+                    let mru = u16::from_be_bytes(data);
+                    if (MIN_MRU..=self.max_mru).contains(&mru) {
+                        self.mru = mru;
+                    }
+                }
+                _ => self.mru_rej = true,
+            },
             Option::Asyncmap => {
                 if !is_rej && data.len() == 4 {
                     self.asyncmap = u32::from_be_bytes(data.try_into().unwrap())
@@ -89,7 +318,69 @@ impl Protocol for LCP {
                     self.asyncmap_rej = true
                 }
             }
+            Option::Magic => {
+                if is_rej {
+                    self.magic_rej = true;
+                    return;
+                }
+
+                // RFC 1661 section 6.4: a Nak with the Magic-Number we sent in our own Nak
+                // means we're talking to ourselves.
+                if data == self.magic_nak {
+                    self.loop_count += 1;
+                    warn!("LCP: link may be looped back ({:?})", self.loop_count);
+                    if self.loop_count >= self.loopback_fail {
+                        self.looped_back = true;
+                    }
+                } else {
+                    self.loop_count = 0;
+                }
+                self.magic = new_magic(&mut self.rand);
+            }
+            // Keep asking for the configured protocol until the Naks turn into a Reject.
+            Option::Auth => {
+                if is_rej {
+                    warn!("LCP: peer refused to authenticate");
+                    self.authenticate_peer_rej = true;
+                }
+            }
+            // No value to agree on, a Nak can only mean the peer doesn't want them.
+            Option::Pfc => self.pfc_rej = true,
+            Option::Acfc => self.acfc_rej = true,
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two peers with the same seed Nak each other's Magic-Number in lockstep until the link is
+    /// taken as looped back, before Max-Failure turns the Naks into Rejects.
+    #[test]
+    fn same_seed_looped_back() {
+        let config = Config {
+            max_failure: 2,
+            ..Default::default()
+        };
+        let mut a = LCP::new(&config);
+        let mut b = LCP::new(&config);
+        for round in 1..=2 {
+            assert_eq!(a.magic(), b.magic());
+            let magic = a.magic().to_be_bytes();
+            let Verdict::Nack(nak) = a.peer_option_received(Option::Magic.into(), &magic) else {
+                panic!("Magic-Number not Nak'd");
+            };
+            let a_nak: [u8; 4] = nak.try_into().unwrap();
+            let Verdict::Nack(nak) = b.peer_option_received(Option::Magic.into(), &magic) else {
+                panic!("Magic-Number not Nak'd");
+            };
+            let b_nak: [u8; 4] = nak.try_into().unwrap();
+
+            a.own_option_nacked(Option::Magic.into(), &b_nak, false, false);
+            b.own_option_nacked(Option::Magic.into(), &a_nak, false, false);
+            assert_eq!(a.take_looped_back(), round == 2);
+        }
+    }
+}
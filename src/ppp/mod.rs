@@ -1,24 +1,359 @@
+mod ccp;
+mod chap;
+mod eap;
+mod echo;
 mod ipv4cp;
+mod ipv6cp;
 mod lcp;
+mod mschapv2;
 mod option_fsm;
 mod pap;
 
+use core::net::Ipv4Addr;
+
+use self::ccp::CCP;
+use self::chap::{
+    Algorithm as CHAPAlgorithm, CHAPServer, ServerState as CHAPServerState, State as CHAPState,
+    CHAP,
+};
+use self::eap::{State as EAPState, EAP};
+use self::echo::Echo;
 use self::ipv4cp::IPv4CP;
-use self::lcp::{AuthType, LCP};
-use self::option_fsm::{OptionFsm, State};
-use self::pap::{State as PAPState, PAP};
-use crate::wire::{Packet, ProtocolType};
+use self::ipv6cp::IPv6CP;
+use self::lcp::{DEFAULT_MRU, LCP};
+use self::option_fsm::{LayerEvent, OptionFsm, State};
+use self::pap::{PAPServer, ServerState as PAPServerState, State as PAPState, PAP};
+use crate::compress::{iphc, vj};
+use crate::rand::Rand;
+use crate::time::{Duration, Instant};
+use crate::wire::{parse_protocol, Code, Packet, ProtocolType};
 
 pub use self::ipv4cp::Ipv4Status;
+pub use self::ipv6cp::Ipv6Status;
+pub use self::lcp::AuthType;
+
+/// Verifies the credentials of a peer authenticating with us.
+///
+/// Set in [`Config::authenticator`] to require the peer to authenticate.
+pub trait Authenticator {
+    /// Check the username and password the peer sent with PAP.
+    ///
+    /// The default implementation rejects everything.
+    fn check_pap(&self, username: &[u8], password: &[u8]) -> bool {
+        let _ = (username, password);
+        false
+    }
+
+    /// Get the CHAP secret shared with the peer named `name`. None if it's unknown.
+    ///
+    /// The default implementation knows no one.
+    fn chap_secret(&self, name: &[u8]) -> Option<&[u8]> {
+        let _ = name;
+        None
+    }
+}
+
+impl core::fmt::Debug for dyn Authenticator + '_ {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for dyn Authenticator + '_ {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Authenticator")
+    }
+}
+
+/// Allocates IPv4 addresses for peers.
+///
+/// Set in [`Config::address_pool`] to assign the peer its address.
+pub trait AddressPool {
+    /// Allocate an address for the peer. None if the pool is exhausted.
+    fn allocate(&self) -> Option<Ipv4Addr>;
+
+    /// Return an address previously allocated, once IPv4CP is down.
+    fn release(&self, address: Ipv4Addr);
+}
+
+impl core::fmt::Debug for dyn AddressPool + '_ {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("AddressPool")
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for dyn AddressPool + '_ {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "AddressPool")
+    }
+}
+
+/// A packet compression algorithm, negotiated with the Compression Control Protocol (CCP),
+/// RFC 1962.
+///
+/// Set in [`Config::compressors`] to offer it to the peer. Once CCP is up, the packets of
+/// network-layer protocols are compressed with the algorithm the peer asked for, and
+/// decompressed with the one it agreed to.
+///
+/// The same instance compresses what we send and decompresses what we receive, it must keep
+/// separate state for each direction. Like [`AddressPool`], methods take `&self`: keep the
+/// state in a `RefCell` or similar.
+pub trait Compressor {
+    /// CCP option type of the algorithm, e.g. 1 for Predictor type 1.
+    fn option_type(&self) -> u8;
+
+    /// Write the data of the option we request, with the parameters for decompressing the
+    /// packets the peer sends, to `data`. Returns its length.
+    ///
+    /// The default implementation writes none.
+    fn rx_option(&self, data: &mut [u8]) -> usize {
+        let _ = data;
+        0
+    }
+
+    /// The peer sent a Configure-Nak for our option, suggesting `data` instead. Returns false
+    /// to stop requesting the algorithm.
+    ///
+    /// The default implementation stops.
+    fn rx_option_nacked(&self, data: &[u8]) -> bool {
+        let _ = data;
+        false
+    }
+
+    /// Check the option the peer requested, with the parameters for compressing the packets
+    /// we send.
+    ///
+    /// To suggest other parameters, write them to `nak` and return [`OptionVerdict::Nak`].
+    fn tx_option(&self, data: &[u8], nak: &mut [u8]) -> OptionVerdict;
+
+    /// Start compressing with the parameters last accepted by
+    /// [`tx_option()`](Self::tx_option), with an empty history.
+    ///
+    /// Called when CCP goes up, and when the peer sends a Reset-Request because it couldn't
+    /// decompress a packet.
+    fn reset_tx(&self);
+
+    /// Start decompressing with the parameters last requested by
+    /// [`rx_option()`](Self::rx_option), with an empty history.
+    ///
+    /// Called when CCP goes up, and when the peer acknowledges our Reset-Request.
+    fn reset_rx(&self);
+
+    /// Compress a packet of `protocol`, writing the data of the Compressed Datagram to `out`.
+    /// Returns its length, or None to send the packet uncompressed.
+    fn compress(&self, protocol: u16, data: &[u8], out: &mut [u8]) -> Option<usize>;
+
+    /// Decompress the data of a Compressed Datagram, writing the packet to `out`. Returns its
+    /// protocol and length.
+    ///
+    /// Returns None if it's corrupted or a packet was lost: a Reset-Request is sent to the peer,
+    /// and Compressed Datagrams are dropped until it acknowledges it.
+    fn decompress(&self, data: &[u8], out: &mut [u8]) -> Option<(u16, usize)>;
+
+    /// A packet of `protocol` was received uncompressed while CCP is up. Algorithms whose
+    /// compressor keeps history for packets it couldn't compress add it to their history.
+    ///
+    /// The default implementation does nothing.
+    fn incompressible(&self, protocol: u16, data: &[u8]) {
+        let _ = (protocol, data);
+    }
+}
+
+impl core::fmt::Debug for dyn Compressor + '_ {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Compressor({})", self.option_type())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for dyn Compressor + '_ {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Compressor({=u8})", self.option_type())
+    }
+}
+
+/// Response to a CCP option the peer requested, see [`Compressor::tx_option()`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OptionVerdict {
+    /// Accept the parameters.
+    Ack,
+    /// Suggest the first `n` bytes written to `nak` instead.
+    Nak(usize),
+    /// The parameters can't be used, and there's nothing to suggest.
+    Reject,
+}
+
+/// Authentication protocol.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AuthProtocol {
+    /// Password Authentication Protocol, RFC 1334.
+    PAP,
+    /// Challenge Handshake Authentication Protocol with MD5, RFC 1994.
+    CHAP,
+}
 
 /// PPP configuration.
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Config<'a> {
-    /// Username for PAP.
+    /// Username for PAP and CHAP.
+    ///
+    /// CHAP authentication fails if it doesn't fit in a Response, i.e. if it's longer than
+    /// 105 bytes, or 72 with MS-CHAPv2. As the CHAP authenticator, only its first 105 bytes
+    /// are sent as our name.
     pub username: &'a [u8],
-    /// Password for PAP.
+    /// Password for PAP, or secret for CHAP.
     pub password: &'a [u8],
+    /// Authentication types we accept to authenticate ourselves with, most preferred first.
+    ///
+    /// If the peer asks for one that's not in the list, it's Nak'd with the first one, or
+    /// rejected if the list only has [`AuthType::None`]. The link is closed if the peer
+    /// doesn't ask for authentication and [`AuthType::None`] is not in the list.
+    pub auth_types: &'a [AuthType],
+    /// If set, the peer must authenticate with [`peer_auth_protocol`](Self::peer_auth_protocol),
+    /// and its credentials are checked with it.
+    pub authenticator: Option<&'a dyn Authenticator>,
+    /// Protocol the peer must authenticate with, if [`authenticator`](Self::authenticator) is set.
+    pub peer_auth_protocol: AuthProtocol,
+    /// Interval between CHAP re-challenges once the peer has authenticated. None disables them.
+    pub rechallenge_interval: Option<Duration>,
+
+    /// Maximum-Receive-Unit: largest packet we can receive, excluding PPP framing.
+    ///
+    /// Requested from the peer if it's not the default of 1500. Received frames larger than this
+    /// are dropped.
+    pub mru: u16,
+
+    /// Whether to negotiate IPv6 with IPv6CP (RFC 5072). If false, the peer's IPv6CP packets
+    /// are Protocol-Rejected.
+    pub ipv6: bool,
+
+    /// Network-layer protocol numbers, besides IPv4 and IPv6, whose packets are passed to the
+    /// application in [`PPPoSAction::Received`](crate::pppos::PPPoSAction::Received).
+    ///
+    /// Packets of other protocols not handled by this crate are Protocol-Rejected.
+    pub extra_protocols: &'a [u16],
+
+    /// IPv4 address to request for ourselves. None lets the peer assign one.
+    pub ipv4_address: Option<Ipv4Addr>,
+    /// Whether to use the address the peer suggests in a Configure-Nak instead of
+    /// [`ipv4_address`](Self::ipv4_address), like pppd's `ipcp-accept-local`.
+    ///
+    /// If false, Naks are ignored and the configured address keeps being requested, until
+    /// Max-Failure turns them into a Reject.
+    pub ipv4_accept_local: bool,
+    /// Whether to ask the peer for NBNS (WINS) servers, reported in
+    /// [`Ipv4Status::nbns_servers`]. Few peers provide them, so it's off by default to save
+    /// a Configure-Reject round trip.
+    pub request_nbns_servers: bool,
+    /// IPv4 address the peer must use. If it proposes another one, it's Nak'd with this one.
+    /// None accepts any address, or assigns one from [`address_pool`](Self::address_pool).
+    pub ipv4_peer_address: Option<Ipv4Addr>,
+    /// If set, the peer's IPv4 address is allocated from it, and the peer is Nak'd into using it.
+    pub address_pool: Option<&'a dyn AddressPool>,
+    /// DNS servers to provide to the peer when it asks for them, like pppd's `ms-dns`.
+    pub peer_dns_servers: [Option<Ipv4Addr>; 2],
+    /// NBNS (WINS) servers to provide to the peer when it asks for them, like pppd's `ms-wins`.
+    pub peer_nbns_servers: [Option<Ipv4Addr>; 2],
+    /// Number of Van Jacobson TCP/IP header compression slots (RFC 1144), i.e. TCP connections
+    /// whose headers can be compressed at once, in each direction. 0 disables compression.
+    /// At most 16.
+    ///
+    /// Received headers are decompressed in place in `rx_buf`, so it needs up to 120 bytes of
+    /// room after the largest frame.
+    pub vj_slots: u8,
+    /// Whether to negotiate IP header compression (RFC 2507) with IPv4CP and IPv6CP, for the
+    /// TCP, UDP and other headers of IPv4 and IPv6 packets. For IPv4, it's preferred over
+    /// [`vj_slots`](Self::vj_slots) if the peer supports it.
+    ///
+    /// The number of contexts is set at compile time with one of the `iphc-contexts-*`
+    /// features, this has no effect without one. Like with VJ, `rx_buf` needs up to 120 bytes
+    /// of room after the largest frame.
+    pub iphc: bool,
+    /// Packet compression algorithms to negotiate with CCP, most preferred first. At most 8
+    /// are used.
+    ///
+    /// CCP is only negotiated if there's at least one, otherwise the peer's CCP packets are
+    /// Protocol-Rejected. Received packets are decompressed into `rx_buf` after the frame, so
+    /// it needs room for another `mru` bytes. Likewise, sent packets are compressed into the
+    /// end of `tx_buf`, before being framed at its start.
+    pub compressors: &'a [&'a dyn Compressor],
+
+    /// Restart timer for LCP and NCP Configure-Request and Terminate-Request retransmissions.
+    pub restart_timeout: Duration,
+    /// Retransmission timeout for PAP Authenticate-Request.
+    ///
+    /// When authenticating with CHAP or EAP, the link fails with [`LinkFailure::NegotiationFailed`]
+    /// if the authenticator sends nothing for [`max_configure`](Self::max_configure) times this.
+    pub auth_restart_timeout: Duration,
+
+    /// Max-Configure: number of Configure-Requests (and PAP Authenticate-Requests) sent
+    /// without reply before giving up.
+    pub max_configure: u8,
+    /// Max-Terminate: number of Terminate-Requests sent without reply before assuming
+    /// the peer is unable to respond.
+    pub max_terminate: u8,
+    /// Max-Failure: number of Configure-Naks sent or received without an Ack before
+    /// assuming negotiation is not converging. Further Naks are turned into Rejects.
+    pub max_failure: u8,
+
+    /// Interval between LCP Echo-Requests sent to check the peer is alive. None disables them.
+    pub echo_interval: Option<Duration>,
+    /// Number of unanswered LCP Echo-Requests after which the peer is considered dead
+    /// and the link is closed. 0 means never.
+    pub echo_failure: u8,
+
+    /// Seed for the random number generator, used for the LCP Magic-Number and the IPv6
+    /// Interface-Identifier.
+    ///
+    /// It must be different on every device, and should be different on every boot, e.g. taken
+    /// from a hardware RNG. Two peers with the same seed pick the same Magic-Numbers, so their
+    /// link is taken as looped back.
+    pub random_seed: u64,
+}
+
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        Self {
+            username: b"",
+            password: b"",
+            auth_types: &[
+                AuthType::PAP,
+                AuthType::CHAP,
+                AuthType::MSCHAPv2,
+                AuthType::EAP,
+                AuthType::None,
+            ],
+            authenticator: None,
+            peer_auth_protocol: AuthProtocol::PAP,
+            rechallenge_interval: None,
+            mru: DEFAULT_MRU,
+            ipv6: true,
+            extra_protocols: &[],
+            ipv4_address: None,
+            ipv4_accept_local: true,
+            request_nbns_servers: false,
+            ipv4_peer_address: None,
+            address_pool: None,
+            peer_dns_servers: [None; 2],
+            peer_nbns_servers: [None; 2],
+            vj_slots: 0,
+            iphc: false,
+            compressors: &[],
+            restart_timeout: Duration::from_secs(3),
+            auth_restart_timeout: Duration::from_secs(3),
+            max_configure: 10,
+            max_terminate: 2,
+            max_failure: 5,
+            echo_interval: None,
+            echo_failure: 4,
+            random_seed: 0,
+        }
+    }
 }
 
 /// Phase of the PPP connection.
@@ -27,6 +362,8 @@ pub struct Config<'a> {
 pub enum Phase {
     /// Dead, not connected.
     Dead,
+    /// Terminating the connection, waiting for the peer to acknowledge.
+    Terminate,
     /// Establishing initial connection.
     Establish,
     /// Authenticating with e.g. PAP.
@@ -37,6 +374,89 @@ pub enum Phase {
     Open,
 }
 
+/// Reason the PPP connection went to phase [`Dead`](Phase::Dead) on its own.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkFailure {
+    /// Negotiation of a protocol did not complete: the peer stopped replying
+    /// after Max-Configure requests.
+    NegotiationFailed {
+        /// PPP protocol number of the protocol that failed, e.g. `0xc021` for LCP.
+        protocol: u16,
+    },
+    /// The lower layer went down, e.g. the modem lost carrier.
+    CarrierLost,
+    /// The peer stopped replying to LCP Echo-Requests.
+    EchoTimeout,
+    /// The link is looped back: we received our own LCP Configure-Requests.
+    LoopedBack,
+    /// The peer failed to authenticate with us: it refused to, or its credentials were wrong.
+    PeerAuthFailed,
+    /// The peer didn't agree to any authentication type in [`Config::auth_types`].
+    AuthNotAcceptable,
+    /// The peer refused our credentials.
+    AuthFailed {
+        /// PPP protocol number of the authentication protocol, e.g. `0xc223` for CHAP.
+        protocol: u16,
+        /// Error code from the MS-CHAPv2 Failure message, e.g. 691 for bad credentials.
+        /// None for other protocols.
+        code: Option<u32>,
+        /// Message from the PAP Authenticate-Nak or CHAP Failure, e.g. `Login incorrect`.
+        /// Empty for other protocols.
+        message: AuthMessage,
+    },
+}
+
+/// Message text sent by the peer along with an authentication result.
+///
+/// Truncated to [`AuthMessage::MAX_LEN`] bytes. It's usually ASCII, but no encoding is guaranteed.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct AuthMessage {
+    buf: [u8; AuthMessage::MAX_LEN],
+    len: u8,
+}
+
+impl AuthMessage {
+    /// Maximum length of the message kept.
+    pub const MAX_LEN: usize = 64;
+
+    pub(crate) const EMPTY: AuthMessage = AuthMessage {
+        buf: [0; AuthMessage::MAX_LEN],
+        len: 0,
+    };
+
+    pub(crate) fn new(msg: &[u8]) -> Self {
+        let len = msg.len().min(Self::MAX_LEN);
+        let mut buf = [0; Self::MAX_LEN];
+        buf[..len].copy_from_slice(&msg[..len]);
+        Self {
+            buf,
+            len: len as u8,
+        }
+    }
+
+    /// The message bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+impl core::fmt::Debug for AuthMessage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match core::str::from_utf8(self.as_bytes()) {
+            Ok(s) => s.fmt(f),
+            Err(_) => self.as_bytes().fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AuthMessage {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{=[u8]:a}", self.as_bytes())
+    }
+}
+
 /// Status of the PPP connection.
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -45,14 +465,46 @@ pub struct Status {
     pub phase: Phase,
     /// IPv4 configuration obtained from IPv4CP. None if IPv4CP is not up.
     pub ipv4: Option<Ipv4Status>,
+    /// IPv6 configuration obtained from IPv6CP. None if IPv6CP is not up.
+    pub ipv6: Option<Ipv6Status>,
+    /// Largest IP packet that can be sent to the peer, i.e. the peer's Maximum-Receive-Unit.
+    /// 1500 unless LCP negotiated otherwise.
+    pub mtu: u16,
+    /// Authentication type negotiated to authenticate ourselves to the peer.
+    /// [`AuthType::None`] if LCP is not up.
+    pub auth: AuthType,
+    /// Round-trip time measured with the last LCP Echo-Reply. None if no reply has been
+    /// received since LCP went up, or if [`Config::echo_interval`] is None.
+    pub echo_rtt: Option<Duration>,
+    /// Why the connection failed. None if it hasn't failed since the last [`open()`](crate::pppos::PPPoS::open).
+    pub failure: Option<LinkFailure>,
 }
 
 pub(crate) struct PPP<'a> {
     phase: Phase,
     opening: bool,
-    pub(crate) lcp: OptionFsm<LCP>,
+    closing: bool,
+    /// Whether the lower layer (the serial link) can carry packets.
+    lower_up: bool,
+    failure: Option<LinkFailure>,
+    pub(crate) lcp: OptionFsm<LCP<'a>>,
+    echo: Echo,
     pub(crate) pap: PAP<'a>,
-    pub(crate) ipv4cp: OptionFsm<IPv4CP>,
+    pap_server: PAPServer<'a>,
+    chap: CHAP<'a>,
+    chap_server: CHAPServer<'a>,
+    eap: EAP<'a>,
+    pub(crate) ipv4cp: OptionFsm<IPv4CP<'a>>,
+    ipv6cp: OptionFsm<IPv6CP>,
+    ccp: OptionFsm<CCP<'a>>,
+    /// VJ header compression state, used while IPv4CP is up.
+    pub(crate) vj_tx: vj::Compressor,
+    pub(crate) vj_rx: vj::Decompressor,
+    /// IPHC header compression state, shared by IPv4CP and IPv6CP.
+    pub(crate) iphc_tx: iphc::Compressor,
+    pub(crate) iphc_rx: iphc::Decompressor,
+    /// Random numbers for authentication challenges.
+    rand: Rand,
 }
 
 impl<'a> PPP<'a> {
@@ -60,9 +512,25 @@ impl<'a> PPP<'a> {
         Self {
             phase: Phase::Dead,
             opening: false,
-            lcp: OptionFsm::new(LCP::new()),
-            pap: PAP::new(config.username, config.password),
-            ipv4cp: OptionFsm::new(IPv4CP::new()),
+            closing: false,
+            lower_up: true,
+            failure: None,
+            lcp: OptionFsm::new(LCP::new(&config), &config),
+            echo: Echo::new(&config),
+            pap: PAP::new(&config),
+            pap_server: PAPServer::new(&config),
+            chap: CHAP::new(&config),
+            chap_server: CHAPServer::new(&config),
+            eap: EAP::new(&config),
+            ipv4cp: OptionFsm::new(IPv4CP::new(&config), &config),
+            ipv6cp: OptionFsm::new(IPv6CP::new(&config), &config),
+            ccp: OptionFsm::new(CCP::new(&config), &config),
+            vj_tx: vj::Compressor::new(),
+            vj_rx: vj::Decompressor::new(),
+            iphc_tx: iphc::Compressor::new(),
+            iphc_rx: iphc::Decompressor::new(),
+            // Use a different sequence than LCP's Magic-Numbers.
+            rand: Rand::new(!config.random_seed),
         }
     }
 
@@ -74,88 +542,700 @@ impl<'a> PPP<'a> {
             } else {
                 None
             },
+            ipv6: if self.ipv6cp.state() == State::Opened {
+                Some(self.ipv6cp.proto().status())
+            } else {
+                None
+            },
+            mtu: self.lcp.proto().peer_mru,
+            auth: if self.lcp.state() == State::Opened {
+                self.lcp.proto().auth
+            } else {
+                AuthType::None
+            },
+            echo_rtt: self.echo.rtt(),
+            failure: self.failure,
         }
     }
 
     pub fn open(&mut self) -> Result<(), crate::InvalidStateError> {
         match self.phase {
             Phase::Dead => {
-                self.phase = Phase::Establish;
+                self.set_phase(Phase::Establish);
                 self.opening = true;
+                self.failure = None;
                 Ok(())
             }
             _ => Err(crate::InvalidStateError),
         }
     }
 
-    pub fn received(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
-        let proto = u16::from_be_bytes(pkt[0..2].try_into().unwrap());
+    pub fn close(&mut self, reason: &[u8]) -> Result<(), crate::InvalidStateError> {
+        match self.phase {
+            Phase::Dead => Err(crate::InvalidStateError),
+            _ => {
+                self.lcp.set_terminate_reason(reason);
+                self.opening = false;
+                self.closing = true;
+                Ok(())
+            }
+        }
+    }
 
-        match proto.into() {
-            ProtocolType::LCP => self.lcp.handle(pkt, &mut tx),
-            ProtocolType::PAP => self.pap.handle(pkt, &mut tx),
-            ProtocolType::IPv4 => todo!(),
-            ProtocolType::IPv4CP => self.ipv4cp.handle(pkt, &mut tx),
-            ProtocolType::Unknown => tx(self.lcp.send_protocol_reject(pkt)),
+    /// Down event for LCP: the lower layer can no longer carry packets.
+    pub fn lower_down(&mut self) {
+        if !self.lower_up {
+            return;
+        }
+        self.lower_up = false;
+        if self.phase == Phase::Dead {
+            return;
+        }
+
+        warn!("PPP: lower layer down");
+        if self.failure.is_none() {
+            self.failure = Some(LinkFailure::CarrierLost);
+        }
+
+        // There's no lower layer to send anything on, so tear down everything without
+        // telling the peer.
+        self.lcp.down();
+        self.echo.stop();
+        self.pap.close();
+        self.pap_server.close();
+        self.chap_server.close();
+        self.chap.close();
+        self.eap.close();
+        self.reset_ncps();
+
+        match self.lcp.state() {
+            // Still administratively open, renegotiate when the lower layer comes back up.
+            State::Starting => self.set_phase(Phase::Establish),
+            _ => {
+                self.lcp.close(Instant::ZERO, |_| {});
+                self.set_phase(Phase::Dead);
+            }
         }
     }
 
-    pub fn poll(&mut self, mut tx: impl FnMut(Packet<'_>)) {
-        // TODO this state machine can probably be written in nicer way.
-        // TODO this is probably not rfc compliant, check what other impls do
-        let old_phase = self.phase;
-        match self.phase {
-            Phase::Dead => {}
-            Phase::Establish => {
-                if self.lcp.state() == State::Closed {
-                    tx(self.lcp.open());
-                    self.opening = false;
-                }
+    /// Up event for LCP: the lower layer is ready to carry packets.
+    pub fn lower_up(&mut self) {
+        if !self.lower_up {
+            info!("PPP: lower layer up");
+            self.lower_up = true;
+        }
+    }
 
-                if self.lcp.state() == State::Opened {
-                    match self.lcp.proto().auth {
-                        AuthType::None => {
-                            tx(self.ipv4cp.open());
-                            self.phase = Phase::Network;
+    /// When [`poll()`](Self::poll) must be called next to handle timeouts.
+    pub fn poll_at(&self) -> Option<Instant> {
+        [
+            self.lcp.poll_at(),
+            self.echo.poll_at(),
+            self.pap.poll_at(),
+            self.pap_server.poll_at(),
+            self.chap.poll_at(),
+            self.eap.poll_at(),
+            self.chap_server.poll_at(),
+            self.ipv4cp.poll_at(),
+            self.ipv6cp.poll_at(),
+            self.ccp.poll_at(),
+            self.ccp.proto().poll_at(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    pub fn received(&mut self, pkt: &mut [u8], now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        // Control protocols are never compressed, so they always have a 2-byte protocol field.
+        let Some((proto, proto_len)) = parse_protocol(pkt) else {
+            return;
+        };
+
+        match proto.into() {
+            ProtocolType::LCP => {
+                if pkt.len() >= 8
+                    && Code::from(pkt[2]) == Code::ProtocolRej
+                    && self.lcp.state() == State::Opened
+                {
+                    let rejected = u16::from_be_bytes(pkt[6..8].try_into().unwrap());
+                    self.protocol_rejected(rejected.into(), now, &mut tx);
+                }
+                if pkt.len() >= 6 && self.lcp.state() == State::Opened {
+                    let magic = self.lcp.proto().magic();
+                    match Code::from(pkt[2]) {
+                        Code::EchoReq => {
+                            if let Some(reply) = self.echo.received_request(pkt, magic) {
+                                tx(reply);
+                            }
+                            return;
                         }
-                        AuthType::PAP => {
-                            tx(self.pap.open());
-                            self.phase = Phase::Auth;
+                        Code::EchoReply => {
+                            self.echo.received_reply(pkt, magic, now);
+                            return;
                         }
+                        _ => {}
                     }
-                } else {
-                    if self.pap.state() != PAPState::Closed {
-                        self.pap.close();
-                    }
-                    if self.ipv4cp.state() != State::Closed {
-                        self.ipv4cp.close();
+                }
+                let ev = self.lcp.handle(pkt, now, &mut tx);
+                self.lcp_event(ev, now, &mut tx);
+                if self.lcp.proto_mut().take_looped_back() {
+                    warn!("PPP: link is looped back, closing");
+                    if self.failure.is_none() {
+                        self.failure = Some(LinkFailure::LoopedBack);
                     }
+                    self.lcp.set_terminate_reason(b"loopback detected");
+                    self.close_lcp(now, &mut tx);
+                }
+            }
+            ProtocolType::PAP => {
+                // Authenticate-Requests are for the authenticator, the rest for the authenticatee.
+                if pkt.len() >= 3 && Code::from(pkt[2]) == Code::ConfigureReq {
+                    self.pap_server.handle(pkt, &mut tx);
+                } else {
+                    self.pap.handle(pkt);
                 }
+                self.check_auth(now, &mut tx);
             }
-            Phase::Auth => {
-                if self.pap.state() == PAPState::Opened {
-                    self.phase = Phase::Network;
-                    tx(self.ipv4cp.open());
+            ProtocolType::CHAP => {
+                // Responses are for the authenticator, the rest for the authenticatee.
+                if pkt.len() >= 3 && Code::from(pkt[2]) == Code::ConfigureAck {
+                    self.chap_server.handle(pkt, now, &mut tx);
                 } else {
-                    if self.ipv4cp.state() != State::Closed {
-                        self.ipv4cp.close();
+                    self.chap.handle(pkt, now, &mut self.rand, &mut tx);
+                }
+                self.check_auth(now, &mut tx);
+            }
+            ProtocolType::EAP => {
+                self.eap.handle(pkt, now, &mut tx);
+                self.check_auth(now, &mut tx);
+            }
+            // Network-layer packets are returned to the user by PPPoS, they never get here.
+            ProtocolType::IPv4 | ProtocolType::IPv6 => {
+                warn!("PPP: dropping unexpected {:?} packet", proto);
+            }
+            ProtocolType::IPv4CP => {
+                let ev = self.ipv4cp.handle(pkt, now, &mut tx);
+                self.ipv4cp_event(ev, now, &mut tx);
+            }
+            ProtocolType::IPv6CP if self.ipv6cp.proto().enabled() => {
+                let ev = self.ipv6cp.handle(pkt, now, &mut tx);
+                self.ipv6cp_event(ev, now, &mut tx);
+            }
+            ProtocolType::CCP if self.ccp.proto().enabled() => {
+                if pkt.len() >= 6 && self.ccp.state() == State::Opened {
+                    let id = pkt[3];
+                    match Code::from(pkt[2]) {
+                        Code::ResetReq => {
+                            tx(self.ccp.proto_mut().received_reset_request(id));
+                            return;
+                        }
+                        Code::ResetAck => {
+                            self.ccp.proto_mut().received_reset_ack(id);
+                            return;
+                        }
+                        _ => {}
+                    }
+                }
+                let ev = self.ccp.handle(pkt, now, &mut tx);
+                self.ccp_event(ev);
+            }
+            // Handled by PPPoS once CCP is up, and dropped while waiting for a Reset-Ack.
+            ProtocolType::CompressedDatagram if self.ccp.proto().enabled() => {
+                debug!("PPP: dropping compressed datagram");
+            }
+            // Only handled by PPPoS once negotiated.
+            ProtocolType::VJCompressedTCP
+            | ProtocolType::VJUncompressedTCP
+            | ProtocolType::IPHCFullHeader
+            | ProtocolType::IPHCCompressedTCP
+            | ProtocolType::IPHCCompressedTCPNoDelta
+            | ProtocolType::IPHCCompressedNonTCP
+            | ProtocolType::IPHCContextState
+            | ProtocolType::IPv6CP
+            | ProtocolType::CCP
+            | ProtocolType::CompressedDatagram
+            | ProtocolType::Unknown => {
+                if self.lcp.state() == State::Opened {
+                    tx(self.lcp.send_protocol_reject(proto, &pkt[proto_len..]))
+                }
+            }
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        if self.opening {
+            self.opening = false;
+            let ev = self.lcp.open(now, &mut tx);
+            self.lcp_event(ev, now, &mut tx);
+        }
+        if self.closing {
+            self.closing = false;
+            info!("PPP: closing link");
+            self.close_lcp(now, &mut tx);
+        }
+        if self.lower_up && self.lcp.state() == State::Starting {
+            let ev = self.lcp.up(now, &mut tx);
+            self.lcp_event(ev, now, &mut tx);
+        }
+
+        let ev = self.lcp.poll(now, &mut tx);
+        self.lcp_event(ev, now, &mut tx);
+
+        if self.echo.poll(now, self.lcp.proto().magic(), &mut tx) {
+            if self.failure.is_none() {
+                self.failure = Some(LinkFailure::EchoTimeout);
+            }
+            self.lcp.set_terminate_reason(b"peer not responding");
+            self.close_lcp(now, &mut tx);
+        }
+
+        self.pap.poll(now, &mut tx);
+        self.pap_server.poll(now);
+        self.chap.poll(now);
+        self.eap.poll(now);
+        self.chap_server.poll(now, &mut self.rand, &mut tx);
+        self.check_auth(now, &mut tx);
+
+        let ev = self.ipv4cp.poll(now, &mut tx);
+        self.ipv4cp_event(ev, now, &mut tx);
+        let ev = self.ipv6cp.poll(now, &mut tx);
+        self.ipv6cp_event(ev, now, &mut tx);
+        let ev = self.ccp.poll(now, &mut tx);
+        self.ccp_event(ev);
+        self.ccp.proto_mut().poll(now, &mut tx);
+    }
+
+    /// Compressor for a packet of `protocol` we send. None if it must be sent uncompressed.
+    pub fn ccp_tx(&self, protocol: u16) -> Option<&'a dyn Compressor> {
+        if self.ccp.state() != State::Opened || !ccp::compressible(protocol) {
+            return None;
+        }
+        self.ccp.proto().tx()
+    }
+
+    /// Decompressor for the compressed datagrams we receive. None if they must be dropped.
+    pub fn ccp_rx(&self) -> Option<&'a dyn Compressor> {
+        if self.ccp.state() != State::Opened {
+            return None;
+        }
+        self.ccp.proto().rx()
+    }
+
+    /// A packet of `protocol` was received uncompressed.
+    pub fn ccp_incompressible(&self, protocol: u16, data: &[u8]) {
+        if let Some(c) = self.ccp_rx().filter(|_| ccp::compressible(protocol)) {
+            c.incompressible(protocol, data);
+        }
+    }
+
+    /// A compressed datagram couldn't be decompressed, ask the peer to reset.
+    pub fn ccp_decompress_failed(&mut self) {
+        warn!("PPP: CCP decompression failed, resetting");
+        self.ccp.proto_mut().decompress_failed();
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        if self.phase != phase {
+            info!("PPP link phase {:?} -> {:?}", self.phase, phase);
+            self.phase = phase;
+        }
+    }
+
+    fn lcp_event(&mut self, ev: Option<LayerEvent>, now: Instant, tx: &mut impl FnMut(Packet<'_>)) {
+        match ev {
+            None => {}
+            // If the lower layer is down, LCP stays in Starting until lower_up().
+            Some(LayerEvent::Started) => {
+                if self.lower_up {
+                    self.lcp.up(now, &mut *tx);
+                }
+            }
+            Some(LayerEvent::Up) => {
+                self.echo.start(now);
+                if self.lcp.proto_mut().take_authenticate_peer_rej() {
+                    self.peer_auth_failed(b"authentication required", now, tx);
+                    return;
+                }
+                if !self.lcp.proto().auth_acceptable() {
+                    warn!(
+                        "PPP: peer didn't agree to an acceptable authentication type, closing link"
+                    );
+                    if self.failure.is_none() {
+                        self.failure = Some(LinkFailure::AuthNotAcceptable);
                     }
+                    self.lcp.set_terminate_reason(b"authentication required");
+                    self.close_lcp(now, tx);
+                    return;
+                }
+
+                // Authenticate ourselves.
+                match self.lcp.proto().auth {
+                    AuthType::None => {}
+                    AuthType::PAP => tx(self.pap.open(now)),
+                    AuthType::CHAP => self.chap.open(CHAPAlgorithm::MD5, now),
+                    AuthType::MSCHAPv2 => self.chap.open(CHAPAlgorithm::MSCHAPv2, now),
+                    AuthType::EAP => self.eap.open(now),
+                }
+                // Authenticate the peer.
+                match self.lcp.proto().authenticate_peer() {
+                    None => {}
+                    Some(AuthProtocol::PAP) => self.pap_server.open(now),
+                    Some(AuthProtocol::CHAP) => tx(self.chap_server.open(now, &mut self.rand)),
+                }
+
+                if self.lcp.proto().auth == AuthType::None
+                    && self.lcp.proto().authenticate_peer().is_none()
+                {
+                    self.start_network(now, tx);
+                } else {
+                    self.set_phase(Phase::Auth);
+                }
+            }
+            Some(LayerEvent::Down) => {
+                self.echo.stop();
+                self.pap.close();
+                self.pap_server.close();
+                self.chap_server.close();
+                self.chap.close();
+                self.eap.close();
+                self.reset_ncps();
+                match self.lcp.state() {
+                    State::Closing | State::Stopping => self.set_phase(Phase::Terminate),
+                    _ => self.set_phase(Phase::Establish),
                 }
             }
-            Phase::Network => {
-                if self.ipv4cp.state() == State::Opened {
-                    self.phase = Phase::Open;
+            Some(LayerEvent::Finished) => {
+                if self.lcp.take_failed() && self.failure.is_none() {
+                    self.failure = Some(LinkFailure::NegotiationFailed {
+                        protocol: ProtocolType::LCP.into(),
+                    });
                 }
+                self.echo.stop();
+                self.pap.close();
+                self.pap_server.close();
+                self.chap_server.close();
+                self.chap.close();
+                self.eap.close();
+                self.reset_ncps();
+
+                // The link is dead, return LCP to the Initial state so it can be opened again.
+                self.lcp.close(now, |_| {});
+                self.lcp.down();
+                self.set_phase(Phase::Dead);
             }
-            Phase::Open => {}
         }
+    }
 
-        if self.lcp.state() == State::Closed && !self.opening {
-            self.phase = Phase::Dead
+    fn ipv4cp_event(
+        &mut self,
+        ev: Option<LayerEvent>,
+        now: Instant,
+        tx: &mut impl FnMut(Packet<'_>),
+    ) {
+        match ev {
+            None | Some(LayerEvent::Started) => {}
+            Some(LayerEvent::Up) => {
+                let ipv4cp = self.ipv4cp.proto();
+                let (tx_slots, compress_slot_id) = ipv4cp.vj_tx_params();
+                self.vj_tx.reset(tx_slots, compress_slot_id);
+                self.vj_rx.reset(ipv4cp.vj_rx_slots());
+                self.iphc_tx.set_ipv4(ipv4cp.iphc_tx_params());
+                self.iphc_rx.set_ipv4(ipv4cp.iphc_rx_enabled());
+                self.set_phase(Phase::Open);
+            }
+            Some(LayerEvent::Down) => {
+                self.ipv4cp.proto_mut().release_peer_address();
+                self.stop_ipv4_compression();
+                self.ncp_down();
+            }
+            Some(LayerEvent::Finished) => {
+                self.ipv4cp.proto_mut().release_peer_address();
+                self.stop_ipv4_compression();
+                let failed = self.ipv4cp.take_failed();
+                self.ncp_finished(ProtocolType::IPv4CP, failed, now, tx);
+            }
         }
+    }
+
+    fn ipv6cp_event(
+        &mut self,
+        ev: Option<LayerEvent>,
+        now: Instant,
+        tx: &mut impl FnMut(Packet<'_>),
+    ) {
+        match ev {
+            None | Some(LayerEvent::Started) => {}
+            // Both ends would have the same link-local address.
+            Some(LayerEvent::Up) if self.ipv6cp.proto().duplicate_iid() => {
+                warn!("PPP: peer has the same IPv6 Interface-Identifier, closing IPv6CP");
+                self.ipv6cp
+                    .set_terminate_reason(b"duplicate Interface-Identifier");
+                let ev = self.ipv6cp.close(now, &mut *tx);
+                self.ipv6cp_event(ev, now, tx);
+            }
+            Some(LayerEvent::Up) => {
+                let ipv6cp = self.ipv6cp.proto();
+                self.iphc_tx.set_ipv6(ipv6cp.iphc_tx_params());
+                self.iphc_rx.set_ipv6(ipv6cp.iphc_rx_enabled());
+                self.set_phase(Phase::Open);
+            }
+            Some(LayerEvent::Down) => {
+                self.stop_ipv6_compression();
+                self.ncp_down();
+            }
+            Some(LayerEvent::Finished) => {
+                self.stop_ipv6_compression();
+                let failed = self.ipv6cp.take_failed() || self.ipv6cp.proto().duplicate_iid();
+                self.ncp_finished(ProtocolType::IPv6CP, failed, now, tx);
+            }
+        }
+    }
+
+    fn ccp_event(&mut self, ev: Option<LayerEvent>) {
+        match ev {
+            None | Some(LayerEvent::Started) => {}
+            Some(LayerEvent::Up) => self.ccp.proto_mut().up(),
+            Some(LayerEvent::Down) => self.ccp.proto_mut().down(),
+            // Packets are just sent uncompressed, the link stays up.
+            Some(LayerEvent::Finished) => {
+                if self.ccp.take_failed() {
+                    warn!("PPP: CCP negotiation failed, not compressing");
+                }
+                self.ccp.proto_mut().down();
+            }
+        }
+    }
+
+    fn stop_ipv4_compression(&mut self) {
+        self.vj_tx.reset(0, false);
+        self.vj_rx.reset(0);
+        self.iphc_tx.set_ipv4(None);
+        self.iphc_rx.set_ipv4(false);
+    }
+
+    fn stop_ipv6_compression(&mut self) {
+        self.iphc_tx.set_ipv6(None);
+        self.iphc_rx.set_ipv6(false);
+    }
+
+    /// Whether any network protocol is open or still negotiating.
+    fn ncp_running(&self) -> bool {
+        let running = |state| !matches!(state, State::Initial | State::Closed | State::Stopped);
+        running(self.ipv4cp.state()) || running(self.ipv6cp.state())
+    }
+
+    /// A network protocol went down: leave the Open phase if it was the last one up.
+    fn ncp_down(&mut self) {
+        if self.phase == Phase::Open
+            && self.ipv4cp.state() != State::Opened
+            && self.ipv6cp.state() != State::Opened
+        {
+            self.set_phase(Phase::Network);
+        }
+    }
+
+    /// A network protocol finished: close the link if there's nothing left to carry.
+    fn ncp_finished(
+        &mut self,
+        protocol: ProtocolType,
+        failed: bool,
+        now: Instant,
+        tx: &mut impl FnMut(Packet<'_>),
+    ) {
+        if self.ncp_running() {
+            debug!(
+                "PPP: {:?} finished, other network protocols still running",
+                protocol
+            );
+            return;
+        }
+        if failed {
+            self.fail(protocol, now, tx);
+        } else {
+            self.lcp
+                .set_terminate_reason(b"no network protocols running");
+            self.close_lcp(now, tx);
+        }
+    }
+
+    /// Move to the Network phase once authentication succeeds in both directions, or close
+    /// the link if it failed.
+    ///
+    /// CHAP can also fail after the Auth phase, on a re-challenge.
+    fn check_auth(&mut self, now: Instant, tx: &mut impl FnMut(Packet<'_>)) {
+        let ours_done = match self.lcp.proto().auth {
+            AuthType::None => true,
+            AuthType::PAP => match self.pap.state() {
+                PAPState::Opened => true,
+                PAPState::Failed => return self.fail(ProtocolType::PAP, now, tx),
+                PAPState::Refused => {
+                    let message = self.pap.message();
+                    return self.auth_failed(ProtocolType::PAP, None, message, now, tx);
+                }
+                _ => false,
+            },
+            AuthType::CHAP | AuthType::MSCHAPv2 => match self.chap.state() {
+                CHAPState::Opened => true,
+                CHAPState::Failed => {
+                    let code = self.chap.error_code();
+                    let message = self.chap.message();
+                    return self.auth_failed(ProtocolType::CHAP, code, message, now, tx);
+                }
+                CHAPState::TimedOut => return self.fail(ProtocolType::CHAP, now, tx),
+                _ => false,
+            },
+            AuthType::EAP => match self.eap.state() {
+                EAPState::Opened => true,
+                EAPState::Failed => {
+                    return self.auth_failed(ProtocolType::EAP, None, AuthMessage::EMPTY, now, tx)
+                }
+                EAPState::TimedOut => return self.fail(ProtocolType::EAP, now, tx),
+                _ => false,
+            },
+        };
+
+        let peer_done = match self.lcp.proto().authenticate_peer() {
+            None => true,
+            Some(AuthProtocol::PAP) => match self.pap_server.state() {
+                PAPServerState::Opened => true,
+                PAPServerState::Failed => {
+                    return self.peer_auth_failed(b"authentication failed", now, tx)
+                }
+                _ => false,
+            },
+            Some(AuthProtocol::CHAP) => match self.chap_server.state() {
+                CHAPServerState::Opened => true,
+                CHAPServerState::Failed => {
+                    return self.peer_auth_failed(b"authentication failed", now, tx)
+                }
+                _ => false,
+            },
+        };
+
+        if ours_done && peer_done && self.phase == Phase::Auth {
+            self.start_network(now, tx);
+        }
+    }
+
+    fn start_network(&mut self, now: Instant, tx: &mut impl FnMut(Packet<'_>)) {
+        self.set_phase(Phase::Network);
+        let ev = self.ipv4cp.open(now, &mut *tx);
+        self.ipv4cp_event(ev, now, tx);
+        let ev = self.ipv4cp.up(now, &mut *tx);
+        self.ipv4cp_event(ev, now, tx);
+        if self.ipv6cp.proto().enabled() {
+            let ev = self.ipv6cp.open(now, &mut *tx);
+            self.ipv6cp_event(ev, now, tx);
+            let ev = self.ipv6cp.up(now, &mut *tx);
+            self.ipv6cp_event(ev, now, tx);
+        }
+        if self.ccp.proto().enabled() {
+            let ev = self.ccp.open(now, &mut *tx);
+            self.ccp_event(ev);
+            let ev = self.ccp.up(now, &mut *tx);
+            self.ccp_event(ev);
+        }
+    }
+
+    /// Return all network protocols and CCP to the Initial state, after LCP went down.
+    fn reset_ncps(&mut self) {
+        if self.ipv4cp.state() != State::Initial {
+            self.ipv4cp.down();
+            self.ipv4cp.close(Instant::ZERO, |_| {});
+            self.ipv4cp.proto_mut().release_peer_address();
+            self.stop_ipv4_compression();
+        }
+        if self.ipv6cp.state() != State::Initial {
+            self.ipv6cp.down();
+            self.ipv6cp.close(Instant::ZERO, |_| {});
+            self.stop_ipv6_compression();
+        }
+        if self.ccp.state() != State::Initial {
+            self.ccp.down();
+            self.ccp.close(Instant::ZERO, |_| {});
+            self.ccp.proto_mut().down();
+        }
+    }
+
+    /// Handle a Protocol-Reject received from the peer.
+    fn protocol_rejected(
+        &mut self,
+        protocol: ProtocolType,
+        now: Instant,
+        tx: &mut impl FnMut(Packet<'_>),
+    ) {
+        match protocol {
+            ProtocolType::IPv4CP => {
+                let ev = self.ipv4cp.protocol_rejected(now, &mut *tx);
+                self.ipv4cp_event(ev, now, tx);
+            }
+            ProtocolType::IPv6CP => {
+                let ev = self.ipv6cp.protocol_rejected(now, &mut *tx);
+                self.ipv6cp_event(ev, now, tx);
+            }
+            ProtocolType::CCP => {
+                let ev = self.ccp.protocol_rejected(now, &mut *tx);
+                self.ccp_event(ev);
+            }
+            _ => debug!("PPP: peer rejected protocol {:?}", protocol),
+        }
+    }
+
+    fn fail(&mut self, protocol: ProtocolType, now: Instant, tx: &mut impl FnMut(Packet<'_>)) {
+        if self.failure.is_none() {
+            warn!("PPP: {:?} negotiation failed, closing link", protocol);
+            self.failure = Some(LinkFailure::NegotiationFailed {
+                protocol: protocol.into(),
+            });
+        }
+        self.lcp.set_terminate_reason(b"negotiation failed");
+        self.close_lcp(now, tx);
+    }
+
+    /// The peer refused our credentials: close the link.
+    fn auth_failed(
+        &mut self,
+        protocol: ProtocolType,
+        code: Option<u32>,
+        message: AuthMessage,
+        now: Instant,
+        tx: &mut impl FnMut(Packet<'_>),
+    ) {
+        if self.failure.is_none() {
+            warn!(
+                "PPP: {:?} authentication failed: {:?}, closing link",
+                protocol, message
+            );
+            self.failure = Some(LinkFailure::AuthFailed {
+                protocol: protocol.into(),
+                code,
+                message,
+            });
+        }
+        self.lcp.set_terminate_reason(b"authentication failed");
+        self.close_lcp(now, tx);
+    }
+
+    /// The peer failed to authenticate with us: close the link.
+    fn peer_auth_failed(&mut self, reason: &[u8], now: Instant, tx: &mut impl FnMut(Packet<'_>)) {
+        if self.failure.is_none() {
+            warn!("PPP: peer failed to authenticate, closing link");
+            self.failure = Some(LinkFailure::PeerAuthFailed);
+        }
+        self.lcp.set_terminate_reason(reason);
+        self.close_lcp(now, tx);
+    }
 
-        if old_phase != self.phase {
-            info!("PPP link phase {:?} -> {:?}", old_phase, self.phase);
+    /// Send the Close event to LCP, with the reason previously set in it.
+    fn close_lcp(&mut self, now: Instant, tx: &mut impl FnMut(Packet<'_>)) {
+        let ev = self.lcp.close(now, &mut *tx);
+        self.lcp_event(ev, now, tx);
+        match self.lcp.state() {
+            State::Closing => self.set_phase(Phase::Terminate),
+            // Closed before the open was even processed.
+            State::Initial => self.set_phase(Phase::Dead),
+            _ => {}
         }
     }
 }
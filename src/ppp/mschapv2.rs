@@ -0,0 +1,151 @@
+//! MS-CHAPv2 computations, RFC 2759 section 8.
+
+use crate::crypto::{des_encrypt, Md4, Sha1};
+
+const MAGIC1: &[u8; 39] = b"Magic server to client signing constant";
+const MAGIC2: &[u8; 41] = b"Pad to make it do more than one iteration";
+
+/// Compute the NT-Response sent in the Response packet.
+pub fn generate_nt_response(
+    auth_challenge: &[u8; 16],
+    peer_challenge: &[u8; 16],
+    username: &[u8],
+    password: &[u8],
+) -> [u8; 24] {
+    let challenge = challenge_hash(peer_challenge, auth_challenge, username);
+    challenge_response(&challenge, &nt_password_hash(password))
+}
+
+/// Compute the authenticator response the peer must send in the Success packet, as the
+/// 20 bytes encoded in the `S=` field.
+pub fn generate_authenticator_response(
+    password: &[u8],
+    nt_response: &[u8; 24],
+    peer_challenge: &[u8; 16],
+    auth_challenge: &[u8; 16],
+    username: &[u8],
+) -> [u8; 20] {
+    let mut md4 = Md4::new();
+    md4.update(&nt_password_hash(password));
+    let password_hash_hash = md4.finalize();
+
+    let mut sha = Sha1::new();
+    sha.update(&password_hash_hash);
+    sha.update(nt_response);
+    sha.update(MAGIC1);
+    let digest = sha.finalize();
+
+    let challenge = challenge_hash(peer_challenge, auth_challenge, username);
+    let mut sha = Sha1::new();
+    sha.update(&digest);
+    sha.update(&challenge);
+    sha.update(MAGIC2);
+    sha.finalize()
+}
+
+fn challenge_hash(
+    peer_challenge: &[u8; 16],
+    auth_challenge: &[u8; 16],
+    username: &[u8],
+) -> [u8; 8] {
+    // Only the user name is hashed, without any "DOMAIN\" prefix.
+    let username = match username.iter().rposition(|&b| b == b'\\') {
+        Some(i) => &username[i + 1..],
+        None => username,
+    };
+
+    let mut sha = Sha1::new();
+    sha.update(peer_challenge);
+    sha.update(auth_challenge);
+    sha.update(username);
+    let digest = sha.finalize();
+    unwrap!(digest[..8].try_into())
+}
+
+/// MD4 of the password in UTF-16LE.
+///
+/// The password is taken as UTF-8, or as Latin-1 if it's not valid UTF-8.
+fn nt_password_hash(password: &[u8]) -> [u8; 16] {
+    let mut md4 = Md4::new();
+    match core::str::from_utf8(password) {
+        Ok(password) => {
+            for c in password.encode_utf16() {
+                md4.update(&c.to_le_bytes());
+            }
+        }
+        Err(_) => {
+            for &b in password {
+                md4.update(&[b, 0]);
+            }
+        }
+    }
+    md4.finalize()
+}
+
+fn challenge_response(challenge: &[u8; 8], password_hash: &[u8; 16]) -> [u8; 24] {
+    let mut key = [0; 21];
+    key[..16].copy_from_slice(password_hash);
+
+    let mut response = [0; 24];
+    for (resp, key) in response.chunks_exact_mut(8).zip(key.chunks_exact(7)) {
+        resp.copy_from_slice(&des_encrypt(unwrap!(key.try_into()), challenge));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hex;
+
+    // RFC 2759 section 9.2.
+    const USERNAME: &[u8] = b"User";
+    const PASSWORD: &[u8] = b"clientPass";
+    const AUTH_CHALLENGE: &str = "5b5d7c7d7b3f2f3e3c2c602132262628";
+    const PEER_CHALLENGE: &str = "21402324255e262a28295f2b3a337c7e";
+    const NT_RESPONSE: &str = "82309ecd8d708b5ea08faa3981cd83544233114a3d85d6df";
+
+    #[test]
+    fn intermediate_values() {
+        assert_eq!(
+            challenge_hash(&hex(PEER_CHALLENGE), &hex(AUTH_CHALLENGE), USERNAME),
+            hex("d02e4386bce91226")
+        );
+        assert_eq!(
+            nt_password_hash(PASSWORD),
+            hex("44ebba8d5312b8d611474411f56989ae")
+        );
+    }
+
+    #[test]
+    fn nt_response() {
+        let resp = generate_nt_response(
+            &hex(AUTH_CHALLENGE),
+            &hex(PEER_CHALLENGE),
+            USERNAME,
+            PASSWORD,
+        );
+        assert_eq!(resp, hex(NT_RESPONSE));
+
+        // The domain isn't part of the hash.
+        let resp = generate_nt_response(
+            &hex(AUTH_CHALLENGE),
+            &hex(PEER_CHALLENGE),
+            b"DOMAIN\\User",
+            PASSWORD,
+        );
+        assert_eq!(resp, hex(NT_RESPONSE));
+    }
+
+    #[test]
+    fn authenticator_response() {
+        let resp = generate_authenticator_response(
+            PASSWORD,
+            &hex(NT_RESPONSE),
+            &hex(PEER_CHALLENGE),
+            &hex(AUTH_CHALLENGE),
+            USERNAME,
+        );
+        assert_eq!(resp, hex("407a5589115fd0d6209f510fe9c04566932cda56"));
+    }
+}
@@ -1,6 +1,13 @@
 use heapless::Vec;
 
-use crate::wire::{Code, OptionVal, Options, PPPPayload, Packet, Payload, ProtocolType};
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{
+    Code, Options, PPPPayload, Packet, Payload, ProtocolType, HEADER_LEN, MAX_PACKET_LEN,
+};
+
+/// Maximum length of the reason sent in Terminate-Requests. Longer reasons are truncated.
+pub(crate) const MAX_TERMINATE_REASON_LEN: usize = 32;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -13,35 +20,94 @@ pub(crate) enum Verdict<'a> {
 pub(crate) trait Protocol {
     fn protocol(&self) -> ProtocolType;
 
+    /// Negotiation starts afresh: request our options again, including the ones the peer
+    /// rejected in the previous negotiation.
+    fn own_options_start(&mut self);
     fn own_options(&mut self, f: impl FnMut(u8, &[u8]));
-    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool);
+    /// The peer sent a Configure-Nak or Configure-Reject for one of our options.
+    ///
+    /// `is_rej` means we must stop requesting it: the peer rejected it, or kept Nak'ing it
+    /// past Max-Failure. `peer_rej` is only set for an actual Configure-Reject.
+    fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool, peer_rej: bool);
+    /// The peer sent a Configure-Ack for our last request, with this option.
+    fn own_option_acked(&mut self, _code: u8, _data: &[u8]) {}
 
     fn peer_options_start(&mut self);
     fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict;
 }
 
+/// Automaton states, RFC 1661 section 4.2. See `states.txt` for the full transition table.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub(crate) enum State {
+    Initial,
+    Starting,
     Closed,
+    Stopped,
+    Closing,
+    Stopping,
     ReqSent,
     AckReceived,
     AckSent,
     Opened,
 }
 
+/// Layer actions (tlu, tld, tls, tlf) the owner of the automaton must react to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum LayerEvent {
+    /// This-Layer-Up: the automaton entered the Opened state.
+    Up,
+    /// This-Layer-Down: the automaton left the Opened state.
+    Down,
+    /// This-Layer-Started: the lower layer is needed.
+    Started,
+    /// This-Layer-Finished: the lower layer is no longer needed.
+    Finished,
+}
+
 pub(crate) struct OptionFsm<P> {
     id: u8,
     state: State,
     proto: P,
+
+    restart_timeout: Duration,
+    /// Deadline of the Restart timer. None if the timer is stopped.
+    timer: Option<Instant>,
+
+    max_configure: u8,
+    max_terminate: u8,
+    max_failure: u8,
+    /// Requests left to send before giving up.
+    restart_count: u8,
+    /// Configure-Naks sent without sending a Configure-Ack.
+    failure_count: u8,
+    /// Configure-Naks received without receiving a Configure-Ack.
+    nak_count: u8,
+    /// Set when negotiation gave up (This-Layer-Finished due to timeout or rejection).
+    failed: bool,
+    /// Data sent in Terminate-Requests, kept around for retransmissions.
+    terminate_reason: Vec<u8, MAX_TERMINATE_REASON_LEN>,
 }
 
 impl<P: Protocol> OptionFsm<P> {
-    pub fn new(proto: P) -> Self {
+    pub fn new(proto: P, config: &Config) -> Self {
         Self {
             id: 1,
-            state: State::Closed,
+            state: State::Initial,
             proto,
+
+            restart_timeout: config.restart_timeout,
+            timer: None,
+
+            max_configure: config.max_configure,
+            max_terminate: config.max_terminate,
+            max_failure: config.max_failure,
+            restart_count: 0,
+            failure_count: 0,
+            nak_count: 0,
+            failed: false,
+            terminate_reason: Vec::new(),
         }
     }
 
@@ -53,116 +119,313 @@ impl<P: Protocol> OptionFsm<P> {
         &self.proto
     }
 
-    pub fn _proto_mut(&mut self) -> &mut P {
+    pub fn proto_mut(&mut self) -> &mut P {
         &mut self.proto
     }
 
-    pub fn open(&mut self) -> Packet<'_> {
-        assert!(self.state == State::Closed);
-        self.state = State::ReqSent;
-        self.send_configure_request()
+    /// Whether negotiation gave up because the peer stopped responding.
+    ///
+    /// Clears the flag, so each failure is reported once.
+    pub fn take_failed(&mut self) -> bool {
+        core::mem::take(&mut self.failed)
+    }
+
+    /// When the Restart timer expires, if it's running.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Up event: the lower layer is ready to carry packets.
+    pub fn up(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        let old_state = self.state;
+        match self.state {
+            State::Initial => self.state = State::Closed,
+            State::Starting => {
+                self.start_negotiation();
+                tx(self.send_configure_request(now));
+                self.state = State::ReqSent;
+            }
+            x => debug!("{:?}: ignoring Up in state {:?}", self.proto.protocol(), x),
+        }
+        self.log_state_change(old_state);
+        None
+    }
+
+    /// Down event: the lower layer can no longer carry packets.
+    pub fn down(&mut self) -> Option<LayerEvent> {
+        let old_state = self.state;
+        self.timer = None;
+        let ev = match self.state {
+            State::Closed | State::Closing => {
+                self.state = State::Initial;
+                None
+            }
+            State::Stopped => {
+                self.state = State::Starting;
+                Some(LayerEvent::Started)
+            }
+            State::Stopping | State::ReqSent | State::AckReceived | State::AckSent => {
+                self.state = State::Starting;
+                None
+            }
+            State::Opened => {
+                self.state = State::Starting;
+                Some(LayerEvent::Down)
+            }
+            State::Initial | State::Starting => {
+                debug!(
+                    "{:?}: ignoring Down in state {:?}",
+                    self.proto.protocol(),
+                    self.state
+                );
+                None
+            }
+        };
+        self.log_state_change(old_state);
+        ev
+    }
+
+    /// Open event: administratively allow the link to be opened.
+    pub fn open(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        let old_state = self.state;
+        let ev = match self.state {
+            State::Initial => {
+                self.state = State::Starting;
+                Some(LayerEvent::Started)
+            }
+            State::Closed => {
+                self.start_negotiation();
+                tx(self.send_configure_request(now));
+                self.state = State::ReqSent;
+                None
+            }
+            State::Closing => {
+                self.state = State::Stopping;
+                None
+            }
+            _ => None,
+        };
+        self.log_state_change(old_state);
+        ev
+    }
+
+    /// Set the data sent in Terminate-Requests from the next [`close()`](Self::close) on.
+    pub fn set_terminate_reason(&mut self, reason: &[u8]) {
+        let len = reason.len().min(MAX_TERMINATE_REASON_LEN);
+        self.terminate_reason = unwrap!(Vec::from_slice(&reason[..len]));
+    }
+
+    /// Close event: administratively close the link.
+    pub fn close(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        let old_state = self.state;
+        let ev = match self.state {
+            State::Starting => {
+                self.state = State::Initial;
+                Some(LayerEvent::Finished)
+            }
+            State::Stopped => {
+                self.state = State::Closed;
+                None
+            }
+            State::Stopping => {
+                self.state = State::Closing;
+                None
+            }
+            State::ReqSent | State::AckReceived | State::AckSent => {
+                self.init_restart_count(self.max_terminate);
+                tx(self.send_terminate_request(now));
+                self.state = State::Closing;
+                None
+            }
+            State::Opened => {
+                self.init_restart_count(self.max_terminate);
+                tx(self.send_terminate_request(now));
+                self.state = State::Closing;
+                Some(LayerEvent::Down)
+            }
+            State::Initial | State::Closed | State::Closing => None,
+        };
+        self.log_state_change(old_state);
+        ev
+    }
+
+    /// RXJ- event: the peer rejected this protocol with a Protocol-Reject.
+    pub fn protocol_rejected(
+        &mut self,
+        now: Instant,
+        tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        warn!("{:?}: rejected by peer", self.proto.protocol());
+        let old_state = self.state;
+        let ev = self.received_fatal_reject(now, tx);
+        self.log_state_change(old_state);
+        ev
     }
 
-    pub fn close(&mut self) {
-        self.state = State::Closed;
+    /// Handle the Restart timer expiring (TO+ and TO- events).
+    pub fn poll(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        match self.timer {
+            Some(deadline) if deadline <= now => {}
+            _ => return None,
+        }
+        self.timer = None;
+
+        debug!(
+            "{:?}: restart timer expired in state {:?}",
+            self.proto.protocol(),
+            self.state
+        );
+        let old_state = self.state;
+        let ev = if self.restart_count > 0 {
+            // TO+
+            match self.state {
+                State::Closing | State::Stopping => tx(self.send_terminate_request(now)),
+                State::ReqSent | State::AckReceived => {
+                    tx(self.send_configure_request(now));
+                    self.state = State::ReqSent;
+                }
+                State::AckSent => tx(self.send_configure_request(now)),
+                _ => {}
+            }
+            None
+        } else {
+            // TO-
+            match self.state {
+                State::Closing => {
+                    self.state = State::Closed;
+                    Some(LayerEvent::Finished)
+                }
+                State::Stopping => {
+                    self.state = State::Stopped;
+                    Some(LayerEvent::Finished)
+                }
+                State::ReqSent | State::AckReceived | State::AckSent => {
+                    warn!(
+                        "{:?}: no reply after {:?} Configure-Requests, giving up",
+                        self.proto.protocol(),
+                        self.max_configure
+                    );
+                    self.failed = true;
+                    self.state = State::Stopped;
+                    Some(LayerEvent::Finished)
+                }
+                _ => None,
+            }
+        };
+
+        self.log_state_change(old_state);
+        ev
     }
 
-    pub fn handle(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
+    pub fn handle(
+        &mut self,
+        pkt: &mut [u8],
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
         if pkt.len() < 6 {
             warn!("PPP packet too short");
-            return;
+            return None;
         }
         let code = Code::from(pkt[2]);
         let id = pkt[3];
         let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
-        if len + 2 > pkt.len() {
+        if len < 4 || len + 2 > pkt.len() {
             warn!("PPP packet len too short");
-            return;
+            return None;
         }
         let pkt = &mut pkt[..len + 2];
 
         debug!("{:?}: rx {:?}", self.proto.protocol(), code);
+
+        if matches!(self.state, State::Initial | State::Starting) {
+            debug!(
+                "{:?}: ignoring {:?}, lower layer is down",
+                self.proto.protocol(),
+                code
+            );
+            return None;
+        }
+
         let old_state = self.state;
-        match (code, self.state) {
-            // reply EchoReq on state Opened, ignore in all other states (including Closed!)
-            (Code::EchoReq, State::Opened) => tx(self.send_echo_response(pkt)),
-            (Code::EchoReq, x) => {
-                debug!("ignoring unexpected EchoReq in state {:?}", x)
-            }
-
-            // DiscardReqs are, well, discarded.
-            (Code::DiscardReq, _) => {}
-
-            // in state Closed, reply to any packet with TerminateAck (except to EchoReq!)
-            (_, State::Closed) => tx(self.send_terminate_ack(id)),
-
-            (Code::ConfigureReq, _) => {
-                let resp = self.received_configure_req(pkt);
-                let acked = matches!(resp.payload, Payload::PPP(Code::ConfigureAck, _, _));
-                tx(resp);
-
-                match (acked, self.state) {
-                    (_, State::Closed) => unreachable!(),
-                    (true, State::ReqSent) => self.state = State::AckSent,
-                    (true, State::AckReceived) => self.state = State::Opened,
-                    (true, State::AckSent) => self.state = State::AckSent,
-                    (true, State::Opened) => {
-                        tx(self.send_configure_request());
-                        self.state = State::AckSent;
-                    }
-                    (false, State::AckSent) => self.state = State::ReqSent,
-                    (false, State::Opened) => {
-                        tx(self.send_configure_request());
+        let is_lcp = self.proto.protocol() == ProtocolType::LCP;
+        let ev = match code {
+            Code::ConfigureReq => self.received_configure_req(pkt, now, &mut tx),
+            Code::ConfigureAck if id == self.id => self.received_configure_ack(pkt, now, &mut tx),
+            Code::ConfigureNack | Code::ConfigureRej if id == self.id => {
+                self.received_configure_nak(pkt, now, &mut tx)
+            }
+            Code::ConfigureAck | Code::ConfigureNack | Code::ConfigureRej => {
+                debug!(
+                    "{:?}: ignoring {:?} with mismatched id",
+                    self.proto.protocol(),
+                    code
+                );
+                None
+            }
+            Code::TerminateReq => self.received_terminate_req(id, now, &mut tx),
+            Code::TerminateAck => self.received_terminate_ack(now, &mut tx),
+            Code::CodeRej => {
+                // Rejecting any of the codes needed for negotiation is catastrophic (RXJ-),
+                // anything else is permitted (RXJ+).
+                let rejected = Code::from(*pkt.get(6).unwrap_or(&0));
+                if matches!(
+                    rejected,
+                    Code::ConfigureReq
+                        | Code::ConfigureAck
+                        | Code::ConfigureNack
+                        | Code::ConfigureRej
+                        | Code::TerminateReq
+                        | Code::TerminateAck
+                        | Code::CodeRej
+                ) {
+                    warn!(
+                        "{:?}: peer rejected code {:?}",
+                        self.proto.protocol(),
+                        rejected
+                    );
+                    self.received_fatal_reject(now, &mut tx)
+                } else {
+                    if self.state == State::AckReceived {
                         self.state = State::ReqSent;
                     }
-                    (false, _) => {}
+                    None
                 }
             }
-
-            (Code::ConfigureAck, State::ReqSent) => self.state = State::AckReceived,
-            (Code::ConfigureAck, State::AckSent) => self.state = State::Opened,
-            (Code::ConfigureAck, State::AckReceived) | (Code::ConfigureAck, State::Opened) => {
-                self.state = State::ReqSent;
-                tx(self.send_configure_request())
-            }
-
-            (Code::ConfigureNack, _) | (Code::ConfigureRej, _) => {
-                let is_rej = code == Code::ConfigureRej;
-
-                if pkt.len() < 6 {
-                    panic!("too short")
+            // Protocol-Reject is forwarded to the rejected protocol by the caller. For LCP itself
+            // it is RXJ+.
+            Code::ProtocolRej if is_lcp => {
+                if self.state == State::AckReceived {
+                    self.state = State::ReqSent;
                 }
-                let pkt = &pkt[6..]; // skip header
-
-                parse_options(pkt, |code, data| {
-                    self.proto.own_option_nacked(code, data, is_rej)
-                })
-                .unwrap();
-
-                match self.state {
-                    State::Closed => unreachable!(),
-                    State::AckSent => {}
-                    _ => self.state = State::ReqSent,
-                }
-                tx(self.send_configure_request())
+                None
             }
-            (Code::TerminateReq, State::Opened) => {
-                self.state = State::Closed;
-                tx(self.send_terminate_ack(id))
+            // EchoReqs are answered by the caller in state Opened, since it knows the
+            // Magic-Number. Ignore them in all other states (including Closed!)
+            Code::EchoReq if is_lcp => {
+                debug!("ignoring unexpected EchoReq in state {:?}", self.state);
+                None
             }
-            (Code::TerminateReq, State::ReqSent)
-            | (Code::TerminateReq, State::AckReceived)
-            | (Code::TerminateReq, State::AckSent) => {
-                self.state = State::ReqSent;
-                tx(self.send_terminate_ack(id))
+            // EchoReplies and DiscardReqs are, well, discarded.
+            Code::EchoReply | Code::DiscardReq if is_lcp => None,
+            // Likewise, CCP Reset-Requests and Reset-Acks are handled by the caller in state
+            // Opened, and meaningless in the others.
+            Code::ResetReq | Code::ResetAck if self.proto.protocol() == ProtocolType::CCP => {
+                debug!("ignoring unexpected {:?} in state {:?}", code, self.state);
+                None
+            }
+            _ => {
+                // RUC
+                tx(self.send_code_reject(pkt));
+                None
             }
-
-            x => debug!(
-                "ignoring unexpected packet {:?} in state {:?}",
-                x, self.state
-            ),
         };
 
+        self.log_state_change(old_state);
+        ev
+    }
+
+    fn log_state_change(&self, old_state: State) {
         if old_state != self.state {
             debug!(
                 "{:?}: state {:?} -> {:?}",
@@ -173,34 +436,304 @@ impl<P: Protocol> OptionFsm<P> {
         }
     }
 
+    /// Initialize the counters for a new negotiation (irc before the first scr).
+    fn start_negotiation(&mut self) {
+        self.proto.own_options_start();
+        self.terminate_reason.clear();
+        self.failure_count = 0;
+        self.nak_count = 0;
+        self.init_restart_count(self.max_configure);
+    }
+
+    /// RCR+ and RCR- events.
+    fn received_configure_req(
+        &mut self,
+        pkt: &[u8],
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        match self.state {
+            State::Closed => {
+                tx(self.send_terminate_ack(pkt[3]));
+                return None;
+            }
+            State::Closing | State::Stopping => return None,
+            State::Stopped => {
+                self.start_negotiation();
+                tx(self.send_configure_request(now));
+            }
+            _ => {}
+        }
+
+        let Some(resp) = self.build_configure_resp(pkt) else {
+            warn!(
+                "{:?}: malformed or oversized Configure-Request",
+                self.proto.protocol()
+            );
+            return None;
+        };
+        let good = matches!(resp.payload, Payload::PPP(Code::ConfigureAck, _, _));
+        if good {
+            self.failure_count = 0;
+        } else if matches!(resp.payload, Payload::PPP(Code::ConfigureNack, _, _)) {
+            self.failure_count = self.failure_count.saturating_add(1);
+        }
+
+        let mut ev = None;
+        if self.state == State::Opened {
+            ev = Some(LayerEvent::Down);
+            tx(self.send_configure_request(now));
+        }
+        tx(resp);
+
+        self.state = match (good, self.state) {
+            (true, State::AckReceived) => {
+                self.timer = None;
+                ev = Some(LayerEvent::Up);
+                State::Opened
+            }
+            (true, _) => State::AckSent,
+            (false, State::AckReceived) => State::AckReceived,
+            (false, _) => State::ReqSent,
+        };
+        ev
+    }
+
+    /// RCA event.
+    fn received_configure_ack(
+        &mut self,
+        pkt: &[u8],
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        if matches!(self.state, State::ReqSent | State::AckSent) {
+            let res = parse_options(&pkt[6..], |code, data| {
+                self.proto.own_option_acked(code, data)
+            });
+            if res.is_err() {
+                warn!("{:?}: malformed Configure-Ack", self.proto.protocol());
+            }
+        }
+
+        match self.state {
+            State::Closed | State::Stopped => {
+                tx(self.send_terminate_ack(pkt[3]));
+                None
+            }
+            State::ReqSent => {
+                self.nak_count = 0;
+                self.init_restart_count(self.max_configure);
+                self.state = State::AckReceived;
+                None
+            }
+            State::AckReceived => {
+                tx(self.send_configure_request(now));
+                self.state = State::ReqSent;
+                None
+            }
+            State::AckSent => {
+                self.nak_count = 0;
+                self.init_restart_count(self.max_configure);
+                self.timer = None;
+                self.state = State::Opened;
+                Some(LayerEvent::Up)
+            }
+            State::Opened => {
+                tx(self.send_configure_request(now));
+                self.state = State::ReqSent;
+                Some(LayerEvent::Down)
+            }
+            _ => None,
+        }
+    }
+
+    /// RCN event, for both Configure-Nak and Configure-Reject.
+    fn received_configure_nak(
+        &mut self,
+        pkt: &[u8],
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        match self.state {
+            State::Closed | State::Stopped => {
+                tx(self.send_terminate_ack(pkt[3]));
+                return None;
+            }
+            State::Closing | State::Stopping => return None,
+            _ => {}
+        }
+
+        let peer_rej = Code::from(pkt[2]) == Code::ConfigureRej;
+        let mut is_rej = peer_rej;
+
+        // If the peer keeps Nak'ing the same options, negotiation is not
+        // converging. Stop requesting them, as if they had been rejected.
+        if !is_rej {
+            self.nak_count = self.nak_count.saturating_add(1);
+            if self.nak_count > self.max_failure {
+                warn!(
+                    "{:?}: peer Nak'd {:?} times, treating as Reject",
+                    self.proto.protocol(),
+                    self.nak_count
+                );
+                is_rej = true;
+            }
+        }
+
+        let res = parse_options(&pkt[6..], |code, data| {
+            self.proto.own_option_nacked(code, data, is_rej, peer_rej)
+        });
+        if res.is_err() {
+            warn!("{:?}: malformed Configure-Nak/Rej", self.proto.protocol());
+        }
+
+        match self.state {
+            State::ReqSent | State::AckSent => self.init_restart_count(self.max_configure),
+            State::AckReceived => self.state = State::ReqSent,
+            State::Opened => {
+                tx(self.send_configure_request(now));
+                self.state = State::ReqSent;
+                return Some(LayerEvent::Down);
+            }
+            _ => {}
+        }
+        tx(self.send_configure_request(now));
+        None
+    }
+
+    /// RTR event.
+    fn received_terminate_req(
+        &mut self,
+        id: u8,
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        tx(self.send_terminate_ack(id));
+        match self.state {
+            State::ReqSent | State::AckReceived | State::AckSent => {
+                self.state = State::ReqSent;
+                None
+            }
+            State::Opened => {
+                info!("{:?}: terminated by peer", self.proto.protocol());
+                // zrc: wait one Restart period before finishing, so the Terminate-Ack gets through.
+                self.restart_count = 0;
+                self.timer = Some(now + self.restart_timeout);
+                self.state = State::Stopping;
+                Some(LayerEvent::Down)
+            }
+            _ => None,
+        }
+    }
+
+    /// RTA event.
+    fn received_terminate_ack(
+        &mut self,
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        match self.state {
+            State::Closing => {
+                self.timer = None;
+                self.state = State::Closed;
+                Some(LayerEvent::Finished)
+            }
+            State::Stopping => {
+                self.timer = None;
+                self.state = State::Stopped;
+                Some(LayerEvent::Finished)
+            }
+            State::AckReceived => {
+                self.state = State::ReqSent;
+                None
+            }
+            State::Opened => {
+                tx(self.send_configure_request(now));
+                self.state = State::ReqSent;
+                Some(LayerEvent::Down)
+            }
+            _ => None,
+        }
+    }
+
+    /// RXJ- event.
+    fn received_fatal_reject(
+        &mut self,
+        now: Instant,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        self.failed = true;
+        self.terminate_reason.clear();
+        match self.state {
+            State::Closed | State::Closing => {
+                self.timer = None;
+                self.state = State::Closed;
+                Some(LayerEvent::Finished)
+            }
+            State::Stopped
+            | State::Stopping
+            | State::ReqSent
+            | State::AckReceived
+            | State::AckSent => {
+                self.timer = None;
+                self.state = State::Stopped;
+                Some(LayerEvent::Finished)
+            }
+            State::Opened => {
+                self.init_restart_count(self.max_terminate);
+                tx(self.send_terminate_request(now));
+                self.state = State::Stopping;
+                Some(LayerEvent::Down)
+            }
+            State::Initial | State::Starting => None,
+        }
+    }
+
+    fn init_restart_count(&mut self, max: u8) {
+        self.restart_count = max;
+    }
+
     fn next_id(&mut self) -> u8 {
         self.id = self.id.wrapping_add(1);
         self.id
     }
 
-    fn send_configure_request(&mut self) -> Packet<'static> {
-        let mut opts = Vec::new();
+    fn send_configure_request(&mut self, now: Instant) -> Packet<'static> {
+        self.timer = Some(now + self.restart_timeout);
+        self.restart_count = self.restart_count.saturating_sub(1);
 
+        let mut opts = Options::new();
+        let proto = self.proto.protocol();
         self.proto.own_options(|code, data| {
-            if opts.push(OptionVal::new(code, data)).is_err() {
-                panic!("tx ConfigureReq: too many options")
+            if opts.push(code, data).is_err() {
+                warn!(
+                    "{:?}: option {:?} doesn't fit in Configure-Req",
+                    proto, code
+                );
             }
         });
 
         Packet {
-            proto: self.proto.protocol(),
+            proto,
             payload: Payload::PPP(
                 Code::ConfigureReq,
                 self.next_id(),
-                PPPPayload::Options(Options(opts)),
+                PPPPayload::Options(opts),
             ),
         }
     }
 
-    fn _send_terminate_request<'a>(&mut self, reason: &'a mut [u8]) -> Packet<'a> {
+    fn send_terminate_request(&mut self, now: Instant) -> Packet<'_> {
+        self.timer = Some(now + self.restart_timeout);
+        self.restart_count = self.restart_count.saturating_sub(1);
+
         Packet {
             proto: self.proto.protocol(),
-            payload: Payload::PPP(Code::TerminateReq, self.next_id(), PPPPayload::Raw(reason)),
+            payload: Payload::PPP(
+                Code::TerminateReq,
+                self.next_id(),
+                PPPPayload::Raw(&mut self.terminate_reason),
+            ),
         }
     }
 
@@ -211,48 +744,53 @@ impl<P: Protocol> OptionFsm<P> {
         }
     }
 
-    fn _send_code_reject<'a>(&mut self, pkt: &'a mut [u8]) -> Packet<'a> {
+    fn send_code_reject<'a>(&mut self, pkt: &'a mut [u8]) -> Packet<'a> {
+        // The Rejected-Packet is truncated to fit in the peer's MRU (RFC 1661 section 5.6).
+        let end = pkt.len().min(2 + MAX_PACKET_LEN - HEADER_LEN);
         Packet {
             proto: self.proto.protocol(),
             payload: Payload::PPP(
                 Code::CodeRej,
                 self.next_id(),
-                PPPPayload::Raw(&mut pkt[2..]),
+                PPPPayload::Raw(&mut pkt[2..end]),
             ),
         }
     }
 
-    fn send_echo_response<'a>(&mut self, pkt: &'a mut [u8]) -> Packet<'a> {
-        pkt[2] = Code::EchoReply as u8;
-        Packet {
-            proto: self.proto.protocol(),
-            payload: Payload::Raw(&mut pkt[2..]),
-        }
-    }
-
     // TODO maybe this should be in PPP because it's only for LCP
-    pub fn send_protocol_reject<'a>(&mut self, pkt: &'a mut [u8]) -> Packet<'a> {
+    pub fn send_protocol_reject<'a>(&mut self, proto: u16, info: &'a [u8]) -> Packet<'a> {
+        // Likewise for the Rejected-Information (RFC 1661 section 5.7).
+        let info = &info[..info.len().min(MAX_PACKET_LEN - HEADER_LEN - 2)];
         Packet {
             proto: self.proto.protocol(),
-            payload: Payload::PPP(Code::ProtocolRej, self.next_id(), PPPPayload::Raw(pkt)),
+            payload: Payload::PPP(
+                Code::ProtocolRej,
+                self.next_id(),
+                PPPPayload::ProtocolRej(proto, info),
+            ),
         }
     }
 
-    fn received_configure_req(&mut self, pkt: &[u8]) -> Packet<'static> {
+    /// Build the Configure-Ack, Configure-Nak or Configure-Reject for a received Configure-Request.
+    ///
+    /// Returns None if the request is malformed, or too long to Ack.
+    fn build_configure_resp(&mut self, pkt: &[u8]) -> Option<Packet<'static>> {
         let id = pkt[3];
         let mut code = Code::ConfigureAck;
-
-        if pkt.len() < 6 {
-            panic!("too short");
-        }
         let pkt = &pkt[6..]; // skip header
 
-        let mut opts = Vec::new();
+        let mut opts = Options::new();
+        let mut ack_full = false;
+
+        // After Max-Failure Configure-Naks without an Ack, negotiation is not converging:
+        // convert further Naks to Rejects.
+        let nak_to_rej = self.failure_count >= self.max_failure;
 
         self.proto.peer_options_start();
         parse_options(pkt, |ocode, odata| {
             let (ret_code, data) = match self.proto.peer_option_received(ocode, odata) {
                 Verdict::Ack => (Code::ConfigureAck, odata),
+                Verdict::Nack(_) if nak_to_rej => (Code::ConfigureRej, odata),
                 Verdict::Nack(data) => (Code::ConfigureNack, data),
                 Verdict::Rej => (Code::ConfigureRej, odata),
             };
@@ -262,18 +800,28 @@ impl<P: Protocol> OptionFsm<P> {
                 opts.clear();
             }
 
-            if code == ret_code {
-                if opts.push(OptionVal::new(ocode, data)).is_err() {
-                    panic!("rx ConfigureReq: too many options")
-                }
+            // Naks and Rejects that don't fit are left for the next request. An Ack must list
+            // every option, it can't be sent if they don't fit.
+            if code == ret_code && opts.push(ocode, data).is_err() {
+                debug!(
+                    "{:?}: option {:?} doesn't fit in {:?}",
+                    self.proto.protocol(),
+                    ocode,
+                    code
+                );
+                ack_full |= code == Code::ConfigureAck;
             }
         })
-        .unwrap();
+        .ok()?;
 
-        Packet {
-            proto: self.proto.protocol(),
-            payload: Payload::PPP(code, id, PPPPayload::Options(Options(opts))),
+        if ack_full && code == Code::ConfigureAck {
+            return None;
         }
+
+        Some(Packet {
+            proto: self.proto.protocol(),
+            payload: Payload::PPP(code, id, PPPPayload::Options(opts)),
+        })
     }
 }
 
@@ -304,3 +852,76 @@ fn parse_options(mut pkt: &[u8], mut f: impl FnMut(u8, &[u8])) -> Result<(), Mal
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct MalformedError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Requests nothing, and acks or rejects every option of the peer.
+    struct Dummy {
+        ack: bool,
+    }
+
+    impl Protocol for Dummy {
+        fn protocol(&self) -> ProtocolType {
+            ProtocolType::IPv4CP
+        }
+        fn own_options_start(&mut self) {}
+        fn own_options(&mut self, _f: impl FnMut(u8, &[u8])) {}
+        fn own_option_nacked(&mut self, _code: u8, _data: &[u8], _is_rej: bool, _peer_rej: bool) {}
+        fn peer_options_start(&mut self) {}
+        fn peer_option_received(&mut self, _code: u8, _data: &[u8]) -> Verdict<'_> {
+            if self.ack {
+                Verdict::Ack
+            } else {
+                Verdict::Rej
+            }
+        }
+    }
+
+    /// Send a Configure-Request with options of `lens` bytes of data. Returns the code and
+    /// length of the reply, None if there's none.
+    fn reply_to(ack: bool, lens: &[usize]) -> Option<(Code, usize)> {
+        let mut fsm = OptionFsm::new(Dummy { ack }, &Config::default());
+        fsm.open(Instant::ZERO, |_| {});
+        fsm.up(Instant::ZERO, |_| {});
+
+        let mut pkt = [0; 512];
+        pkt[..4].copy_from_slice(&[0x80, 0x21, Code::ConfigureReq.into(), 7]);
+        let mut len = HEADER_LEN;
+        for (i, &n) in lens.iter().enumerate() {
+            pkt[len] = 200 + i as u8;
+            pkt[len + 1] = 2 + n as u8;
+            len += 2 + n;
+        }
+        pkt[4..6].copy_from_slice(&(len as u16 - 2).to_be_bytes());
+
+        let mut reply = None;
+        fsm.handle(&mut pkt[..len], Instant::ZERO, |p| {
+            assert!(p.buffer_len() <= MAX_PACKET_LEN);
+            if let Payload::PPP(code, 7, _) = p.payload {
+                reply = Some((code, p.buffer_len()));
+            }
+        });
+        reply
+    }
+
+    #[test]
+    fn long_options() {
+        // Rejects that don't fit are left out.
+        assert_eq!(
+            reply_to(false, &[16; 8]),
+            Some((Code::ConfigureRej, HEADER_LEN + 6 * 18))
+        );
+        assert_eq!(
+            reply_to(false, &[40, 200]),
+            Some((Code::ConfigureRej, HEADER_LEN + 42))
+        );
+        assert_eq!(
+            reply_to(true, &[4; 8]),
+            Some((Code::ConfigureAck, HEADER_LEN + 48))
+        );
+        // Can't be acked.
+        assert_eq!(reply_to(true, &[16; 8]), None);
+    }
+}
@@ -1,3 +1,5 @@
+use super::{AuthMessage, Authenticator, Config};
+use crate::time::{Duration, Instant};
 use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -6,6 +8,21 @@ pub enum State {
     Closed,
     ReqSent,
     Opened,
+    /// The peer didn't reply to any of our Authenticate-Requests.
+    Failed,
+    /// The peer refused our credentials with an Authenticate-Nak.
+    Refused,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ServerState {
+    Closed,
+    /// Waiting for the peer's Authenticate-Request.
+    Listen,
+    Opened,
+    /// The peer's credentials were wrong, or it didn't send any in time.
+    Failed,
 }
 pub struct PAP<'a> {
     state: State,
@@ -13,17 +30,32 @@ pub struct PAP<'a> {
 
     username: &'a [u8],
     password: &'a [u8],
+
+    restart_timeout: Duration,
+    /// Deadline for retransmitting the Authenticate-Request. None if not waiting for a reply.
+    timer: Option<Instant>,
+    max_configure: u8,
+    /// Authenticate-Requests left to send before giving up.
+    restart_count: u8,
+    /// Message from the peer's Authenticate-Nak.
+    message: AuthMessage,
 }
 
 impl<'a> PAP<'a> {
-    pub fn new(username: &'a [u8], password: &'a [u8]) -> Self {
-        assert!(username.len() <= u8::MAX as usize);
-        assert!(password.len() <= u8::MAX as usize);
+    pub fn new(config: &Config<'a>) -> Self {
+        assert!(config.username.len() <= u8::MAX as usize);
+        assert!(config.password.len() <= u8::MAX as usize);
         Self {
             state: State::Closed,
             id: 1,
-            username,
-            password,
+            username: config.username,
+            password: config.password,
+
+            restart_timeout: config.auth_restart_timeout,
+            timer: None,
+            max_configure: config.max_configure,
+            restart_count: 0,
+            message: AuthMessage::EMPTY,
         }
     }
 
@@ -31,17 +63,54 @@ impl<'a> PAP<'a> {
         self.state
     }
 
-    pub fn open(&mut self) -> Packet<'_> {
+    /// Message the peer sent in its Authenticate-Nak.
+    pub fn message(&self) -> AuthMessage {
+        self.message
+    }
+
+    pub fn open(&mut self, now: Instant) -> Packet<'_> {
         assert!(self.state == State::Closed);
         self.state = State::ReqSent;
-        self.send_configure_request()
+        self.message = AuthMessage::EMPTY;
+        self.restart_count = self.max_configure;
+        self.send_configure_request(now)
     }
 
     pub fn close(&mut self) {
         self.state = State::Closed;
+        self.timer = None;
     }
 
-    pub fn handle(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
+    /// When the retransmission timer expires, if it's running.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Retransmit the Authenticate-Request if no reply arrived in time.
+    pub fn poll(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        match self.timer {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+
+        match self.state {
+            State::ReqSent if self.restart_count == 0 => {
+                warn!(
+                    "PAP: no reply after {:?} requests, giving up",
+                    self.max_configure
+                );
+                self.state = State::Failed;
+                self.timer = None;
+            }
+            State::ReqSent => {
+                debug!("PAP: timeout, retransmitting");
+                tx(self.send_configure_request(now))
+            }
+            _ => self.timer = None,
+        }
+    }
+
+    pub fn handle(&mut self, pkt: &[u8]) {
         if pkt.len() < 6 {
             warn!("PAP packet too short");
             return;
@@ -49,17 +118,26 @@ impl<'a> PAP<'a> {
         let code = Code::from(pkt[2]);
         let _id = pkt[3];
         let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
-        if len > pkt.len() {
-            warn!("PAP packet len too short");
+        if len < 4 || len + 2 > pkt.len() {
+            warn!("PAP packet len invalid");
             return;
         }
-        let _pkt = &mut pkt[..len + 2];
+        let pkt = &pkt[..len + 2];
 
         debug!("PAP: rx {:?}", code);
         let old_state = self.state;
         match (code, self.state) {
-            (Code::ConfigureAck, State::ReqSent) => self.state = State::Opened,
-            (Code::ConfigureNack, State::ReqSent) => tx(self.send_configure_request()),
+            (Code::ConfigureAck, State::ReqSent) => {
+                self.state = State::Opened;
+                self.timer = None;
+            }
+            // Retrying with the same credentials would fail again.
+            (Code::ConfigureNack, State::ReqSent) => {
+                self.message = AuthMessage::new(parse_message(&pkt[6..]));
+                warn!("PAP: authentication failed: {:?}", self.message);
+                self.state = State::Refused;
+                self.timer = None;
+            }
             _ => {}
         }
 
@@ -73,7 +151,10 @@ impl<'a> PAP<'a> {
         self.id
     }
 
-    fn send_configure_request(&mut self) -> Packet<'a> {
+    fn send_configure_request(&mut self, now: Instant) -> Packet<'a> {
+        self.timer = Some(now + self.restart_timeout);
+        self.restart_count = self.restart_count.saturating_sub(1);
+
         debug!("PAP: tx {:?}", Code::ConfigureReq);
         Packet {
             proto: ProtocolType::PAP,
@@ -85,3 +166,126 @@ impl<'a> PAP<'a> {
         }
     }
 }
+
+/// PAP as the authenticator, checking the peer's credentials with the [`Authenticator`].
+pub struct PAPServer<'a> {
+    state: ServerState,
+    authenticator: Option<&'a dyn Authenticator>,
+
+    /// How long to wait for the peer's Authenticate-Request.
+    timeout: Duration,
+    timer: Option<Instant>,
+}
+
+impl<'a> PAPServer<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        Self {
+            state: ServerState::Closed,
+            authenticator: config.authenticator,
+            // Give the peer as long as we'd keep retrying as the authenticatee.
+            timeout: Duration::from_millis(
+                config.auth_restart_timeout.as_millis() * config.max_configure as u64,
+            ),
+            timer: None,
+        }
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    pub fn open(&mut self, now: Instant) {
+        assert!(self.state == ServerState::Closed);
+        self.state = ServerState::Listen;
+        self.timer = Some(now + self.timeout);
+    }
+
+    pub fn close(&mut self) {
+        self.state = ServerState::Closed;
+        self.timer = None;
+    }
+
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer
+    }
+
+    /// Give up if the peer didn't send its credentials in time.
+    pub fn poll(&mut self, now: Instant) {
+        match self.timer {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        self.timer = None;
+        if self.state == ServerState::Listen {
+            warn!("PAP: peer didn't authenticate in time");
+            self.state = ServerState::Failed;
+        }
+    }
+
+    pub fn handle(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
+        if pkt.len() < 6 {
+            warn!("PAP packet too short");
+            return;
+        }
+        let code = Code::from(pkt[2]);
+        let id = pkt[3];
+        let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
+        if len < 4 || len + 2 > pkt.len() {
+            warn!("PAP packet len invalid");
+            return;
+        }
+        let data = &pkt[6..len + 2];
+
+        // The request may be retransmitted if our Ack got lost, so answer it again when Opened.
+        if code != Code::ConfigureReq
+            || !matches!(self.state, ServerState::Listen | ServerState::Opened)
+        {
+            return;
+        }
+        let Some((username, password)) = parse_request(data) else {
+            warn!("PAP: malformed Authenticate-Request");
+            return;
+        };
+
+        let ok = self
+            .authenticator
+            .is_some_and(|a| a.check_pap(username, password));
+
+        let old_state = self.state;
+        self.timer = None;
+        let (code, msg): (_, &[u8]) = if ok {
+            self.state = ServerState::Opened;
+            (Code::ConfigureAck, b"Login ok")
+        } else {
+            warn!("PAP: peer sent wrong credentials");
+            self.state = ServerState::Failed;
+            (Code::ConfigureNack, b"Login incorrect")
+        };
+        if old_state != self.state {
+            debug!("PAP: server state {:?} -> {:?}", old_state, self.state);
+        }
+
+        debug!("PAP: tx {:?}", code);
+        tx(Packet {
+            proto: ProtocolType::PAP,
+            payload: Payload::PPP(code, id, PPPPayload::PAPMessage(msg)),
+        });
+    }
+}
+
+/// Get the message from an Authenticate-Ack or Authenticate-Nak's data.
+fn parse_message(data: &[u8]) -> &[u8] {
+    match data.split_first() {
+        Some((&msg_len, data)) => &data[..data.len().min(msg_len as usize)],
+        None => &[],
+    }
+}
+
+/// Parse the Peer-ID and Password from an Authenticate-Request.
+fn parse_request(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&user_len, data) = data.split_first()?;
+    let (username, data) = data.split_at_checked(user_len as usize)?;
+    let (&pass_len, data) = data.split_first()?;
+    let password = data.get(..pass_len as usize)?;
+    Some((username, password))
+}
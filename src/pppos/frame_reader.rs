@@ -5,7 +5,6 @@ use super::crc::crc16;
 #[derive(Copy, Clone, Debug)]
 enum State {
     Start,
-    Address,
     Data,
     Complete,
 }
@@ -14,24 +13,47 @@ pub struct FrameReader {
     state: State,
     escape: bool,
     len: usize,
+    /// Max length of a frame in the buffer, from the address field to the FCS.
+    max_len: usize,
+    /// Whether a frame was dropped because of a bad FCS or because it was too large.
+    error: bool,
 }
 
 impl FrameReader {
-    pub fn new() -> Self {
+    pub fn new(mru: u16) -> Self {
         Self {
             state: State::Start,
             escape: false,
             len: 0,
+            // address and control (2) + protocol (2) + information + FCS (2)
+            max_len: mru as usize + 6,
+            error: false,
         }
     }
 
-    pub fn receive(&mut self) -> Option<Range<usize>> {
+    /// Discard any partially received frame.
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+        self.escape = false;
+        self.len = 0;
+    }
+
+    /// Whether a frame was dropped since the last call, clearing the flag.
+    pub fn take_error(&mut self) -> bool {
+        core::mem::replace(&mut self.error, false)
+    }
+
+    /// Get the received frame, from the protocol field to the end of the information field.
+    pub fn receive(&mut self, buf: &[u8]) -> Option<Range<usize>> {
         match self.state {
             State::Complete => {
                 let len = self.len;
                 self.len = 0;
-                self.state = State::Address;
-                Some(1..len - 2)
+                self.state = State::Data;
+
+                // The address and control fields may be omitted with ACFC.
+                let start = if buf[..2] == [0xff, 0x03] { 2 } else { 0 };
+                Some(start..len - 2)
             }
             _ => None,
         }
@@ -40,17 +62,19 @@ impl FrameReader {
     pub fn consume(&mut self, buf: &mut [u8], data: &[u8]) -> usize {
         for (i, &b) in data.iter().enumerate() {
             match (self.state, b) {
-                (State::Start, 0x7e) => self.state = State::Address,
+                (State::Start, 0x7e) => self.state = State::Data,
                 (State::Start, _) => {}
-                (State::Address, 0xff) => self.state = State::Data,
-                (State::Address, 0x7e) => self.state = State::Address,
-                (State::Address, _) => self.state = State::Start,
+                // Consecutive flags, the frame hasn't started yet.
+                (State::Data, 0x7e) if self.len == 0 => self.escape = false,
                 (State::Data, 0x7e) => {
                     // End of packet
-                    let ok = self.len >= 3
-                        && buf[0] == 0x03
-                        && crc16(0x00FF, &buf[..self.len]) == 0xf0b8;
-                    self.state = if ok { State::Complete } else { State::Address }
+                    self.escape = false;
+                    if self.frame_ok(buf) {
+                        self.state = State::Complete
+                    } else {
+                        self.len = 0;
+                        self.error = true;
+                    }
                 }
                 (State::Data, 0x7d) => self.escape = true,
                 (State::Data, mut b) => {
@@ -58,9 +82,11 @@ impl FrameReader {
                         self.escape = false;
                         b ^= 0x20;
                     }
-                    if self.len == usize::MAX || self.len >= buf.len() {
+                    if self.len >= self.max_len || self.len >= buf.len() {
+                        // Too large, drop it.
                         self.state = State::Start;
                         self.len = 0;
+                        self.error = true;
                     } else {
                         buf[self.len as usize] = b;
                         self.len += 1;
@@ -74,4 +100,21 @@ impl FrameReader {
         // All consumed
         data.len()
     }
+
+    fn frame_ok(&self, buf: &[u8]) -> bool {
+        let frame = &buf[..self.len];
+        if crc16(0xFFFF, frame) != 0xf0b8 {
+            return false;
+        }
+
+        // Skip address and control fields, unless compressed.
+        let frame = match frame {
+            [0xff, 0x03, rest @ ..] => rest,
+            [0xff, ..] => return false,
+            _ => frame,
+        };
+
+        // At least a 1-byte protocol field and the FCS.
+        frame.len() >= 3
+    }
 }
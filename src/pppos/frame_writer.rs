@@ -35,10 +35,13 @@ impl<'a> FrameWriter<'a> {
         self.len
     }
 
-    pub fn start(&mut self) -> Result<(), BufferFullError> {
-        self.crc = crc16(0xFFFF, &[0xFF, 0x03]);
-        self.append_raw(&[0x7e, 0xff])?;
-        self.append_escaped(&[0x03])?;
+    /// Start a frame. If `acfc`, the address and control fields are omitted.
+    pub fn start(&mut self, acfc: bool) -> Result<(), BufferFullError> {
+        self.crc = 0xFFFF;
+        self.append_raw(&[0x7e])?;
+        if !acfc {
+            self.append(&[0xff, 0x03])?;
+        }
 
         Ok(())
     }
@@ -1,6 +1,6 @@
 //! PPP over Serial
 
-mod crc;
+pub(crate) mod crc;
 mod frame_reader;
 mod frame_writer;
 
@@ -8,20 +8,38 @@ use core::ops::Range;
 
 use self::frame_reader::FrameReader;
 use self::frame_writer::FrameWriter;
+use crate::compress::{iphc, vj};
 use crate::ppp::PPP;
-use crate::wire::{Packet, ProtocolType};
-use crate::{Config, Status};
+use crate::wire::{parse_protocol, Packet, Payload, ProtocolType, MAX_PACKET_LEN};
+use crate::{Compressor, Config, Instant, Status};
 
 pub use self::frame_writer::BufferFullError;
 
+/// Error returned by [`PPPoS::send()`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SendError {
+    /// The given buffer is too small.
+    BufferFull,
+    /// The packet is larger than the MTU negotiated with the peer, see [`Status::mtu`].
+    PacketTooLarge,
+}
+
+impl From<BufferFullError> for SendError {
+    fn from(_: BufferFullError) -> Self {
+        Self::BufferFull
+    }
+}
+
 /// Return value from [`PPPoS::poll()`].
 pub enum PPPoSAction {
     /// No action needed to take.
     None,
-    /// An IP packet was received.
+    /// A network-layer packet was received: IPv4, IPv6, or one of [`Config::extra_protocols`].
     ///
+    /// The first field is the PPP protocol number, e.g. `0x0021` for IPv4 or `0x0057` for IPv6.
     /// The packet is located in `rx_buf[range]`, you must pass it to higher layers for processing.
-    Received(Range<usize>),
+    Received(u16, Range<usize>),
     /// PPP wants to transmit some data.
     ///
     /// You must transmit `tx_buf[..n]` over the serial connection.
@@ -32,6 +50,9 @@ pub enum PPPoSAction {
 pub struct PPPoS<'a> {
     frame_reader: FrameReader,
     ppp: PPP<'a>,
+    extra_protocols: &'a [u16],
+    /// Time of the last [`poll()`](Self::poll), for IPHC refreshes of compressed streams.
+    now: Instant,
 }
 
 impl<'a> PPPoS<'a> {
@@ -41,8 +62,10 @@ impl<'a> PPPoS<'a> {
     /// call [`open()`](Self::open) to get it to start connecting.
     pub fn new(config: Config<'a>) -> Self {
         Self {
-            frame_reader: FrameReader::new(),
+            frame_reader: FrameReader::new(config.mru),
+            extra_protocols: config.extra_protocols,
             ppp: PPP::new(config),
+            now: Instant::ZERO,
         }
     }
 
@@ -60,37 +83,161 @@ impl<'a> PPPoS<'a> {
         self.ppp.open()
     }
 
+    /// Notify that the lower layer went down, e.g. the modem dropped DCD (carrier detect).
+    ///
+    /// Any partially received frame is discarded and network protocols are taken down
+    /// without notifying the peer. If the connection was open or opening, it goes back to phase
+    /// [`Establish`](crate::Phase::Establish) and renegotiates once [`lower_up()`](Self::lower_up)
+    /// is called. Otherwise, it goes to phase [`Dead`](crate::Phase::Dead).
+    /// [`Status::failure`](crate::Status::failure) reports
+    /// [`CarrierLost`](crate::LinkFailure::CarrierLost).
+    ///
+    /// The lower layer is assumed to be up when the `PPPoS` is created.
+    pub fn lower_down(&mut self) {
+        self.frame_reader.reset();
+        self.ppp.lower_down();
+    }
+
+    /// Notify that the lower layer is up again, e.g. the modem raised DCD (carrier detect).
+    ///
+    /// If the connection is in phase [`Establish`](crate::Phase::Establish), negotiation
+    /// restarts on the next [`poll()`](Self::poll).
+    pub fn lower_up(&mut self) {
+        self.frame_reader.reset();
+        self.ppp.lower_up();
+    }
+
+    /// Get when [`poll()`](Self::poll) must be called next to handle timeouts.
+    ///
+    /// Returns `None` if no timer is running. You must still call [`poll()`](Self::poll)
+    /// whenever new data is consumed.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.ppp.poll_at()
+    }
+
+    /// Start closing the PPPoS connection.
+    ///
+    /// Network protocols are taken down and an LCP Terminate-Request is sent with `reason`
+    /// (if any, truncated to 32 bytes) on the next [`poll()`](Self::poll). The request is
+    /// retransmitted until the peer acknowledges it or Max-Terminate is reached, then the
+    /// connection goes to phase [`Dead`](crate::Phase::Dead).
+    ///
+    /// Returns an error if it's already in phase [`Dead`](crate::Phase::Dead).
+    pub fn close(&mut self, reason: Option<&[u8]>) -> Result<(), crate::InvalidStateError> {
+        self.ppp.close(reason.unwrap_or(&[]))
+    }
+
     /// Process received data and generate data to be send.
     ///
+    /// `now` is the current time, it's used to retransmit requests the peer hasn't replied to.
+    /// See [`poll_at()`](Self::poll_at) for when to call `poll` if no data is received.
+    ///
     /// The return value tells you what action to take. See [`PPPoSAction`] documentation
     /// for details.
-    pub fn poll(&mut self, tx_buf: &mut [u8], rx_buf: &mut [u8]) -> PPPoSAction {
+    pub fn poll(&mut self, tx_buf: &mut [u8], rx_buf: &mut [u8], now: Instant) -> PPPoSAction {
+        self.now = now;
         let mut w = FrameWriter::new(tx_buf);
+        let acfc = self.ppp.lcp.proto().peer_acfc;
 
         let mut tx = |pkt: Packet<'_>| {
             //debug!("tx: {:?}", pkt);
 
-            let mut buf = [0; 128];
+            let mut buf = [0; MAX_PACKET_LEN];
             let len = pkt.buffer_len();
             assert!(len <= buf.len());
             pkt.emit(&mut buf[..len]);
 
-            w.start().unwrap();
+            // LCP packets must be sent with address and control fields (RFC 1661 section 6.6).
+            // Control protocol numbers can't be compressed, so PFC doesn't apply.
+            w.start(acfc && pkt.proto != ProtocolType::LCP).unwrap();
             w.append(&mut buf[..len]).unwrap();
             w.finish().unwrap();
         };
 
+        // A lost frame may have been VJ or IPHC compressed, the following ones can't be
+        // decompressed.
+        if self.frame_reader.take_error() {
+            self.ppp.vj_rx.toss();
+            self.ppp.iphc_rx.toss();
+        }
+
         // Handle input
-        if let Some(range) = self.frame_reader.receive() {
+        let range = self.frame_reader.receive(rx_buf);
+        if let Some(range) = range.and_then(|range| self.decompress(rx_buf, range)) {
             let pkt = &mut rx_buf[range.clone()];
-            let proto = u16::from_be_bytes(pkt[0..2].try_into().unwrap());
-            match proto.into() {
-                ProtocolType::IPv4 => return PPPoSAction::Received((range.start + 2)..range.end),
-                _ => self.ppp.received(pkt, &mut tx),
+            match parse_protocol(pkt) {
+                Some((proto, proto_len))
+                    if proto == ProtocolType::IPv4.into()
+                        || proto == ProtocolType::IPv6.into()
+                        || self.extra_protocols.contains(&proto) =>
+                {
+                    return PPPoSAction::Received(proto, (range.start + proto_len)..range.end);
+                }
+                Some((proto, proto_len))
+                    if self.ppp.vj_rx.is_enabled()
+                        && (proto == ProtocolType::VJCompressedTCP.into()
+                            || proto == ProtocolType::VJUncompressedTCP.into()) =>
+                {
+                    let range = (range.start + proto_len)..range.end;
+                    let range = if proto == ProtocolType::VJCompressedTCP.into() {
+                        self.ppp.vj_rx.compressed(rx_buf, range)
+                    } else {
+                        let ok = self.ppp.vj_rx.uncompressed(&mut rx_buf[range.clone()]);
+                        ok.then_some(range)
+                    };
+                    if let Some(range) = range {
+                        return PPPoSAction::Received(ProtocolType::IPv4.into(), range);
+                    }
+                }
+                Some((proto, proto_len))
+                    if self.ppp.iphc_rx.is_enabled()
+                        && (proto == ProtocolType::IPHCFullHeader.into()
+                            || proto == ProtocolType::IPHCCompressedTCP.into()
+                            || proto == ProtocolType::IPHCCompressedTCPNoDelta.into()
+                            || proto == ProtocolType::IPHCCompressedNonTCP.into()) =>
+                {
+                    let range = (range.start + proto_len)..range.end;
+                    let range = if proto == ProtocolType::IPHCFullHeader.into() {
+                        let ok = self.ppp.iphc_rx.full_header(&mut rx_buf[range.clone()]);
+                        ok.then_some(range)
+                    } else if proto == ProtocolType::IPHCCompressedTCP.into() {
+                        self.ppp.iphc_rx.compressed_tcp(rx_buf, range)
+                    } else if proto == ProtocolType::IPHCCompressedNonTCP.into() {
+                        self.ppp.iphc_rx.compressed_non_tcp(rx_buf, range)
+                    } else {
+                        // We never ask for the TCP checksum to be omitted, so the peer shouldn't
+                        // send these.
+                        warn!("IPHC: dropping COMPRESSED_TCP_NODELTA packet");
+                        None
+                    };
+                    if let Some(range) = range {
+                        let proto = match rx_buf[range.start] >> 4 {
+                            6 => ProtocolType::IPv6,
+                            _ => ProtocolType::IPv4,
+                        };
+                        return PPPoSAction::Received(proto.into(), range);
+                    }
+                }
+                Some((proto, proto_len))
+                    if self.ppp.iphc_tx.is_enabled()
+                        && proto == ProtocolType::IPHCContextState.into() =>
+                {
+                    // The peer lost some of our packets, resynchronize the TCP contexts.
+                    self.ppp.iphc_tx.context_state(&pkt[proto_len..]);
+                }
+                _ => self.ppp.received(pkt, now, &mut tx),
             }
         }
 
-        self.ppp.poll(tx);
+        let mut context_state = [0; iphc::CONTEXT_STATE_LEN];
+        if let Some(n) = self.ppp.iphc_rx.context_state(&mut context_state) {
+            tx(Packet {
+                proto: ProtocolType::IPHCContextState,
+                payload: Payload::Raw(&mut context_state[..n]),
+            });
+        }
+
+        self.ppp.poll(now, tx);
 
         let r = w.len();
         if r == 0 {
@@ -100,21 +247,182 @@ impl<'a> PPPoS<'a> {
         }
     }
 
-    /// Send an IP packet.
+    /// Decompress the frame at `rx_buf[range]` if it's a CCP compressed datagram.
+    ///
+    /// The packet is written after the frame, with its protocol field, to be handled like a
+    /// received frame. Returns its range, or None if it must be dropped.
+    fn decompress(&mut self, rx_buf: &mut [u8], range: Range<usize>) -> Option<Range<usize>> {
+        let (proto, proto_len) = parse_protocol(&rx_buf[range.clone()])?;
+        let data = (range.start + proto_len)..range.end;
+        if proto != ProtocolType::CompressedDatagram.into() {
+            self.ppp.ccp_incompressible(proto, &rx_buf[data]);
+            return Some(range);
+        }
+        let Some(c) = self.ppp.ccp_rx() else {
+            // Dropped or rejected by PPP.
+            return Some(range);
+        };
+
+        let (frame, out) = rx_buf.split_at_mut(range.end);
+        match c.decompress(&frame[data], out.get_mut(2..)?) {
+            Some((proto, n)) => {
+                out[..2].copy_from_slice(&proto.to_be_bytes());
+                Some(range.end..range.end + 2 + n)
+            }
+            None => {
+                self.ppp.ccp_decompress_failed();
+                None
+            }
+        }
+    }
+
+    /// Send an IPv4 or IPv6 packet.
+    ///
+    /// The protocol is chosen from the IP version in the packet header. See
+    /// [`send_with_protocol()`](Self::send_with_protocol) for the details.
+    pub fn send(&mut self, pkt: &[u8], tx_buf: &mut [u8]) -> Result<usize, SendError> {
+        let proto = match pkt.first().map(|b| b >> 4) {
+            Some(6) => ProtocolType::IPv6,
+            _ => ProtocolType::IPv4,
+        };
+        self.send_with_protocol(proto.into(), pkt, tx_buf)
+    }
+
+    /// Send a network-layer packet with the given PPP protocol number, e.g. `0x0021` for IPv4.
+    ///
+    /// If IP header compression was negotiated, the headers of IPv4 and IPv6 packets are
+    /// compressed. Otherwise, if Van Jacobson compression was negotiated, the headers of IPv4
+    /// TCP packets are. Then, if CCP is up, the whole packet is compressed.
     ///
     /// You must provide enough buffer space for the data to be transmitted. This function
     /// returns the size of the encoded packet `n`, you must transmit `tx_buf[..n]` over the
     /// serial connection.
     ///
-    /// Returns `BufferFullError` if `tx_buf` is too small.
-    pub fn send(&mut self, pkt: &[u8], tx_buf: &mut [u8]) -> Result<usize, BufferFullError> {
-        // TODO check IPv4CP is up
+    /// Returns [`SendError::PacketTooLarge`] if `pkt` is larger than [`Status::mtu`], or
+    /// [`SendError::BufferFull`] if `tx_buf` is too small.
+    pub fn send_with_protocol(
+        &mut self,
+        proto: u16,
+        pkt: &[u8],
+        tx_buf: &mut [u8],
+    ) -> Result<usize, SendError> {
+        // TODO check the network protocol is up
+
+        if pkt.len() > self.ppp.lcp.proto().peer_mru as usize {
+            return Err(SendError::PacketTooLarge);
+        }
+
+        let mut header = [0; vj::MAX_HEADER];
+        let (proto, header_len, consumed) = self.compress(proto, pkt, &mut header);
+
+        let header = &header[..header_len];
+        let res = match self.ppp.ccp_tx(proto) {
+            Some(c) => self.write_compressed(c, proto, header, &pkt[consumed..], tx_buf),
+            None => self.write_frame(proto, header, &pkt[consumed..], tx_buf),
+        };
+        if res.is_err() {
+            // The compressors assume the peer gets every packet, resynchronize them.
+            self.ppp.vj_tx.clear();
+            self.ppp.iphc_tx.clear();
+        }
+        Ok(res?)
+    }
 
-        let mut w = FrameWriter::new_with_asyncmap(tx_buf, self.ppp.lcp.proto().asyncmap_remote);
-        let proto: u16 = ProtocolType::IPv4.into();
-        w.start()?;
-        w.append(&proto.to_be_bytes())?;
-        w.append(pkt)?;
+    /// Compress the header of `pkt` into `header`, if it's an IP packet.
+    ///
+    /// Returns the protocol to send it with, the compressed header length and how many bytes
+    /// of `pkt` it replaces.
+    fn compress(
+        &mut self,
+        proto: u16,
+        pkt: &[u8],
+        header: &mut [u8; vj::MAX_HEADER],
+    ) -> (u16, usize, usize) {
+        if proto == ProtocolType::IPv4.into() || proto == ProtocolType::IPv6.into() {
+            let proto = match self.ppp.iphc_tx.compress(pkt, header, self.now) {
+                (iphc::PacketType::Regular, ..) => None,
+                (iphc::PacketType::FullHeader, n, consumed) => {
+                    Some((ProtocolType::IPHCFullHeader, n, consumed))
+                }
+                (iphc::PacketType::CompressedTcp, n, consumed) => {
+                    Some((ProtocolType::IPHCCompressedTCP, n, consumed))
+                }
+                (iphc::PacketType::CompressedNonTcp, n, consumed) => {
+                    Some((ProtocolType::IPHCCompressedNonTCP, n, consumed))
+                }
+            };
+            if let Some((proto, n, consumed)) = proto {
+                return (proto.into(), n, consumed);
+            }
+        }
+
+        if proto == ProtocolType::IPv4.into() {
+            match self.ppp.vj_tx.compress(pkt, header) {
+                (vj::PacketType::Ip, ..) => {}
+                (vj::PacketType::UncompressedTcp, n, consumed) => {
+                    return (ProtocolType::VJUncompressedTCP.into(), n, consumed);
+                }
+                (vj::PacketType::CompressedTcp, n, consumed) => {
+                    return (ProtocolType::VJCompressedTCP.into(), n, consumed);
+                }
+            }
+        }
+        (proto, 0, 0)
+    }
+
+    /// Compress a packet with CCP and write its frame.
+    ///
+    /// The packet is gathered at the end of `tx_buf` and compressed before it. The compressed
+    /// datagram is then moved to the end, and framed at the start.
+    fn write_compressed(
+        &self,
+        c: &dyn Compressor,
+        proto: u16,
+        header: &[u8],
+        data: &[u8],
+        tx_buf: &mut [u8],
+    ) -> Result<usize, BufferFullError> {
+        let len = header.len() + data.len();
+        let split = tx_buf.len().checked_sub(len).ok_or(BufferFullError)?;
+        let (out, pkt) = tx_buf.split_at_mut(split);
+        pkt[..header.len()].copy_from_slice(header);
+        pkt[header.len()..].copy_from_slice(data);
+
+        let mtu = self.ppp.lcp.proto().peer_mru as usize;
+        let out_len = out.len().min(mtu);
+        match c.compress(proto, pkt, &mut out[..out_len]) {
+            Some(n) => {
+                let split = tx_buf.len() - n;
+                tx_buf.copy_within(..n, split);
+                let (frame, compressed) = tx_buf.split_at_mut(split);
+                let proto = ProtocolType::CompressedDatagram.into();
+                self.write_frame(proto, &[], compressed, frame)
+            }
+            None => {
+                let (frame, pkt) = tx_buf.split_at_mut(split);
+                self.write_frame(proto, &[], pkt, frame)
+            }
+        }
+    }
+
+    fn write_frame(
+        &self,
+        proto: u16,
+        header: &[u8],
+        data: &[u8],
+        tx_buf: &mut [u8],
+    ) -> Result<usize, BufferFullError> {
+        let lcp = self.ppp.lcp.proto();
+        let mut w = FrameWriter::new_with_asyncmap(tx_buf, lcp.asyncmap_remote);
+        w.start(lcp.peer_acfc)?;
+        // Only protocol numbers with a zero high byte can be compressed.
+        if lcp.peer_pfc && proto <= 0xff {
+            w.append(&[proto as u8])?;
+        } else {
+            w.append(&proto.to_be_bytes())?;
+        }
+        w.append(header)?;
+        w.append(data)?;
         w.finish()?;
         Ok(w.len())
     }
@@ -0,0 +1,32 @@
+/// Small pseudo-random number generator, seeded from [`Config::random_seed`](crate::Config::random_seed).
+pub(crate) struct Rand {
+    state: u64,
+}
+
+impl Rand {
+    pub(crate) const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub(crate) fn rand_u32(&mut self) -> u32 {
+        // sPCG32 from https://www.pcg-random.org/paper.html
+        // see also https://nullprogram.com/blog/2017/09/21/
+        const M: u64 = 0xbb2efcec3c39611d;
+        const A: u64 = 0x7be1c0c1d7b6a4c9;
+        self.state = self.state.wrapping_mul(M).wrapping_add(A);
+
+        const BITS: u32 = 64;
+        const OUTBITS: u32 = 32;
+        const SHIFT: u32 = 3;
+        let rot = (self.state >> (BITS - SHIFT)) as u32;
+        (((self.state >> ((BITS - OUTBITS - SHIFT) / 2)) ^ self.state)
+            >> (BITS - OUTBITS - SHIFT - rot)) as u32
+    }
+
+    pub(crate) fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(4) {
+            let r = self.rand_u32().to_le_bytes();
+            chunk.copy_from_slice(&r[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use core::ops::{Add, AddAssign, Sub};
+
+/// A point in time, in milliseconds.
+///
+/// The epoch is arbitrary: it can be the time since boot, the time since
+/// the `PPPoS` was created, or anything else, as long as it's monotonic and
+/// the same clock is used for every call.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Ord, PartialOrd, Hash, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Instant {
+    millis: u64,
+}
+
+impl Instant {
+    /// The zero instant.
+    pub const ZERO: Instant = Instant { millis: 0 };
+
+    /// Create an instant from milliseconds since the epoch.
+    pub const fn from_millis(millis: u64) -> Self {
+        Self { millis }
+    }
+
+    /// Create an instant from seconds since the epoch.
+    pub const fn from_secs(secs: u64) -> Self {
+        Self {
+            millis: secs * 1000,
+        }
+    }
+
+    /// Milliseconds since the epoch.
+    pub const fn as_millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant::from_millis(self.millis.saturating_add(rhs.millis))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        Duration::from_millis(self.millis.saturating_sub(rhs.millis))
+    }
+}
+
+/// A span of time, in milliseconds.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Ord, PartialOrd, Hash, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Duration {
+    millis: u64,
+}
+
+impl Duration {
+    /// The zero duration.
+    pub const ZERO: Duration = Duration { millis: 0 };
+
+    /// Create a duration from milliseconds.
+    pub const fn from_millis(millis: u64) -> Self {
+        Self { millis }
+    }
+
+    /// Create a duration from seconds.
+    pub const fn from_secs(secs: u64) -> Self {
+        Self {
+            millis: secs * 1000,
+        }
+    }
+
+    /// Total milliseconds in the duration.
+    pub const fn as_millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+impl Add<Duration> for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration::from_millis(self.millis.saturating_add(rhs.millis))
+    }
+}
@@ -1,8 +1,15 @@
-use heapless::Vec;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
-pub const MAX_OPTIONS: usize = 6;
-pub const MAX_OPTION_LEN: usize = 4;
+/// Most CCP algorithms we offer.
+pub const MAX_OPTIONS: usize = 8;
+/// Longest CCP option data we send.
+pub const MAX_OPTION_LEN: usize = 16;
+/// Largest control packet we send, including the protocol field. It's no larger than the
+/// smallest MRU we accept from the peer, so the received packets echoed back in rejects are
+/// truncated to fit.
+pub const MAX_PACKET_LEN: usize = 128;
+/// Length of the protocol field and the code, identifier and length of a control packet.
+pub const HEADER_LEN: usize = 6;
 
 #[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -14,10 +21,39 @@ pub enum ProtocolType {
     LCP = 0xc021,
     /// Password Authentication Protocol, rfc1334
     PAP = 0xc023,
+    /// Challenge Handshake Authentication Protocol, rfc1994
+    #[allow(clippy::upper_case_acronyms)]
+    CHAP = 0xc223,
+    /// Extensible Authentication Protocol, rfc3748
+    #[allow(clippy::upper_case_acronyms)]
+    EAP = 0xc227,
     /// Internet Protocol v4
     IPv4 = 0x0021,
+    /// Van Jacobson Compressed TCP/IP, rfc1144
+    VJCompressedTCP = 0x002d,
+    /// Van Jacobson Uncompressed TCP/IP, rfc1144
+    VJUncompressedTCP = 0x002f,
+    /// IP Header Compression full header, rfc2509
+    IPHCFullHeader = 0x0061,
+    /// IP Header Compression compressed TCP, rfc2509
+    IPHCCompressedTCP = 0x0063,
+    /// IP Header Compression compressed TCP without delta encoding, rfc2509
+    IPHCCompressedTCPNoDelta = 0x2063,
+    /// IP Header Compression compressed non-TCP, rfc2509
+    IPHCCompressedNonTCP = 0x0065,
+    /// IP Header Compression context state, rfc2509
+    IPHCContextState = 0x2065,
+    /// Compressed Datagram, rfc1962
+    CompressedDatagram = 0x00fd,
+    /// Compression Control Protocol, rfc1962
+    #[allow(clippy::upper_case_acronyms)]
+    CCP = 0x80fd,
     /// Internet Protocol v4 Control Protocol, rfc1332
     IPv4CP = 0x8021,
+    /// Internet Protocol v6
+    IPv6 = 0x0057,
+    /// Internet Protocol v6 Control Protocol, rfc5072
+    IPv6CP = 0x8057,
 }
 
 #[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug, Ord, PartialOrd)]
@@ -37,6 +73,8 @@ pub enum Code {
     EchoReq = 9,
     EchoReply = 10,
     DiscardReq = 11,
+    ResetReq = 14,
+    ResetAck = 15,
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -57,6 +95,18 @@ impl<'a> Packet<'a> {
     }
 }
 
+/// Parse the protocol field at the start of `pkt`, which may be compressed to 1 byte
+/// with PFC (RFC 1661 section 6.5).
+///
+/// Returns the protocol number and the length of the field.
+pub fn parse_protocol(pkt: &[u8]) -> Option<(u16, usize)> {
+    match *pkt {
+        [a, ..] if a & 1 == 1 => Some((a as u16, 1)),
+        [a, b, ..] => Some((u16::from_be_bytes([a, b]), 2)),
+        _ => None,
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Payload<'a> {
     Raw(&'a mut [u8]),
@@ -89,7 +139,14 @@ impl<'a> Payload<'a> {
 pub enum PPPPayload<'a> {
     Raw(&'a mut [u8]),
     PAP(&'a [u8], &'a [u8]),
+    /// PAP Authenticate-Ack or Authenticate-Nak message.
+    PAPMessage(&'a [u8]),
+    /// CHAP Challenge or Response: value and name.
+    #[allow(clippy::upper_case_acronyms)]
+    CHAP(&'a [u8], &'a [u8]),
     Options(Options),
+    /// Protocol-Reject: rejected protocol and information.
+    ProtocolRej(u16, &'a [u8]),
 }
 
 impl<'a> PPPPayload<'a> {
@@ -97,7 +154,10 @@ impl<'a> PPPPayload<'a> {
         match self {
             Self::Raw(data) => data.len(),
             Self::PAP(user, pass) => 1 + user.len() + 1 + pass.len(),
+            Self::PAPMessage(msg) => 1 + msg.len(),
+            Self::CHAP(value, name) => 1 + value.len() + name.len(),
             Self::Options(options) => options.buffer_len(),
+            Self::ProtocolRej(_proto, data) => 2 + data.len(),
         }
     }
 
@@ -110,64 +170,75 @@ impl<'a> PPPPayload<'a> {
                 buffer[1 + user.len()] = pass.len() as u8;
                 buffer[1 + user.len() + 1..].copy_from_slice(pass);
             }
+            Self::PAPMessage(msg) => {
+                buffer[0] = msg.len() as u8;
+                buffer[1..].copy_from_slice(msg);
+            }
+            Self::CHAP(value, name) => {
+                buffer[0] = value.len() as u8;
+                buffer[1..][..value.len()].copy_from_slice(value);
+                buffer[1 + value.len()..].copy_from_slice(name);
+            }
             Self::Options(options) => options.emit(buffer),
+            Self::ProtocolRej(proto, data) => {
+                buffer[0..2].copy_from_slice(&proto.to_be_bytes());
+                buffer[2..].copy_from_slice(data);
+            }
         }
     }
 }
 
-pub struct Options(pub Vec<OptionVal, MAX_OPTIONS>);
+/// The options are full: the packet would be larger than [`MAX_PACKET_LEN`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OptionsFullError;
+
+/// Options of a Configure packet, encoded.
+///
+/// They're limited to what fits in a packet of [`MAX_PACKET_LEN`], however many and however
+/// long they are.
+pub struct Options {
+    buf: [u8; MAX_PACKET_LEN - HEADER_LEN],
+    len: usize,
+}
 
 impl Options {
-    pub fn buffer_len(&self) -> usize {
-        self.0.iter().map(|opt| opt.buffer_len()).sum()
-    }
-
-    pub fn emit(&self, mut buffer: &mut [u8]) {
-        for o in &self.0 {
-            let len = o.buffer_len();
-            o.emit(&mut buffer[..len]);
-            buffer = &mut buffer[len..];
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET_LEN - HEADER_LEN],
+            len: 0,
         }
     }
-}
 
-#[cfg(feature = "defmt")]
-impl defmt::Format for Options {
-    fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "{=[?]}", &self.0[..])
+    /// Add an option, unless it doesn't fit.
+    pub fn push(&mut self, code: u8, data: &[u8]) -> Result<(), OptionsFullError> {
+        let end = self.len + 2 + data.len();
+        if end > self.buf.len() || data.len() > 255 - 2 {
+            return Err(OptionsFullError);
+        }
+        self.buf[self.len] = code;
+        self.buf[self.len + 1] = (2 + data.len()) as u8;
+        self.buf[self.len + 2..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
     }
-}
-
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct OptionVal {
-    code: u8,
-    data: OptionData,
-}
 
-impl OptionVal {
-    pub fn new(code: u8, data: &[u8]) -> Self {
-        Self {
-            code,
-            data: OptionData(unwrap!(Vec::from_slice(data))),
-        }
+    pub fn clear(&mut self) {
+        self.len = 0;
     }
 
     pub fn buffer_len(&self) -> usize {
-        2 + self.data.0.len()
+        self.len
     }
 
     pub fn emit(&self, buffer: &mut [u8]) {
-        buffer[0] = self.code;
-        buffer[1] = self.data.0.len() as u8 + 2;
-        buffer[2..].copy_from_slice(&self.data.0);
+        buffer.copy_from_slice(&self.buf[..self.len])
     }
 }
 
-struct OptionData(Vec<u8, MAX_OPTION_LEN>);
-
 #[cfg(feature = "defmt")]
-impl defmt::Format for OptionData {
+impl defmt::Format for Options {
     fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "{=[?]}", &self.0[..])
+        defmt::write!(fmt, "{=[u8]:x}", &self.buf[..self.len])
     }
 }